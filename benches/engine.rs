@@ -0,0 +1,93 @@
+// インタプリタと事前変換した実行を tests/vsm のプログラムで比べる.
+// cargo bench --bench engine [回数]
+use std::env;
+use std::io::{self, Cursor};
+use std::time::{Duration, Instant};
+
+use virtual_stack_machine::code::Code;
+use virtual_stack_machine::compiler::compile_file;
+use virtual_stack_machine::engine::DecodedCode;
+use virtual_stack_machine::vsm::{TraceType, Vsm};
+
+const PROGRAMS: [(&str, &str); 8] = [
+    ("tests/vsm/add.vsm", ""),
+    ("tests/vsm/average.vsm", "3\n8\n"),
+    ("tests/vsm/exam.vsm", "90\n"),
+    ("tests/vsm/fact.vsm", ""),
+    ("tests/vsm/full.vsm", ""),
+    ("tests/vsm/matrix.vsm", ""),
+    ("tests/vsm/ssort.vmc", ""),
+    ("tests/vsm/while.vsm", ""),
+];
+
+fn measure(code: &Code, input: &str, iterations: u32, fast: bool) -> (Duration, u64) {
+    let mut executed = 0;
+    let started_at = Instant::now();
+    for _ in 0..iterations {
+        let mut vsm = Vsm::with_io(
+            TraceType::No,
+            Box::new(Cursor::new(input.to_string())),
+            Box::new(io::sink()),
+        );
+        vsm.load_code(code.clone());
+        let result = if fast { vsm.exec_code_fast() } else { vsm.exec_code() };
+        if let Err(err) = result {
+            panic!("{}", err);
+        }
+        executed += vsm.executed_instructions();
+    }
+    (started_at.elapsed(), executed)
+}
+
+fn main() {
+    // cargo bench は --bench を渡すので数値の引数だけを見る
+    let iterations = env::args().skip(1).find_map(|arg| arg.parse().ok()).unwrap_or(2000);
+
+    let mut programs = Vec::new();
+    for (file_path, input) in PROGRAMS {
+        let mut code = Code::new();
+        if let Err(errors) = code.read(file_path) {
+            errors.iter().for_each(|error| eprintln!("{}", error.render()));
+            std::process::exit(1);
+        }
+        programs.push((file_path, input, code));
+    }
+    match compile_file("tests/c/ssort.c") {
+        Ok(code) => programs.push(("tests/c/ssort.c", "5\n3\n-2\n8\n0\n7\n1\n4\n", code)),
+        Err(errors) => {
+            errors.iter().for_each(|error| eprintln!("{}", error.render()));
+            std::process::exit(1);
+        }
+    }
+
+    println!("{} iterations per program", iterations);
+    println!(
+        "{:<22} {:>6} {:>12} {:>12} {:>12} {:>8}",
+        "program", "fused", "instructions", "interpreter", "fast", "speedup"
+    );
+    let (mut total_interpreter, mut total_fast) = (Duration::ZERO, Duration::ZERO);
+    for (file_path, input, code) in &programs {
+        let (interpreter, executed) = measure(code, input, iterations, false);
+        let (fast, _) = measure(code, input, iterations, true);
+        total_interpreter += interpreter;
+        total_fast += fast;
+        println!(
+            "{:<22} {:>6} {:>12} {:>10.2}ms {:>10.2}ms {:>7.2}x",
+            file_path,
+            DecodedCode::new(code).superinstructions(),
+            executed / iterations as u64,
+            interpreter.as_secs_f64() * 1000.0,
+            fast.as_secs_f64() * 1000.0,
+            interpreter.as_secs_f64() / fast.as_secs_f64()
+        );
+    }
+    println!(
+        "{:<22} {:>6} {:>12} {:>10.2}ms {:>10.2}ms {:>7.2}x",
+        "total",
+        "",
+        "",
+        total_interpreter.as_secs_f64() * 1000.0,
+        total_fast.as_secs_f64() * 1000.0,
+        total_interpreter.as_secs_f64() / total_fast.as_secs_f64()
+    );
+}
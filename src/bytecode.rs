@@ -0,0 +1,401 @@
+//! VSM のバイナリ形式 (バイトコード)
+//!
+//! 数値は全てリトルエンディアン. `varint` は符号なし LEB128,
+//! `svarint` は zigzag 変換した i32 を LEB128 で表したもの.
+//!
+//! ```text
+//! header
+//!   magic        4 bytes  "VSMB"
+//!   version      u16      FORMAT_VERSION
+//!   flags        u16      予約 (0)
+//!   count        u32      命令数
+//! code (count 個)
+//!   opcode       u8       OPERATION_CODES のインデックス
+//!   operands     svarint  オペランドの数だけ並ぶ
+//! sections (ファイルの終わりまで, 未知のタグは読み飛ばす)
+//!   tag          u8
+//!   length       u32      payload のバイト数
+//!   payload
+//!
+//! SECTION_SYMBOLS
+//!   count        varint
+//!   (address varint, name_length varint, name UTF-8) * count
+//! SECTION_DEBUG
+//!   file_length  varint   0 ならファイル名なし
+//!   file         UTF-8
+//!   line         varint   命令ごとの行番号 (0 は不明) * 命令数
+//! SECTION_SOURCE
+//!   (text_length varint, text UTF-8) * 命令数   命令ごとのソースの行 (コメントを含む)
+//! SECTION_DATA
+//!   count        varint
+//!   value        svarint * count   B0 からのセルの初期値
+//!   label_count  varint
+//!   (offset varint, name_length varint, name UTF-8) * label_count
+//! ```
+
+use core::fmt;
+
+use std::collections::HashMap;
+
+use crate::code::{Code, OperationCode, SourceLocation, MAX_DATA_SIZE};
+
+pub const MAGIC: &[u8; 4] = b"VSMB";
+pub const FORMAT_VERSION: u16 = 1;
+
+pub const SECTION_SYMBOLS: u8 = 1;
+pub const SECTION_DEBUG: u8 = 2;
+pub const SECTION_SOURCE: u8 = 3;
+pub const SECTION_DATA: u8 = 4;
+
+// バイトコードの opcode 番号はこの並び順で決まるので, 追加は末尾に行う
+pub const OPERATION_CODES: [OperationCode; 35] = [
+    OperationCode::Isp,
+    OperationCode::La,
+    OperationCode::Lv,
+    OperationCode::Lc,
+    OperationCode::Li,
+    OperationCode::Dup,
+    OperationCode::Si,
+    OperationCode::Sv,
+    OperationCode::Sb,
+    OperationCode::B,
+    OperationCode::Bz,
+    OperationCode::Call,
+    OperationCode::Ret,
+    OperationCode::Getc,
+    OperationCode::Geti,
+    OperationCode::Putc,
+    OperationCode::Puti,
+    OperationCode::Add,
+    OperationCode::Sub,
+    OperationCode::Mul,
+    OperationCode::Div,
+    OperationCode::Mod,
+    OperationCode::Inv,
+    OperationCode::Eq,
+    OperationCode::Ne,
+    OperationCode::Gt,
+    OperationCode::Lt,
+    OperationCode::Ge,
+    OperationCode::Le,
+    OperationCode::Exit,
+    OperationCode::Alloc,
+    OperationCode::Free,
+    OperationCode::Lh,
+    OperationCode::Sh,
+    OperationCode::Puts,
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BytecodeError {
+    InvalidMagic,
+    UnsupportedVersion(u16),
+    UnexpectedEof,
+    InvalidOperationCode(u8),
+    InvalidVarint,
+    InvalidUtf8,
+    InvalidSection(u8),
+    InvalidSymbol(String),
+    InvalidCallTarget(usize),
+}
+
+impl fmt::Display for BytecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BytecodeError::InvalidMagic => write!(f, "invalid magic number"),
+            BytecodeError::UnsupportedVersion(version) => write!(f, "unsupported version {}", version),
+            BytecodeError::UnexpectedEof => write!(f, "unexpected end of bytecode"),
+            BytecodeError::InvalidOperationCode(byte) => write!(f, "invalid operation code {}", byte),
+            BytecodeError::InvalidVarint => write!(f, "invalid variable-length integer"),
+            BytecodeError::InvalidUtf8 => write!(f, "invalid UTF-8 string"),
+            BytecodeError::InvalidSection(tag) => write!(f, "malformed section {}", tag),
+            BytecodeError::InvalidSymbol(label) => write!(f, "symbol `{}` points outside the code", label),
+            BytecodeError::InvalidCallTarget(program_counter) => {
+                write!(f, "CALL at {:04} jumps outside the code", program_counter)
+            }
+        }
+    }
+}
+
+impl std::error::Error for BytecodeError {}
+
+pub fn is_bytecode(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+fn operation_code_to_byte(operation_code: OperationCode) -> u8 {
+    OPERATION_CODES
+        .iter()
+        .position(|code| *code == operation_code)
+        .unwrap() as u8
+}
+
+pub(crate) fn write_varint(bytes: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            bytes.push(byte);
+            return;
+        }
+        bytes.push(byte | 0x80);
+    }
+}
+
+pub(crate) fn write_svarint(bytes: &mut Vec<u8>, value: i32) {
+    write_varint(bytes, ((value << 1) ^ (value >> 31)) as u32 as u64);
+}
+
+pub(crate) fn write_string(bytes: &mut Vec<u8>, value: &str) {
+    write_varint(bytes, value.len() as u64);
+    bytes.extend_from_slice(value.as_bytes());
+}
+
+pub(crate) struct ByteReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> ByteReader<'a> {
+    pub(crate) fn new(bytes: &'a [u8]) -> ByteReader<'a> {
+        ByteReader { bytes, position: 0 }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.position >= self.bytes.len()
+    }
+
+    pub(crate) fn take(&mut self, length: usize) -> Result<&'a [u8], BytecodeError> {
+        let end = self
+            .position
+            .checked_add(length)
+            .filter(|end| *end <= self.bytes.len())
+            .ok_or(BytecodeError::UnexpectedEof)?;
+        let slice = &self.bytes[self.position..end];
+        self.position = end;
+        Ok(slice)
+    }
+
+    pub(crate) fn u8(&mut self) -> Result<u8, BytecodeError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub(crate) fn u16(&mut self) -> Result<u16, BytecodeError> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    pub(crate) fn u32(&mut self) -> Result<u32, BytecodeError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub(crate) fn varint(&mut self) -> Result<u64, BytecodeError> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.u8()?;
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(BytecodeError::InvalidVarint)
+    }
+
+    pub(crate) fn svarint(&mut self) -> Result<i32, BytecodeError> {
+        let value = u32::try_from(self.varint()?).map_err(|_| BytecodeError::InvalidVarint)?;
+        Ok(((value >> 1) as i32) ^ -((value & 1) as i32))
+    }
+
+    pub(crate) fn usize(&mut self) -> Result<usize, BytecodeError> {
+        usize::try_from(self.varint()?).map_err(|_| BytecodeError::InvalidVarint)
+    }
+
+    pub(crate) fn string(&mut self) -> Result<String, BytecodeError> {
+        let length = self.usize()?;
+        let bytes = self.take(length)?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| BytecodeError::InvalidUtf8)
+    }
+}
+
+fn write_section(bytes: &mut Vec<u8>, tag: u8, payload: Vec<u8>) {
+    bytes.push(tag);
+    bytes.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    bytes.extend(payload);
+}
+
+impl Code {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+        bytes.extend_from_slice(&0u16.to_le_bytes());
+        bytes.extend_from_slice(&(self.len() as u32).to_le_bytes());
+
+        for instruction in self.instructions() {
+            bytes.push(operation_code_to_byte(instruction.operation_code));
+            instruction
+                .operand
+                .iter()
+                .take(self.operand_size(instruction.operation_code))
+                .for_each(|operand| write_svarint(&mut bytes, operand.unwrap_or_default()));
+        }
+
+        if !self.labels().is_empty() {
+            let mut labels = self.labels().iter().collect::<Vec<_>>();
+            labels.sort_by_key(|(label, address)| (**address, label.as_str()));
+            let mut payload = Vec::new();
+            write_varint(&mut payload, labels.len() as u64);
+            for (label, address) in labels {
+                write_varint(&mut payload, *address as u64);
+                write_string(&mut payload, label);
+            }
+            write_section(&mut bytes, SECTION_SYMBOLS, payload);
+        }
+
+        let has_line_numbers = (0..self.len()).any(|program_counter| self.line_number(program_counter).is_some());
+        if has_line_numbers {
+            let mut payload = Vec::new();
+            write_string(&mut payload, self.source_file().unwrap_or_default());
+            for program_counter in 0..self.len() {
+                write_varint(&mut payload, self.line_number(program_counter).unwrap_or(0) as u64);
+            }
+            write_section(&mut bytes, SECTION_DEBUG, payload);
+
+            let mut payload = Vec::new();
+            for program_counter in 0..self.len() {
+                let text = self.source_location(program_counter).map(|location| location.text.as_str());
+                write_string(&mut payload, text.unwrap_or_default());
+            }
+            write_section(&mut bytes, SECTION_SOURCE, payload);
+        }
+
+        if !self.data().is_empty() || !self.data_labels().is_empty() {
+            let mut payload = Vec::new();
+            write_varint(&mut payload, self.data().len() as u64);
+            self.data().iter().for_each(|value| write_svarint(&mut payload, *value));
+            let mut labels = self.data_labels().iter().collect::<Vec<_>>();
+            labels.sort_by_key(|(label, offset)| (**offset, label.as_str()));
+            write_varint(&mut payload, labels.len() as u64);
+            for (label, offset) in labels {
+                write_varint(&mut payload, *offset as u64);
+                write_string(&mut payload, label);
+            }
+            write_section(&mut bytes, SECTION_DATA, payload);
+        }
+
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Code, BytecodeError> {
+        let mut reader = ByteReader::new(bytes);
+        if reader.take(4).map_err(|_| BytecodeError::InvalidMagic)? != MAGIC {
+            return Err(BytecodeError::InvalidMagic);
+        }
+        let version = reader.u16()?;
+        if version != FORMAT_VERSION {
+            return Err(BytecodeError::UnsupportedVersion(version));
+        }
+        let _flags = reader.u16()?;
+        let count = reader.u32()? as usize;
+
+        let mut code = Code::new();
+        let mut source_file = None;
+        let mut line_numbers = Vec::new();
+        let mut texts = Vec::new();
+        for _ in 0..count {
+            let byte = reader.u8()?;
+            let operation_code = *OPERATION_CODES
+                .get(byte as usize)
+                .ok_or(BytecodeError::InvalidOperationCode(byte))?;
+            let mut operand = [None, None];
+            for slot in operand.iter_mut().take(code.operand_size(operation_code)) {
+                *slot = Some(reader.svarint()?);
+            }
+            code.append_instruction(operation_code, operand[0], operand[1]);
+        }
+        code.check_call_targets()?;
+
+        while !reader.is_empty() {
+            let tag = reader.u8()?;
+            let length = reader.u32()? as usize;
+            let mut section = ByteReader::new(reader.take(length)?);
+            match tag {
+                SECTION_SYMBOLS => {
+                    let symbol_count = section.usize()?;
+                    for _ in 0..symbol_count {
+                        let address = section.usize()?;
+                        let label = section.string()?;
+                        if address > count {
+                            return Err(BytecodeError::InvalidSymbol(label));
+                        }
+                        code.insert_label(label, address);
+                    }
+                }
+                SECTION_DEBUG => {
+                    source_file = Some(section.string()?).filter(|file| !file.is_empty());
+                    line_numbers = (0..count).map(|_| section.usize()).collect::<Result<Vec<_>, _>>()?;
+                }
+                SECTION_SOURCE => {
+                    texts = (0..count).map(|_| section.string()).collect::<Result<Vec<_>, _>>()?;
+                }
+                SECTION_DATA => {
+                    let data_count = section.usize()?;
+                    if data_count > MAX_DATA_SIZE {
+                        return Err(BytecodeError::InvalidSection(tag));
+                    }
+                    let data = (0..data_count).map(|_| section.svarint()).collect::<Result<Vec<_>, _>>()?;
+                    let label_count = section.usize()?;
+                    let mut data_labels = HashMap::new();
+                    for _ in 0..label_count {
+                        let offset = section.usize()?;
+                        let label = section.string()?;
+                        if offset > data.len() {
+                            return Err(BytecodeError::InvalidSymbol(label));
+                        }
+                        data_labels.insert(label, offset);
+                    }
+                    code.set_data(data, data_labels);
+                }
+                _ => {}
+            }
+            if matches!(tag, SECTION_SYMBOLS | SECTION_DEBUG | SECTION_SOURCE | SECTION_DATA) && !section.is_empty() {
+                return Err(BytecodeError::InvalidSection(tag));
+            }
+        }
+
+        // 行番号のない命令はソースの情報を持たない
+        if !line_numbers.is_empty() {
+            let file_path = source_file.clone().unwrap_or_default();
+            let source_map = line_numbers
+                .iter()
+                .enumerate()
+                .map(|(program_counter, line)| {
+                    let text = texts.get(program_counter).map(String::as_str).unwrap_or_default();
+                    Some(SourceLocation::new(&file_path, *line, text)).filter(|_| *line != 0)
+                })
+                .collect();
+            code.set_source_map(source_file, source_map);
+        }
+
+        Ok(code)
+    }
+
+    // 読み込めないバイトコードは書かない
+    pub fn write_bytes(&self, file_path: &str) -> std::io::Result<()> {
+        self.check_call_targets()
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?;
+        std::fs::write(file_path, self.to_bytes())
+    }
+
+    // 後から読むコードの後ろに付け直すので, CALL の分岐先はコードの中か末尾 (ラベルだけの行) でなければならない.
+    // 末尾への CALL は実行したときに PC out of range になる
+    pub(crate) fn check_call_targets(&self) -> Result<(), BytecodeError> {
+        let invalid = self.instructions().iter().position(|instruction| {
+            let target = instruction.operand[0].and_then(|target| usize::try_from(target).ok());
+            instruction.operation_code == OperationCode::Call && target.is_none_or(|target| target > self.len())
+        });
+        match invalid {
+            Some(program_counter) => Err(BytecodeError::InvalidCallTarget(program_counter)),
+            None => Ok(()),
+        }
+    }
+}
@@ -1,11 +1,17 @@
 use core::fmt;
 use regex::Regex;
 use std::collections::HashMap;
-use std::fs::File;
-use std::io::{self, BufRead, Write};
+use std::fs::{self, File};
+use std::io::{self, Write};
 use std::str::FromStr;
 
-#[derive(Eq, Hash, PartialEq, Clone, Copy)]
+use crate::bytecode::{self, BytecodeError};
+use crate::disasm::Disassembler;
+
+// .word / .string / .zero で確保できるセルの合計
+pub const MAX_DATA_SIZE: usize = 1 << 20;
+
+#[derive(Debug, Eq, Hash, PartialEq, Clone, Copy)]
 pub enum OperationCode {
     Isp,
     La,
@@ -37,6 +43,11 @@ pub enum OperationCode {
     Ge,
     Le,
     Exit,
+    Alloc,
+    Free,
+    Lh,
+    Sh,
+    Puts,
 }
 
 impl fmt::Display for OperationCode {
@@ -72,6 +83,11 @@ impl fmt::Display for OperationCode {
             OperationCode::Ge => write!(f, "GE"),
             OperationCode::Le => write!(f, "LE"),
             OperationCode::Exit => write!(f, "EXIT"),
+            OperationCode::Alloc => write!(f, "ALLOC"),
+            OperationCode::Free => write!(f, "FREE"),
+            OperationCode::Lh => write!(f, "LH"),
+            OperationCode::Sh => write!(f, "SH"),
+            OperationCode::Puts => write!(f, "PUTS"),
         }
     }
 }
@@ -111,12 +127,17 @@ impl FromStr for OperationCode {
             "GE" => Ok(OperationCode::Ge),
             "LE" => Ok(OperationCode::Le),
             "EXIT" => Ok(OperationCode::Exit),
+            "ALLOC" => Ok(OperationCode::Alloc),
+            "FREE" => Ok(OperationCode::Free),
+            "LH" => Ok(OperationCode::Lh),
+            "SH" => Ok(OperationCode::Sh),
+            "PUTS" => Ok(OperationCode::Puts),
             _ => Err("Invalid operation code"),
         }
     }
 }
 
-#[derive(Clone, Copy)]
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
 pub struct Instruction {
     pub operation_code: OperationCode,
     pub operand: [Option<i32>; 2],
@@ -135,23 +156,310 @@ impl fmt::Display for Instruction {
             operand_str = " ".to_string() + &operand_str;
         }
         // 構造体の各フィールドをフォーマットして書き込む
-        write!(f, "{}{}", self.operation_code.to_string(), operand_str)
+        write!(f, "{}{}", self.operation_code, operand_str)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceSpan {
+    pub file_path: String,
+    pub line: usize,
+    pub column_start: usize,
+    pub column_end: usize,
+    pub line_text: String,
+}
+
+impl SourceSpan {
+    // column_start / column_end は 1 始まりの文字位置 (column_end は含まない)
+    pub fn text(&self) -> String {
+        self.line_text
+            .chars()
+            .skip(self.column_start - 1)
+            .take(self.column_end - self.column_start)
+            .collect()
+    }
+
+    // rustc 風にエラー箇所を ^^^ で示す
+    pub fn render(&self, message: &str, note: Option<&str>) -> String {
+        format!("error: {}\n{}", message, self.snippet(note))
+    }
+
+    // render からメッセージの行を除いたもの
+    pub fn snippet(&self, note: Option<&str>) -> String {
+        let line_number = self.line.to_string();
+        let padding = " ".repeat(line_number.len());
+        let mut rendered = format!(
+            "{}--> {}:{}:{}\n",
+            padding, self.file_path, self.line, self.column_start
+        );
+        rendered += &format!("{} |\n", padding);
+        rendered += &format!("{} | {}\n", line_number, self.line_text);
+        rendered += &format!(
+            "{} | {}{}\n",
+            padding,
+            " ".repeat(self.column_start - 1),
+            "^".repeat(self.column_end.saturating_sub(self.column_start).max(1))
+        );
+        if let Some(note) = note {
+            rendered += &format!("{} = note: {}\n", padding, note);
+        }
+        rendered
     }
 }
 
+// 命令の元になったソースの行. text は行全体, comment は行末の // より後ろ
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceLocation {
+    pub file_path: String,
+    pub line: usize,
+    pub text: String,
+    pub comment: Option<String>,
+}
+
+impl SourceLocation {
+    pub fn new(file_path: &str, line: usize, text: &str) -> SourceLocation {
+        let comment = text
+            .find("//")
+            .map(|start| text[start + 2..].trim().to_string())
+            .filter(|comment| !comment.is_empty());
+        SourceLocation {
+            file_path: file_path.to_string(),
+            line,
+            text: text.to_string(),
+            comment,
+        }
+    }
+
+    // コメントを除いた部分
+    pub fn code(&self) -> &str {
+        match self.text.find("//") {
+            Some(start) => self.text[..start].trim(),
+            None => self.text.trim(),
+        }
+    }
+
+    // エラー表示用にコメントを除いた部分を指す
+    pub fn span(&self) -> SourceSpan {
+        let column_start = self.text.chars().take_while(|c| c.is_whitespace()).count() + 1;
+        SourceSpan {
+            file_path: self.file_path.clone(),
+            line: self.line,
+            column_start,
+            column_end: column_start + self.code().chars().count(),
+            line_text: self.text.clone(),
+        }
+    }
+}
+
+impl fmt::Display for SourceLocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.file_path, self.line, self.text.trim())
+    }
+}
+
+#[derive(Debug)]
+pub enum AssembleError {
+    Io {
+        file_path: String,
+        error: io::Error,
+    },
+    InvalidOperationCode {
+        span: SourceSpan,
+    },
+    OperandCount {
+        span: SourceSpan,
+        operation_code: OperationCode,
+        expected: usize,
+        found: usize,
+    },
+    InvalidOperand {
+        span: SourceSpan,
+    },
+    InvalidLabelName {
+        span: SourceSpan,
+    },
+    UndefinedLabel {
+        span: SourceSpan,
+    },
+    DuplicateLabel {
+        span: SourceSpan,
+        first_line: Option<usize>,
+    },
+    LabelNotAllowed {
+        span: SourceSpan,
+        operation_code: OperationCode,
+    },
+    InvalidDirective {
+        span: SourceSpan,
+    },
+    WrongSection {
+        span: SourceSpan,
+        section: &'static str,
+    },
+    DataTooLarge {
+        span: SourceSpan,
+    },
+    Bytecode {
+        file_path: String,
+        error: BytecodeError,
+    },
+    DataNotRelocatable {
+        file_path: String,
+    },
+}
+
+impl AssembleError {
+    pub fn span(&self) -> Option<&SourceSpan> {
+        match self {
+            AssembleError::Io { .. } | AssembleError::Bytecode { .. } | AssembleError::DataNotRelocatable { .. } => None,
+            AssembleError::InvalidOperationCode { span }
+            | AssembleError::OperandCount { span, .. }
+            | AssembleError::InvalidOperand { span }
+            | AssembleError::InvalidLabelName { span }
+            | AssembleError::UndefinedLabel { span }
+            | AssembleError::DuplicateLabel { span, .. }
+            | AssembleError::LabelNotAllowed { span, .. }
+            | AssembleError::InvalidDirective { span }
+            | AssembleError::WrongSection { span, .. }
+            | AssembleError::DataTooLarge { span } => Some(span),
+        }
+    }
+
+    pub fn message(&self) -> String {
+        let text = self.span().map(|span| span.text()).unwrap_or_default();
+        match self {
+            AssembleError::Io { file_path, error } => {
+                format!("cannot read `{}`: {}", file_path, error)
+            }
+            AssembleError::Bytecode { file_path, error } => {
+                format!("invalid bytecode `{}`: {}", file_path, error)
+            }
+            AssembleError::DataNotRelocatable { file_path } => {
+                format!("cannot load the data section of `{}` after other data", file_path)
+            }
+            AssembleError::InvalidOperationCode { .. } => {
+                format!("invalid operation code `{}`", text)
+            }
+            AssembleError::OperandCount {
+                operation_code,
+                expected,
+                found,
+                ..
+            } => format!(
+                "`{}` takes {} operand(s) but {} were given",
+                operation_code, expected, found
+            ),
+            AssembleError::InvalidOperand { .. } => format!("invalid operand `{}`", text),
+            AssembleError::InvalidLabelName { .. } => format!("invalid label name `{}`", text),
+            AssembleError::UndefinedLabel { .. } => format!("undefined label `{}`", text),
+            AssembleError::DuplicateLabel { .. } => format!("duplicate label `{}`", text),
+            AssembleError::LabelNotAllowed { operation_code, .. } => format!(
+                "`{}` does not take a label operand `{}`",
+                operation_code, text
+            ),
+            AssembleError::InvalidDirective { .. } => format!("invalid directive `{}`", text),
+            AssembleError::WrongSection { section, .. } => {
+                format!("`{}` must be placed in the `{}` section", text, section)
+            }
+            AssembleError::DataTooLarge { .. } => {
+                format!("the data section exceeds {} cells", MAX_DATA_SIZE)
+            }
+        }
+    }
+
+    fn note(&self) -> Option<String> {
+        match self {
+            AssembleError::DuplicateLabel {
+                first_line: Some(first_line),
+                ..
+            } => Some(format!("first defined at line {}", first_line)),
+            AssembleError::LabelNotAllowed { .. } => {
+                Some("code labels can only be used with `B`, `BZ` and `CALL`, data labels with `LA`, `LV`, `SV` and `LC`".to_string())
+            }
+            _ => None,
+        }
+    }
+
+    pub fn render(&self) -> String {
+        match self.span() {
+            Some(span) => span.render(&self.message(), self.note().as_deref()),
+            None => format!("error: {}\n", self.message()),
+        }
+    }
+}
+
+impl fmt::Display for AssembleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.span() {
+            Some(span) => write!(
+                f,
+                "{}:{}:{}: {}",
+                span.file_path,
+                span.line,
+                span.column_start,
+                self.message()
+            ),
+            None => write!(f, "{}", self.message()),
+        }
+    }
+}
+
+impl std::error::Error for AssembleError {}
+
+enum OperandError {
+    Invalid,
+    UndefinedLabel,
+    LabelNotAllowed,
+}
+
+struct Token<'a> {
+    text: &'a str,
+    column_start: usize,
+    column_end: usize,
+    byte_end: usize,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Section {
+    Text,
+    Data,
+}
+
+struct PendingInstruction<'a> {
+    line_number: usize,
+    line_text: &'a str,
+    operation_code: OperationCode,
+    operands: Vec<Token<'a>>,
+}
+
+#[derive(Clone)]
 pub struct Code {
     operand_size_map: HashMap<OperationCode, usize>,
     instruction_vec: Vec<Instruction>,
+    labels: HashMap<String, usize>,
+    source_file: Option<String>,
+    source_map: Vec<Option<SourceLocation>>,
+    data: Vec<i32>,
+    data_labels: HashMap<String, usize>,
+}
+
+impl Default for Code {
+    fn default() -> Self {
+        Code::new()
+    }
 }
 
 impl Code {
 
     pub fn get_instruction(&self, program_counter : usize) -> Instruction {
-        self.instruction_vec[program_counter].clone()
+        self.instruction_vec[program_counter]
     }
     pub fn len(&self) -> usize {
         self.instruction_vec.len()
     }
+    pub fn is_empty(&self) -> bool {
+        self.instruction_vec.is_empty()
+    }
     pub fn new() -> Code {
         let operand_size_map_init = [
             (OperationCode::Isp, 1),
@@ -184,97 +492,488 @@ impl Code {
             (OperationCode::Ge, 0),
             (OperationCode::Le, 0),
             (OperationCode::Exit, 0),
+            (OperationCode::Alloc, 0),
+            (OperationCode::Free, 0),
+            (OperationCode::Lh, 0),
+            (OperationCode::Sh, 0),
+            (OperationCode::Puts, 0),
         ];
 
         Code {
             operand_size_map: HashMap::from(operand_size_map_init),
             instruction_vec: Vec::new(),
+            labels: HashMap::new(),
+            source_file: None,
+            source_map: Vec::new(),
+            data: Vec::new(),
+            data_labels: HashMap::new(),
         }
     }
 
-    fn remove_comments(input: &str) -> String {
-        let comment_regex = Regex::new(r"//.*$").unwrap();
-        comment_regex.replace_all(input, "").to_string()
+    fn remove_comments<'a>(comment_regex: &Regex, input: &'a str) -> &'a str {
+        match comment_regex.find(input) {
+            Some(comment) => &input[..comment.start()],
+            None => input,
+        }
     }
 
-    pub fn read(&mut self, file_path: &str) -> io::Result<()> {
-        let file = File::open(file_path)?;
-        let reader = io::BufReader::new(file);
+    fn is_label_name(name: &str) -> bool {
+        let mut chars = name.chars();
+        match chars.next() {
+            Some(head) if head.is_ascii_alphabetic() || head == '_' => {
+                chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+            }
+            _ => false,
+        }
+    }
 
-        for line in reader.lines() {
-            let command = match line {
-                Ok(command) => command,
-                Err(err) => {
-                    eprintln!("Error reading line: {}", err);
-                    return Err(err.into());
+    fn tokenize(line: &str) -> Vec<Token<'_>> {
+        let mut tokens = Vec::new();
+        let mut token_start: Option<(usize, usize)> = None;
+        let mut column = 0;
+        for (byte_index, c) in line.char_indices() {
+            column += 1;
+            match (c.is_whitespace(), token_start) {
+                (true, Some((start_byte, start_column))) => {
+                    tokens.push(Token {
+                        text: &line[start_byte..byte_index],
+                        column_start: start_column,
+                        column_end: column,
+                        byte_end: byte_index,
+                    });
+                    token_start = None;
                 }
-            };
+                (false, None) => token_start = Some((byte_index, column)),
+                _ => {}
+            }
+        }
+        if let Some((start_byte, start_column)) = token_start {
+            tokens.push(Token {
+                text: &line[start_byte..],
+                column_start: start_column,
+                column_end: column + 1,
+                byte_end: line.len(),
+            });
+        }
+        tokens
+    }
+
+    // バイトコード (マジックナンバーで判定) とテキストの両方を読める
+    pub fn read(&mut self, file_path: &str) -> Result<(), Vec<AssembleError>> {
+        let io_error = |error| {
+            vec![AssembleError::Io {
+                file_path: file_path.to_string(),
+                error,
+            }]
+        };
+        let bytes = fs::read(file_path).map_err(io_error)?;
+        if bytecode::is_bytecode(&bytes) {
+            let code = Code::from_bytes(&bytes).map_err(|error| {
+                vec![AssembleError::Bytecode {
+                    file_path: file_path.to_string(),
+                    error,
+                }]
+            })?;
+            return self.append_code(code, file_path);
+        }
+        let source = String::from_utf8(bytes)
+            .map_err(|error| io_error(io::Error::new(io::ErrorKind::InvalidData, error)))?;
+        self.parse(&source, file_path)
+    }
+
+    // 既に読んだコードと同じ名前のラベルがあれば何も追加しない.
+    // データを指すオペランドは定数と区別できず付け直せないので, データを持つコードは最初のデータとしてしか読めない
+    fn append_code(&mut self, code: Code, file_path: &str) -> Result<(), Vec<AssembleError>> {
+        let has_data = |code: &Code| !code.data.is_empty() || !code.data_labels.is_empty();
+        if has_data(self) && has_data(&code) {
+            return Err(vec![AssembleError::DataNotRelocatable {
+                file_path: file_path.to_string(),
+            }]);
+        }
+        let mut duplicates = code
+            .labels
+            .keys()
+            .chain(code.data_labels.keys())
+            .filter(|label| self.labels.contains_key(*label) || self.data_labels.contains_key(*label))
+            .collect::<Vec<_>>();
+        if !duplicates.is_empty() {
+            duplicates.sort();
+            // バイトコードのラベルには定義した行がないので, 行 0 の `label:` を指す
+            return Err(duplicates
+                .into_iter()
+                .map(|label| AssembleError::DuplicateLabel {
+                    span: SourceSpan {
+                        file_path: file_path.to_string(),
+                        line: 0,
+                        column_start: 1,
+                        column_end: label.chars().count() + 1,
+                        line_text: format!("{}:", label),
+                    },
+                    first_line: None,
+                })
+                .collect());
+        }
+
+        let base_address = self.instruction_vec.len();
+        let relocate = |instruction: Instruction| match instruction {
+            Instruction {
+                operation_code: OperationCode::Call,
+                operand: [Some(target), None],
+            } => Instruction {
+                operation_code: OperationCode::Call,
+                operand: [Some(target + base_address as i32), None],
+            },
+            _ => instruction,
+        };
+        self.instruction_vec
+            .extend(code.instruction_vec.into_iter().map(relocate));
+        self.source_map.extend(code.source_map);
+        self.labels.extend(
+            code.labels
+                .into_iter()
+                .map(|(label, address)| (label, address + base_address)),
+        );
+        self.data.extend(code.data);
+        self.data_labels.extend(code.data_labels);
+        if self.source_file.is_none() {
+            self.source_file = code.source_file;
+        }
+        Ok(())
+    }
 
-            let command_remove_comments = Code::remove_comments(&command);
-            let command_parse = command_remove_comments
-                .split_whitespace()
-                .collect::<Vec<_>>();
-
-            if !command_parse.is_empty() {
-                let operation_str = command_parse[0];
-                let operation_code_result = operation_str.to_string().parse::<OperationCode>();
-                if operation_code_result.is_err() {
-                    eprintln!("Error parsing OperationCode: {}", operation_str);
-                    return Err(io::Error::new(
-                        io::ErrorKind::InvalidData,
-                        "Invalid OperationCode",
-                    ));
+    // 1パス目でラベルのアドレスを確定し, 2パス目でオペランドを解決する
+    // エラーがあっても最後まで読み進め, 全てのエラーをまとめて返す
+    pub fn parse(&mut self, source: &str, file_path: &str) -> Result<(), Vec<AssembleError>> {
+        let span = |line_number: usize, line_text: &str, column_start: usize, column_end: usize| {
+            SourceSpan {
+                file_path: file_path.to_string(),
+                line: line_number,
+                column_start,
+                column_end,
+                line_text: line_text.to_string(),
+            }
+        };
+        let comment_regex = Regex::new(r"//.*$").unwrap();
+        let base_address = self.instruction_vec.len();
+        let mut errors = Vec::new();
+        let mut labels: HashMap<String, (usize, usize)> = HashMap::new();
+        let mut pending_instructions: Vec<PendingInstruction> = Vec::new();
+        let data_base = self.data.len();
+        let mut data = Vec::new();
+        let mut data_labels: HashMap<String, (usize, usize)> = HashMap::new();
+        let mut section = Section::Text;
+
+        for (index, line_text) in source.lines().enumerate() {
+            let line_number = index + 1;
+            let command_remove_comments = Code::remove_comments(&comment_regex, line_text);
+            let mut tokens = Code::tokenize(command_remove_comments).into_iter().peekable();
+
+            while let Some(label) = tokens.peek().and_then(|token| token.text.strip_suffix(':')) {
+                let token = tokens.next().unwrap();
+                let label_span = span(line_number, line_text, token.column_start, token.column_end - 1);
+                if !Code::is_label_name(label) {
+                    errors.push(AssembleError::InvalidLabelName { span: label_span });
+                } else if let Some((_, defined_line)) = labels.get(label).or_else(|| data_labels.get(label)) {
+                    errors.push(AssembleError::DuplicateLabel {
+                        span: label_span,
+                        first_line: Some(*defined_line),
+                    });
+                } else if self.labels.contains_key(label) || self.data_labels.contains_key(label) {
+                    errors.push(AssembleError::DuplicateLabel {
+                        span: label_span,
+                        first_line: None,
+                    });
+                } else if section == Section::Data {
+                    data_labels.insert(label.to_string(), (data_base + data.len(), line_number));
+                } else {
+                    let address = base_address + pending_instructions.len();
+                    labels.insert(label.to_string(), (address, line_number));
                 }
-                let operation_code = operation_code_result.unwrap();
-
-                if let Some(operand_size) = self.operand_size_map.get(&operation_code) {
-                    if operand_size + 1 != command_parse.len() {
-                        eprintln!("command '{}'", command_remove_comments);
-                        eprintln!(
-                            "'{}' has '{}' arguments but '{}' input arguments",
-                            operation_code.to_string(),
-                            operand_size,
-                            command_parse.len() - 1
-                        );
-                        return Err(io::Error::new(
-                            io::ErrorKind::InvalidData,
-                            "Invalid OperationCode",
-                        ));
-                    }
+            }
 
-                    fn parse_operand(command_parse: &[&str], index: usize) -> Result<Option<i32>, std::num::ParseIntError> {
-                        let inner_parse_operand = |value: &str| -> Result<Option<i32>, std::num::ParseIntError> {
-                            value.parse::<i32>().map(Some)
-                        };
-                    
-                        if command_parse.len() > index {
-                            inner_parse_operand(command_parse[index])
-                        } else {
-                            Ok(None)
+            let operation = match tokens.next() {
+                Some(operation) => operation,
+                None => continue,
+            };
+            let operands = tokens.collect::<Vec<_>>();
+
+            if operation.text.starts_with('.') {
+                let column_end = operands.last().unwrap_or(&operation).column_end;
+                let statement_span = span(line_number, line_text, operation.column_start, column_end);
+                let values = match (operation.text, section, operands.len()) {
+                    (".data", _, 0) => {
+                        section = Section::Data;
+                        continue;
+                    }
+                    (".text", _, 0) => {
+                        section = Section::Text;
+                        continue;
+                    }
+                    (".word" | ".zero" | ".string", Section::Text, _) => {
+                        errors.push(AssembleError::WrongSection {
+                            span: span(line_number, line_text, operation.column_start, operation.column_end),
+                            section: ".data",
+                        });
+                        continue;
+                    }
+                    (".word", _, _) => {
+                        let mut values = Vec::new();
+                        for operand in &operands {
+                            match operand.text.parse::<i32>() {
+                                Ok(value) => values.push(value),
+                                Err(_) => errors.push(AssembleError::InvalidOperand {
+                                    span: span(line_number, line_text, operand.column_start, operand.column_end),
+                                }),
+                            }
+                        }
+                        values
+                    }
+                    (".zero", _, 1) => match operands[0].text.parse::<usize>() {
+                        Ok(count) if count <= MAX_DATA_SIZE => vec![0; count],
+                        Ok(_) => {
+                            errors.push(AssembleError::DataTooLarge { span: statement_span });
+                            continue;
+                        }
+                        Err(_) => {
+                            errors.push(AssembleError::InvalidOperand {
+                                span: span(line_number, line_text, operands[0].column_start, operands[0].column_end),
+                            });
+                            continue;
+                        }
+                    },
+                    // 文字列は空白や // を含みうるので, 元の行から読む
+                    (".string", _, count) if count > 0 => {
+                        let rest = &line_text[operation.byte_end..];
+                        let literal_start = operation.byte_end + rest.len() - rest.trim_start().len();
+                        match Code::parse_string_literal(&line_text[literal_start..]) {
+                            Some(values) => values,
+                            None => {
+                                let column = |byte: usize| line_text[..byte].chars().count() + 1;
+                                errors.push(AssembleError::InvalidOperand {
+                                    span: span(line_number, line_text, column(literal_start), column(line_text.trim_end().len())),
+                                });
+                                continue;
+                            }
                         }
                     }
+                    _ => {
+                        errors.push(AssembleError::InvalidDirective { span: statement_span });
+                        continue;
+                    }
+                };
+                if data_base + data.len() + values.len() > MAX_DATA_SIZE {
+                    errors.push(AssembleError::DataTooLarge { span: statement_span });
+                    continue;
+                }
+                data.extend(values);
+                continue;
+            }
 
-                    let operand0 = parse_operand(&command_parse, 1).unwrap();
-                    let operand1 = parse_operand(&command_parse, 2).unwrap();
+            let operation_code = match operation.text.parse::<OperationCode>() {
+                Ok(operation_code) => operation_code,
+                Err(_) => {
+                    errors.push(AssembleError::InvalidOperationCode {
+                        span: span(line_number, line_text, operation.column_start, operation.column_end),
+                    });
+                    continue;
+                }
+            };
 
-                    self.append_instruction(operation_code, operand0, operand1);
-                } else {
-                    eprintln!(
-                        "OperationCode operand_size is not defined: {}",
-                        operation_str
-                    );
-                    return Err(io::Error::new(
-                        io::ErrorKind::InvalidData,
-                        "Invalid OperationCode",
-                    ));
+            if section == Section::Data {
+                errors.push(AssembleError::WrongSection {
+                    span: span(line_number, line_text, operation.column_start, operation.column_end),
+                    section: ".text",
+                });
+                continue;
+            }
+
+            let operand_size = self.operand_size_map[&operation_code];
+            if operand_size != operands.len() {
+                let column_end = operands.last().unwrap_or(&operation).column_end;
+                errors.push(AssembleError::OperandCount {
+                    span: span(line_number, line_text, operation.column_start, column_end),
+                    operation_code,
+                    expected: operand_size,
+                    found: operands.len(),
+                });
+                continue;
+            }
+
+            pending_instructions.push(PendingInstruction {
+                line_number,
+                line_text,
+                operation_code,
+                operands,
+            });
+        }
+
+        let mut instructions = Vec::with_capacity(pending_instructions.len());
+        for (index, pending) in pending_instructions.iter().enumerate() {
+            let address = base_address + index;
+            let mut operand = [None, None];
+            for (index, (slot, token)) in operand.iter_mut().zip(pending.operands.iter()).enumerate() {
+                let token_span = || {
+                    span(pending.line_number, pending.line_text, token.column_start, token.column_end)
+                };
+                match Code::resolve_operand(pending.operation_code, index, token.text, address, &labels, &data_labels) {
+                    Ok(value) => *slot = Some(value),
+                    Err(OperandError::Invalid) => {
+                        errors.push(AssembleError::InvalidOperand { span: token_span() })
+                    }
+                    Err(OperandError::UndefinedLabel) => {
+                        errors.push(AssembleError::UndefinedLabel { span: token_span() })
+                    }
+                    Err(OperandError::LabelNotAllowed) => {
+                        errors.push(AssembleError::LabelNotAllowed {
+                            span: token_span(),
+                            operation_code: pending.operation_code,
+                        })
+                    }
                 }
             }
+            instructions.push(Instruction {
+                operation_code: pending.operation_code,
+                operand,
+            });
         }
 
+        if !errors.is_empty() {
+            errors.sort_by_key(|error| error.span().map(|span| (span.line, span.column_start)));
+            return Err(errors);
+        }
+
+        self.instruction_vec.extend(instructions);
+        self.source_map.extend(
+            pending_instructions
+                .iter()
+                .map(|pending| Some(SourceLocation::new(file_path, pending.line_number, pending.line_text))),
+        );
+        self.labels
+            .extend(labels.into_iter().map(|(label, (address, _))| (label, address)));
+        self.data.extend(data);
+        self.data_labels
+            .extend(data_labels.into_iter().map(|(label, (offset, _))| (label, offset)));
+        if self.source_file.is_none() {
+            self.source_file = Some(file_path.to_string());
+        }
         Ok(())
     }
+
+    // データのラベルは B0 からのオフセットになり, LA / LV / SV の 2 番目と LC のオペランドに書ける
+    fn resolve_operand(
+        operation_code: OperationCode,
+        index: usize,
+        token: &str,
+        address: usize,
+        labels: &HashMap<String, (usize, usize)>,
+        data_labels: &HashMap<String, (usize, usize)>,
+    ) -> Result<i32, OperandError> {
+        if let Ok(value) = token.parse::<i32>() {
+            return Ok(value);
+        }
+        if !Code::is_label_name(token) {
+            return Err(OperandError::Invalid);
+        }
+
+        let target = labels.get(token).map(|(target, _)| *target as i32);
+        let offset = data_labels.get(token).map(|(offset, _)| *offset as i32);
+        match (operation_code, index, target, offset) {
+            (OperationCode::B | OperationCode::Bz, _, Some(target), _) => Ok(target - (address as i32 + 1)),
+            (OperationCode::Call, _, Some(target), _) => Ok(target),
+            (OperationCode::La | OperationCode::Lv | OperationCode::Sv, 1, _, Some(offset))
+            | (OperationCode::Lc, 0, _, Some(offset)) => Ok(offset),
+            (OperationCode::B | OperationCode::Bz | OperationCode::Call, _, None, None) => {
+                Err(OperandError::UndefinedLabel)
+            }
+            (_, _, Some(_), _) | (_, _, _, Some(_)) => Err(OperandError::LabelNotAllowed),
+            (_, _, None, None) => Err(OperandError::Invalid),
+        }
+    }
+
+    // "..." を文字ごとのセルと終端の 0 に変換する. 閉じた後ろにはコメントしか書けない
+    fn parse_string_literal(text: &str) -> Option<Vec<i32>> {
+        let mut chars = text.strip_prefix('"')?.char_indices();
+        let mut values = Vec::new();
+        let rest = loop {
+            let value = match chars.next()? {
+                (index, '"') => break &text[index + 2..],
+                (_, '\\') => match chars.next()?.1 {
+                    'n' => '\n',
+                    't' => '\t',
+                    '0' => '\0',
+                    '\\' => '\\',
+                    '"' => '"',
+                    '\'' => '\'',
+                    _ => return None,
+                },
+                (_, c) => c,
+            };
+            values.push(value as i32);
+        };
+        let rest = rest.trim_start();
+        if !rest.is_empty() && !rest.starts_with("//") {
+            return None;
+        }
+        values.push(0);
+        Some(values)
+    }
+
+    // 実行前に B0 からのセルに置く初期値
+    pub fn data(&self) -> &[i32] {
+        &self.data
+    }
+
+    // データのラベルと B0 からのオフセット
+    pub fn data_labels(&self) -> &HashMap<String, usize> {
+        &self.data_labels
+    }
+
+    pub(crate) fn set_data(&mut self, data: Vec<i32>, data_labels: HashMap<String, usize>) {
+        self.data = data;
+        self.data_labels = data_labels;
+    }
+
+    pub fn label_address(&self, label: &str) -> Option<usize> {
+        self.labels.get(label).copied()
+    }
+
+    pub fn labels(&self) -> &HashMap<String, usize> {
+        &self.labels
+    }
+
+    pub fn instructions(&self) -> &[Instruction] {
+        &self.instruction_vec
+    }
+
+    pub fn operand_size(&self, operation_code: OperationCode) -> usize {
+        self.operand_size_map[&operation_code]
+    }
+
+    pub fn source_file(&self) -> Option<&str> {
+        self.source_file.as_deref()
+    }
+
+    pub fn source_location(&self, program_counter: usize) -> Option<&SourceLocation> {
+        self.source_map.get(program_counter).and_then(|location| location.as_ref())
+    }
+
+    pub fn line_number(&self, program_counter: usize) -> Option<usize> {
+        self.source_location(program_counter).map(|location| location.line)
+    }
+
+    pub(crate) fn set_source_map(&mut self, source_file: Option<String>, source_map: Vec<Option<SourceLocation>>) {
+        self.source_file = source_file;
+        self.source_map = source_map;
+    }
+
+    pub(crate) fn insert_label(&mut self, label: String, address: usize) {
+        self.labels.insert(label, address);
+    }
+
+    // データは命令の前に置くので, データを指すオペランドの値はそのまま読み直せる
     pub fn write(&self, file_path: &str) -> io::Result<()> {
         let mut file = File::create(file_path)?;
+        write!(file, "{}", Disassembler::new(self).disassemble_data())?;
         self.instruction_vec
             .iter()
             .try_for_each(|instruction| writeln!(file, "{}", instruction))?;
@@ -288,9 +987,10 @@ impl Code {
         operand1: Option<i32>,
     ) {
         self.instruction_vec.push(Instruction {
-            operation_code: operation_code,
+            operation_code,
             operand: [operand0, operand1],
         });
+        self.source_map.push(None);
     }
 
     pub fn set_instruction(
@@ -301,7 +1001,7 @@ impl Code {
         operand1: Option<i32>,
     ) {
         self.instruction_vec[index] = Instruction {
-            operation_code: operation_code,
+            operation_code,
             operand: [operand0, operand1],
         };
     }
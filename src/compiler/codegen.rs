@@ -0,0 +1,422 @@
+use std::collections::HashMap;
+
+use super::ast::{
+    BinaryOperator, Declaration, Expression, ExpressionKind, Function, Program, Statement, StatementKind,
+    UnaryOperator,
+};
+use super::builtin;
+use super::semantic::constant_value;
+use crate::code::{Code, OperationCode, SourceLocation};
+
+// CALL の後の M[B1+1], M[B1+2] に呼び出し元の B1 と PC が入るので,
+// 引数と局所変数は B1+3 から並ぶ
+const FRAME_HEADER_SIZE: usize = 3;
+
+#[derive(Debug, Clone, Copy)]
+struct Variable {
+    base: i32,
+    offset: i32,
+    is_array: bool,
+}
+
+// 分岐先は番号付きのラベルで表し, 最後にアドレスを埋める
+struct Generator<'a> {
+    code: Code,
+    line_numbers: Vec<Option<usize>>,
+    line: Option<usize>,
+    labels: Vec<Option<usize>>,
+    branches: Vec<(usize, usize)>,
+    calls: Vec<(usize, &'a str)>,
+    scopes: Vec<HashMap<&'a str, Variable>>,
+    frame_size: usize,
+}
+
+// 意味検査を通ったプログラムだけを渡すこと
+pub fn generate(program: &Program, source: &str, file_path: &str) -> Code {
+    let mut generator = Generator {
+        code: Code::new(),
+        line_numbers: Vec::new(),
+        line: None,
+        labels: Vec::new(),
+        branches: Vec::new(),
+        calls: Vec::new(),
+        scopes: vec![HashMap::new()],
+        frame_size: 0,
+    };
+    generator.program(program);
+    generator.finish(source, file_path)
+}
+
+// 必ず return で終わる文か (後ろに置くコードが到達不能になる)
+fn returns(statement: &Statement) -> bool {
+    match &statement.kind {
+        StatementKind::Return(_) => true,
+        StatementKind::Block(statements) => statements.last().is_some_and(returns),
+        StatementKind::If {
+            then_branch,
+            else_branch: Some(else_branch),
+            ..
+        } => returns(then_branch) && returns(else_branch),
+        _ => false,
+    }
+}
+
+impl<'a> Generator<'a> {
+    fn emit(&mut self, operation_code: OperationCode, operand0: Option<i32>, operand1: Option<i32>) -> usize {
+        self.code.append_instruction(operation_code, operand0, operand1);
+        self.line_numbers.push(self.line);
+        self.code.len() - 1
+    }
+
+    fn emit0(&mut self, operation_code: OperationCode) {
+        self.emit(operation_code, None, None);
+    }
+
+    fn emit1(&mut self, operation_code: OperationCode, operand: i32) {
+        self.emit(operation_code, Some(operand), None);
+    }
+
+    fn emit2(&mut self, operation_code: OperationCode, operand0: i32, operand1: i32) {
+        self.emit(operation_code, Some(operand0), Some(operand1));
+    }
+
+    fn new_label(&mut self) -> usize {
+        self.labels.push(None);
+        self.labels.len() - 1
+    }
+
+    fn place_label(&mut self, label: usize) {
+        self.labels[label] = Some(self.code.len());
+    }
+
+    fn emit_branch(&mut self, operation_code: OperationCode, label: usize) {
+        let address = self.emit(operation_code, Some(0), None);
+        self.branches.push((address, label));
+    }
+
+    fn finish(mut self, source: &str, file_path: &str) -> Code {
+        for (address, label) in std::mem::take(&mut self.branches) {
+            let target = self.labels[label].unwrap();
+            let instruction = self.code.get_instruction(address);
+            let offset = target as i32 - (address as i32 + 1);
+            self.code
+                .set_instruction(address, instruction.operation_code, Some(offset), None);
+        }
+        for (address, name) in std::mem::take(&mut self.calls) {
+            let target = self.code.label_address(name).unwrap();
+            self.code
+                .set_instruction(address, OperationCode::Call, Some(target as i32), None);
+        }
+        let lines = source.lines().collect::<Vec<_>>();
+        let source_map = self
+            .line_numbers
+            .iter()
+            .map(|line| line.map(|line| SourceLocation::new(file_path, line, lines.get(line - 1).unwrap_or(&""))))
+            .collect();
+        self.code.set_source_map(Some(file_path.to_string()), source_map);
+        self.code
+    }
+
+    fn lookup(&self, name: &str) -> Variable {
+        self.scopes
+            .iter()
+            .rev()
+            .find_map(|scope| scope.get(name).copied())
+            .unwrap()
+    }
+
+    // ISP g; (大域変数の初期化); LC g; SB 1; CALL main; EXIT
+    fn program(&mut self, program: &'a Program) {
+        let mut global_size = 0;
+        for declaration in &program.globals {
+            self.scopes[0].insert(
+                &declaration.name,
+                Variable {
+                    base: 0,
+                    offset: global_size as i32,
+                    is_array: declaration.array_size.is_some(),
+                },
+            );
+            global_size += declaration.size();
+        }
+
+        self.emit1(OperationCode::Isp, global_size as i32);
+        for declaration in &program.globals {
+            if let Some(value) = declaration.initializer.as_ref().and_then(constant_value) {
+                self.line = Some(declaration.position.line);
+                let variable = self.lookup(&declaration.name);
+                self.emit2(OperationCode::La, variable.base, variable.offset);
+                self.emit1(OperationCode::Lc, value);
+                self.emit0(OperationCode::Si);
+            }
+        }
+        self.line = None;
+        self.emit1(OperationCode::Lc, global_size as i32);
+        self.emit1(OperationCode::Sb, 1);
+        self.emit_call("main");
+        self.emit0(OperationCode::Exit);
+
+        for function in &program.functions {
+            self.function(function);
+        }
+    }
+
+    fn emit_call(&mut self, name: &'a str) {
+        let address = self.emit(OperationCode::Call, Some(0), None);
+        self.calls.push((address, name));
+    }
+
+    fn function(&mut self, function: &'a Function) {
+        self.line = Some(function.position.line);
+        self.code.insert_label(function.name.clone(), self.code.len());
+
+        let mut parameters = HashMap::new();
+        for (index, parameter) in function.parameters.iter().enumerate() {
+            let variable = Variable {
+                base: 1,
+                offset: (FRAME_HEADER_SIZE + index) as i32,
+                is_array: false,
+            };
+            parameters.insert(parameter.name.as_str(), variable);
+        }
+        self.frame_size = FRAME_HEADER_SIZE + function.parameters.len();
+
+        // 局所変数の数は本体を生成し終わるまで分からないので後で埋める
+        let enter = self.emit(OperationCode::Isp, Some(0), None);
+        self.scopes.push(parameters);
+        self.block(&function.body);
+        self.scopes.pop();
+
+        // 末尾まで来たら 0 を返す
+        if !function.body.last().is_some_and(returns) {
+            self.emit2(OperationCode::La, 1, 0);
+            self.emit1(OperationCode::Lc, 0);
+            self.emit0(OperationCode::Si);
+            self.emit0(OperationCode::Ret);
+        }
+        self.code
+            .set_instruction(enter, OperationCode::Isp, Some(self.frame_size as i32), None);
+    }
+
+    fn block(&mut self, statements: &'a [Statement]) {
+        self.scopes.push(HashMap::new());
+        statements.iter().for_each(|statement| self.statement(statement));
+        self.scopes.pop();
+    }
+
+    fn nested(&mut self, statement: &'a Statement) {
+        self.block(std::slice::from_ref(statement));
+    }
+
+    fn declare(&mut self, declaration: &'a Declaration) {
+        if let Some(initializer) = &declaration.initializer {
+            self.emit2(OperationCode::La, 1, self.frame_size as i32);
+            self.expression(initializer);
+            self.emit0(OperationCode::Si);
+        }
+        let variable = Variable {
+            base: 1,
+            offset: self.frame_size as i32,
+            is_array: declaration.array_size.is_some(),
+        };
+        self.scopes.last_mut().unwrap().insert(&declaration.name, variable);
+        self.frame_size += declaration.size();
+    }
+
+    fn statement(&mut self, statement: &'a Statement) {
+        self.line = Some(statement.position.line);
+        match &statement.kind {
+            StatementKind::Declaration(declarations) => {
+                declarations.iter().for_each(|declaration| self.declare(declaration));
+            }
+            StatementKind::Assign { name, index, value } => {
+                self.address(name, index.as_ref());
+                self.expression(value);
+                self.emit0(OperationCode::Si);
+            }
+            StatementKind::Expression(expression) => {
+                self.expression(expression);
+                self.emit1(OperationCode::Isp, -1);
+            }
+            StatementKind::If {
+                condition,
+                then_branch,
+                else_branch,
+            } => {
+                let else_label = self.new_label();
+                self.expression(condition);
+                self.emit_branch(OperationCode::Bz, else_label);
+                self.nested(then_branch);
+                match else_branch {
+                    Some(else_branch) => {
+                        let end_label = self.new_label();
+                        if !returns(then_branch) {
+                            self.emit_branch(OperationCode::B, end_label);
+                        }
+                        self.place_label(else_label);
+                        self.nested(else_branch);
+                        self.place_label(end_label);
+                    }
+                    None => self.place_label(else_label),
+                }
+            }
+            StatementKind::While { condition, body } => {
+                let (loop_label, end_label) = (self.new_label(), self.new_label());
+                self.place_label(loop_label);
+                self.expression(condition);
+                self.emit_branch(OperationCode::Bz, end_label);
+                self.nested(body);
+                self.line = Some(statement.position.line);
+                self.emit_branch(OperationCode::B, loop_label);
+                self.place_label(end_label);
+            }
+            StatementKind::For {
+                init,
+                condition,
+                step,
+                body,
+            } => {
+                let (loop_label, end_label) = (self.new_label(), self.new_label());
+                if let Some(init) = init {
+                    self.statement(init);
+                }
+                self.place_label(loop_label);
+                if let Some(condition) = condition {
+                    self.line = Some(statement.position.line);
+                    self.expression(condition);
+                    self.emit_branch(OperationCode::Bz, end_label);
+                }
+                self.nested(body);
+                if let Some(step) = step {
+                    self.statement(step);
+                }
+                self.line = Some(statement.position.line);
+                self.emit_branch(OperationCode::B, loop_label);
+                self.place_label(end_label);
+            }
+            StatementKind::Return(value) => {
+                if let Some(value) = value {
+                    self.emit2(OperationCode::La, 1, 0);
+                    self.expression(value);
+                    self.emit0(OperationCode::Si);
+                }
+                self.emit0(OperationCode::Ret);
+            }
+            StatementKind::Block(statements) => self.block(statements),
+        }
+    }
+
+    // 変数または配列要素のアドレスを積む
+    fn address(&mut self, name: &str, index: Option<&'a Expression>) {
+        let variable = self.lookup(name);
+        self.emit2(OperationCode::La, variable.base, variable.offset);
+        if let Some(index) = index {
+            self.expression(index);
+            self.emit0(OperationCode::Add);
+        }
+    }
+
+    fn expression(&mut self, expression: &'a Expression) {
+        match &expression.kind {
+            ExpressionKind::Number(value) => self.emit1(OperationCode::Lc, *value),
+            ExpressionKind::Variable(name) => {
+                let variable = self.lookup(name);
+                debug_assert!(!variable.is_array);
+                self.emit2(OperationCode::Lv, variable.base, variable.offset);
+            }
+            ExpressionKind::Index { name, index } => {
+                self.address(name, Some(index));
+                self.emit0(OperationCode::Li);
+            }
+            ExpressionKind::Call { name, arguments } => self.call(name, arguments),
+            ExpressionKind::Unary { operator, operand } => {
+                self.expression(operand);
+                match operator {
+                    UnaryOperator::Negate => self.emit0(OperationCode::Inv),
+                    UnaryOperator::Not => {
+                        self.emit1(OperationCode::Lc, 0);
+                        self.emit0(OperationCode::Eq);
+                    }
+                }
+            }
+            ExpressionKind::Binary {
+                operator: BinaryOperator::And,
+                left,
+                right,
+            } => {
+                // どちらかが 0 なら右辺を評価せずに 0
+                let (false_label, end_label) = (self.new_label(), self.new_label());
+                self.expression(left);
+                self.emit_branch(OperationCode::Bz, false_label);
+                self.expression(right);
+                self.emit_branch(OperationCode::Bz, false_label);
+                self.emit1(OperationCode::Lc, 1);
+                self.emit_branch(OperationCode::B, end_label);
+                self.place_label(false_label);
+                self.emit1(OperationCode::Lc, 0);
+                self.place_label(end_label);
+            }
+            ExpressionKind::Binary {
+                operator: BinaryOperator::Or,
+                left,
+                right,
+            } => {
+                // 左辺が 0 でなければ右辺を評価せずに 1
+                let (right_label, end_label) = (self.new_label(), self.new_label());
+                self.expression(left);
+                self.emit_branch(OperationCode::Bz, right_label);
+                self.emit1(OperationCode::Lc, 1);
+                self.emit_branch(OperationCode::B, end_label);
+                self.place_label(right_label);
+                self.expression(right);
+                self.emit1(OperationCode::Lc, 0);
+                self.emit0(OperationCode::Ne);
+                self.place_label(end_label);
+            }
+            ExpressionKind::Binary { operator, left, right } => {
+                self.expression(left);
+                self.expression(right);
+                let operation_code = match operator {
+                    BinaryOperator::Add => OperationCode::Add,
+                    BinaryOperator::Sub => OperationCode::Sub,
+                    BinaryOperator::Mul => OperationCode::Mul,
+                    BinaryOperator::Div => OperationCode::Div,
+                    BinaryOperator::Mod => OperationCode::Mod,
+                    BinaryOperator::Equal => OperationCode::Eq,
+                    BinaryOperator::NotEqual => OperationCode::Ne,
+                    BinaryOperator::Less => OperationCode::Lt,
+                    BinaryOperator::Greater => OperationCode::Gt,
+                    BinaryOperator::LessEqual => OperationCode::Le,
+                    BinaryOperator::GreaterEqual => OperationCode::Ge,
+                    BinaryOperator::And | BinaryOperator::Or => unreachable!(),
+                };
+                self.emit0(operation_code);
+            }
+        }
+    }
+
+    // ISP 3; 引数...; ISP -(3+n); CALL f で, 戻ると返り値が積まれている
+    fn call(&mut self, name: &'a str, arguments: &'a [Expression]) {
+        if let Some((operation_code, _)) = builtin(name) {
+            arguments.iter().for_each(|argument| self.expression(argument));
+            // putc / puti / free も式なので引数の値を残し, store は 0 を返す
+            match operation_code {
+                OperationCode::Putc | OperationCode::Puti | OperationCode::Free => {
+                    self.emit0(OperationCode::Dup);
+                    self.emit0(operation_code);
+                }
+                OperationCode::Sh => {
+                    self.emit0(operation_code);
+                    self.emit1(OperationCode::Lc, 0);
+                }
+                _ => self.emit0(operation_code),
+            }
+            return;
+        }
+
+        self.emit1(OperationCode::Isp, FRAME_HEADER_SIZE as i32);
+        arguments.iter().for_each(|argument| self.expression(argument));
+        self.emit1(OperationCode::Isp, -((FRAME_HEADER_SIZE + arguments.len()) as i32));
+        self.emit_call(name);
+    }
+}
@@ -0,0 +1,303 @@
+use super::ast::Position;
+use super::CompileError;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TokenKind {
+    Number(i32),
+    Identifier(String),
+    Int,
+    If,
+    Else,
+    While,
+    For,
+    Return,
+    LeftParen,
+    RightParen,
+    LeftBrace,
+    RightBrace,
+    LeftBracket,
+    RightBracket,
+    Comma,
+    Semicolon,
+    Assign,
+    Plus,
+    Minus,
+    Star,
+    Slash,
+    Percent,
+    Equal,
+    NotEqual,
+    Less,
+    Greater,
+    LessEqual,
+    GreaterEqual,
+    AndAnd,
+    OrOr,
+    Not,
+    Eof,
+}
+
+impl TokenKind {
+    pub fn describe(&self) -> String {
+        match self {
+            TokenKind::Number(value) => format!("number `{}`", value),
+            TokenKind::Identifier(name) => format!("identifier `{}`", name),
+            TokenKind::Eof => "end of file".to_string(),
+            _ => format!("`{}`", self.symbol()),
+        }
+    }
+
+    fn symbol(&self) -> &'static str {
+        match self {
+            TokenKind::Int => "int",
+            TokenKind::If => "if",
+            TokenKind::Else => "else",
+            TokenKind::While => "while",
+            TokenKind::For => "for",
+            TokenKind::Return => "return",
+            TokenKind::LeftParen => "(",
+            TokenKind::RightParen => ")",
+            TokenKind::LeftBrace => "{",
+            TokenKind::RightBrace => "}",
+            TokenKind::LeftBracket => "[",
+            TokenKind::RightBracket => "]",
+            TokenKind::Comma => ",",
+            TokenKind::Semicolon => ";",
+            TokenKind::Assign => "=",
+            TokenKind::Plus => "+",
+            TokenKind::Minus => "-",
+            TokenKind::Star => "*",
+            TokenKind::Slash => "/",
+            TokenKind::Percent => "%",
+            TokenKind::Equal => "==",
+            TokenKind::NotEqual => "!=",
+            TokenKind::Less => "<",
+            TokenKind::Greater => ">",
+            TokenKind::LessEqual => "<=",
+            TokenKind::GreaterEqual => ">=",
+            TokenKind::AndAnd => "&&",
+            TokenKind::OrOr => "||",
+            TokenKind::Not => "!",
+            TokenKind::Number(_) | TokenKind::Identifier(_) | TokenKind::Eof => "",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub position: Position,
+}
+
+struct Lexer<'a> {
+    chars: Vec<char>,
+    index: usize,
+    line: usize,
+    column: usize,
+    file_path: &'a str,
+    source: &'a str,
+}
+
+pub fn tokenize(source: &str, file_path: &str) -> Result<Vec<Token>, CompileError> {
+    let mut lexer = Lexer {
+        chars: source.chars().collect(),
+        index: 0,
+        line: 1,
+        column: 1,
+        file_path,
+        source,
+    };
+    let mut tokens = Vec::new();
+    loop {
+        let token = lexer.next_token()?;
+        let is_eof = token.kind == TokenKind::Eof;
+        tokens.push(token);
+        if is_eof {
+            return Ok(tokens);
+        }
+    }
+}
+
+impl Lexer<'_> {
+    fn peek(&self, offset: usize) -> Option<char> {
+        self.chars.get(self.index + offset).copied()
+    }
+
+    fn advance(&mut self) -> Option<char> {
+        let c = self.peek(0)?;
+        self.index += 1;
+        if c == '\n' {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
+        Some(c)
+    }
+
+    fn error(&self, position: Position, message: String) -> CompileError {
+        CompileError::new(self.source, self.file_path, position, message)
+    }
+
+    fn skip_whitespace_and_comments(&mut self) -> Result<(), CompileError> {
+        loop {
+            match (self.peek(0), self.peek(1)) {
+                (Some(c), _) if c.is_whitespace() => {
+                    self.advance();
+                }
+                (Some('/'), Some('/')) => {
+                    while self.peek(0).is_some_and(|c| c != '\n') {
+                        self.advance();
+                    }
+                }
+                (Some('/'), Some('*')) => {
+                    let position = Position::new(self.line, self.column, 2);
+                    self.advance();
+                    self.advance();
+                    loop {
+                        match (self.peek(0), self.peek(1)) {
+                            (Some('*'), Some('/')) => {
+                                self.advance();
+                                self.advance();
+                                break;
+                            }
+                            (Some(_), _) => {
+                                self.advance();
+                            }
+                            (None, _) => return Err(self.error(position, "unterminated comment".to_string())),
+                        }
+                    }
+                }
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    fn next_token(&mut self) -> Result<Token, CompileError> {
+        self.skip_whitespace_and_comments()?;
+        let (line, column) = (self.line, self.column);
+        let token = |kind: TokenKind, length: usize| Token {
+            kind,
+            position: Position::new(line, column, length),
+        };
+
+        let c = match self.advance() {
+            Some(c) => c,
+            None => return Ok(token(TokenKind::Eof, 1)),
+        };
+
+        if c.is_ascii_digit() {
+            let mut text = c.to_string();
+            while let Some(digit) = self.peek(0).filter(|c| c.is_ascii_alphanumeric()) {
+                text.push(digit);
+                self.advance();
+            }
+            return match text.parse::<i32>() {
+                Ok(value) => Ok(token(TokenKind::Number(value), text.len())),
+                Err(_) => Err(self.error(
+                    Position::new(line, column, text.len()),
+                    format!("invalid number `{}`", text),
+                )),
+            };
+        }
+
+        if c.is_ascii_alphabetic() || c == '_' {
+            let mut text = c.to_string();
+            while let Some(next) = self.peek(0).filter(|c| c.is_ascii_alphanumeric() || *c == '_') {
+                text.push(next);
+                self.advance();
+            }
+            let length = text.len();
+            let kind = match text.as_str() {
+                "int" => TokenKind::Int,
+                "if" => TokenKind::If,
+                "else" => TokenKind::Else,
+                "while" => TokenKind::While,
+                "for" => TokenKind::For,
+                "return" => TokenKind::Return,
+                _ => TokenKind::Identifier(text),
+            };
+            return Ok(token(kind, length));
+        }
+
+        if c == '\'' {
+            return self.char_literal(line, column);
+        }
+
+        let two_char = match (c, self.peek(0)) {
+            ('=', Some('=')) => Some(TokenKind::Equal),
+            ('!', Some('=')) => Some(TokenKind::NotEqual),
+            ('<', Some('=')) => Some(TokenKind::LessEqual),
+            ('>', Some('=')) => Some(TokenKind::GreaterEqual),
+            ('&', Some('&')) => Some(TokenKind::AndAnd),
+            ('|', Some('|')) => Some(TokenKind::OrOr),
+            _ => None,
+        };
+        if let Some(kind) = two_char {
+            self.advance();
+            return Ok(token(kind, 2));
+        }
+
+        let kind = match c {
+            '(' => TokenKind::LeftParen,
+            ')' => TokenKind::RightParen,
+            '{' => TokenKind::LeftBrace,
+            '}' => TokenKind::RightBrace,
+            '[' => TokenKind::LeftBracket,
+            ']' => TokenKind::RightBracket,
+            ',' => TokenKind::Comma,
+            ';' => TokenKind::Semicolon,
+            '=' => TokenKind::Assign,
+            '+' => TokenKind::Plus,
+            '-' => TokenKind::Minus,
+            '*' => TokenKind::Star,
+            '/' => TokenKind::Slash,
+            '%' => TokenKind::Percent,
+            '<' => TokenKind::Less,
+            '>' => TokenKind::Greater,
+            '!' => TokenKind::Not,
+            _ => {
+                return Err(self.error(
+                    Position::new(line, column, 1),
+                    format!("unexpected character `{}`", c),
+                ))
+            }
+        };
+        Ok(token(kind, 1))
+    }
+
+    fn char_literal(&mut self, line: usize, column: usize) -> Result<Token, CompileError> {
+        let value = match self.advance() {
+            Some('\\') => match self.advance() {
+                Some('n') => '\n',
+                Some('t') => '\t',
+                Some('0') => '\0',
+                Some('\\') => '\\',
+                Some('\'') => '\'',
+                _ => {
+                    return Err(self.error(
+                        Position::new(line, column, self.column - column),
+                        "unknown escape sequence".to_string(),
+                    ))
+                }
+            },
+            Some(c) if c != '\'' && c != '\n' => c,
+            _ => {
+                return Err(self.error(
+                    Position::new(line, column, self.column - column),
+                    "empty character literal".to_string(),
+                ))
+            }
+        };
+        if self.advance() != Some('\'') {
+            return Err(self.error(
+                Position::new(line, column, self.column - column),
+                "unterminated character literal".to_string(),
+            ));
+        }
+        Ok(Token {
+            kind: TokenKind::Number(value as i32),
+            position: Position::new(line, column, self.column - column),
+        })
+    }
+}
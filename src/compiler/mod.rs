@@ -0,0 +1,96 @@
+//! C の小さなサブセットを VSM のコードに変換するコンパイラ
+//!
+//! int 型の変数と 1 次元配列, 引数付きの関数, if / while / for / return,
+//! 組み込み関数 getc() / geti() / putc(e) / puti(e) を扱える.
+//! ヒープは alloc(n) / free(p) / load(p) / store(p, v) で使う. ポインタは int で, p + i で i 番目のセルを指す.
+//! 生成するコードは fact.vsm などと同じく ISP / SB / CALL / RET によるフレームを使う.
+
+mod ast;
+mod codegen;
+mod lexer;
+mod parser;
+mod semantic;
+
+use core::fmt;
+use std::fs;
+
+use crate::code::{Code, OperationCode, SourceSpan};
+use ast::Position;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompileError {
+    pub span: Option<SourceSpan>,
+    pub message: String,
+}
+
+impl CompileError {
+    fn new(source: &str, file_path: &str, position: Position, message: String) -> CompileError {
+        let line_text = source.lines().nth(position.line - 1).unwrap_or_default();
+        CompileError {
+            span: Some(SourceSpan {
+                file_path: file_path.to_string(),
+                line: position.line,
+                column_start: position.column,
+                column_end: position.column + position.length,
+                line_text: line_text.to_string(),
+            }),
+            message,
+        }
+    }
+
+    pub fn render(&self) -> String {
+        match &self.span {
+            Some(span) => span.render(&self.message, None),
+            None => format!("error: {}\n", self.message),
+        }
+    }
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.span {
+            Some(span) => write!(
+                f,
+                "{}:{}:{}: {}",
+                span.file_path, span.line, span.column_start, self.message
+            ),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+impl std::error::Error for CompileError {}
+
+// 組み込み関数の命令と引数の数
+fn builtin(name: &str) -> Option<(OperationCode, usize)> {
+    match name {
+        "getc" => Some((OperationCode::Getc, 0)),
+        "geti" => Some((OperationCode::Geti, 0)),
+        "putc" => Some((OperationCode::Putc, 1)),
+        "puti" => Some((OperationCode::Puti, 1)),
+        "alloc" => Some((OperationCode::Alloc, 1)),
+        "free" => Some((OperationCode::Free, 1)),
+        "load" => Some((OperationCode::Lh, 1)),
+        "store" => Some((OperationCode::Sh, 2)),
+        _ => None,
+    }
+}
+
+pub fn compile(source: &str, file_path: &str) -> Result<Code, Vec<CompileError>> {
+    let tokens = lexer::tokenize(source, file_path).map_err(|err| vec![err])?;
+    let program = parser::Parser::new(tokens, source, file_path)
+        .parse_program()
+        .map_err(|err| vec![err])?;
+    semantic::check(&program, source, file_path)?;
+    Ok(codegen::generate(&program, source, file_path))
+}
+
+pub fn compile_file(file_path: &str) -> Result<Code, Vec<CompileError>> {
+    match fs::read_to_string(file_path) {
+        Ok(source) => compile(&source, file_path),
+        Err(err) => Err(vec![CompileError {
+            span: None,
+            message: format!("cannot read `{}`: {}", file_path, err),
+        }]),
+    }
+}
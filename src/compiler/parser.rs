@@ -0,0 +1,414 @@
+use super::ast::{
+    BinaryOperator, Declaration, Expression, ExpressionKind, Function, Parameter, Position, Program, Statement,
+    StatementKind, UnaryOperator,
+};
+use super::lexer::{Token, TokenKind};
+use super::CompileError;
+
+// 深い入れ子でスタックが溢れないように, 式と文の入れ子の深さを制限する
+const MAX_NESTING_DEPTH: usize = 100;
+
+// 再帰下降パーサ. 最初の構文エラーで止まる
+pub struct Parser<'a> {
+    tokens: Vec<Token>,
+    index: usize,
+    source: &'a str,
+    file_path: &'a str,
+    depth: usize,
+}
+
+type ParseResult<T> = Result<T, CompileError>;
+
+impl<'a> Parser<'a> {
+    pub fn new(tokens: Vec<Token>, source: &'a str, file_path: &'a str) -> Parser<'a> {
+        Parser {
+            tokens,
+            index: 0,
+            source,
+            file_path,
+            depth: 0,
+        }
+    }
+
+    fn peek(&self) -> &Token {
+        &self.tokens[self.index.min(self.tokens.len() - 1)]
+    }
+
+    fn peek_kind(&self, offset: usize) -> &TokenKind {
+        &self.tokens[(self.index + offset).min(self.tokens.len() - 1)].kind
+    }
+
+    fn advance(&mut self) -> Token {
+        let token = self.peek().clone();
+        if token.kind != TokenKind::Eof {
+            self.index += 1;
+        }
+        token
+    }
+
+    fn check(&self, kind: &TokenKind) -> bool {
+        self.peek_kind(0) == kind
+    }
+
+    fn eat(&mut self, kind: &TokenKind) -> bool {
+        if self.check(kind) {
+            self.advance();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn error(&self, position: Position, message: String) -> CompileError {
+        CompileError::new(self.source, self.file_path, position, message)
+    }
+
+    fn nested<T>(&mut self, what: &str, parse: fn(&mut Parser<'a>) -> ParseResult<T>) -> ParseResult<T> {
+        if self.depth >= MAX_NESTING_DEPTH {
+            return Err(self.error(self.peek().position, format!("{} nested too deeply", what)));
+        }
+        self.depth += 1;
+        let result = parse(self);
+        self.depth -= 1;
+        result
+    }
+
+    fn unexpected(&self, expected: &str) -> CompileError {
+        let token = self.peek();
+        self.error(
+            token.position,
+            format!("expected {}, found {}", expected, token.kind.describe()),
+        )
+    }
+
+    fn expect(&mut self, kind: &TokenKind) -> ParseResult<Token> {
+        if self.check(kind) {
+            Ok(self.advance())
+        } else {
+            Err(self.unexpected(&kind.describe()))
+        }
+    }
+
+    fn expect_identifier(&mut self) -> ParseResult<(String, Position)> {
+        match self.peek_kind(0).clone() {
+            TokenKind::Identifier(name) => {
+                let token = self.advance();
+                Ok((name, token.position))
+            }
+            _ => Err(self.unexpected("an identifier")),
+        }
+    }
+
+    pub fn parse_program(&mut self) -> ParseResult<Program> {
+        let mut program = Program {
+            globals: Vec::new(),
+            functions: Vec::new(),
+        };
+        while !self.check(&TokenKind::Eof) {
+            self.expect(&TokenKind::Int)?;
+            if matches!(self.peek_kind(0), TokenKind::Identifier(_)) && self.peek_kind(1) == &TokenKind::LeftParen {
+                program.functions.push(self.parse_function()?);
+            } else {
+                program.globals.extend(self.parse_declarators()?);
+            }
+        }
+        Ok(program)
+    }
+
+    fn parse_function(&mut self) -> ParseResult<Function> {
+        let (name, position) = self.expect_identifier()?;
+        self.expect(&TokenKind::LeftParen)?;
+        let mut parameters = Vec::new();
+        if !self.check(&TokenKind::RightParen) {
+            loop {
+                self.expect(&TokenKind::Int)?;
+                let (name, position) = self.expect_identifier()?;
+                parameters.push(Parameter { name, position });
+                if !self.eat(&TokenKind::Comma) {
+                    break;
+                }
+            }
+        }
+        self.expect(&TokenKind::RightParen)?;
+        let body = self.parse_block()?;
+        Ok(Function {
+            name,
+            position,
+            parameters,
+            body,
+        })
+    }
+
+    // int の後ろの "a, b[10], c = 1;" を読む
+    fn parse_declarators(&mut self) -> ParseResult<Vec<Declaration>> {
+        let mut declarations = Vec::new();
+        loop {
+            let (name, position) = self.expect_identifier()?;
+            let array_size = if self.eat(&TokenKind::LeftBracket) {
+                let size = match self.peek_kind(0) {
+                    TokenKind::Number(size) => *size,
+                    _ => return Err(self.unexpected("an array size")),
+                };
+                self.advance();
+                self.expect(&TokenKind::RightBracket)?;
+                Some(size)
+            } else {
+                None
+            };
+            let initializer = if self.eat(&TokenKind::Assign) {
+                Some(self.parse_expression()?)
+            } else {
+                None
+            };
+            declarations.push(Declaration {
+                name,
+                position,
+                array_size,
+                initializer,
+            });
+            if !self.eat(&TokenKind::Comma) {
+                break;
+            }
+        }
+        self.expect(&TokenKind::Semicolon)?;
+        Ok(declarations)
+    }
+
+    fn parse_block(&mut self) -> ParseResult<Vec<Statement>> {
+        self.expect(&TokenKind::LeftBrace)?;
+        let mut statements = Vec::new();
+        while !self.check(&TokenKind::RightBrace) {
+            if self.check(&TokenKind::Eof) {
+                return Err(self.unexpected("`}`"));
+            }
+            statements.push(self.parse_statement()?);
+        }
+        self.advance();
+        Ok(statements)
+    }
+
+    fn parse_statement(&mut self) -> ParseResult<Statement> {
+        let position = self.peek().position;
+        let kind = match self.peek_kind(0) {
+            TokenKind::Int => {
+                self.advance();
+                StatementKind::Declaration(self.parse_declarators()?)
+            }
+            TokenKind::LeftBrace => StatementKind::Block(self.nested("statement", Parser::parse_block)?),
+            TokenKind::Semicolon => {
+                self.advance();
+                StatementKind::Block(Vec::new())
+            }
+            TokenKind::If => {
+                self.advance();
+                let condition = self.parse_condition()?;
+                let then_branch = Box::new(self.nested("statement", Parser::parse_statement)?);
+                let else_branch = if self.eat(&TokenKind::Else) {
+                    Some(Box::new(self.nested("statement", Parser::parse_statement)?))
+                } else {
+                    None
+                };
+                StatementKind::If {
+                    condition,
+                    then_branch,
+                    else_branch,
+                }
+            }
+            TokenKind::While => {
+                self.advance();
+                let condition = self.parse_condition()?;
+                let body = Box::new(self.nested("statement", Parser::parse_statement)?);
+                StatementKind::While { condition, body }
+            }
+            TokenKind::For => {
+                self.advance();
+                self.expect(&TokenKind::LeftParen)?;
+                let init = self.parse_optional_simple_statement(&TokenKind::Semicolon)?;
+                self.expect(&TokenKind::Semicolon)?;
+                let condition = if self.check(&TokenKind::Semicolon) {
+                    None
+                } else {
+                    Some(self.parse_expression()?)
+                };
+                self.expect(&TokenKind::Semicolon)?;
+                let step = self.parse_optional_simple_statement(&TokenKind::RightParen)?;
+                self.expect(&TokenKind::RightParen)?;
+                let body = Box::new(self.nested("statement", Parser::parse_statement)?);
+                StatementKind::For {
+                    init,
+                    condition,
+                    step,
+                    body,
+                }
+            }
+            TokenKind::Return => {
+                self.advance();
+                let value = if self.check(&TokenKind::Semicolon) {
+                    None
+                } else {
+                    Some(self.parse_expression()?)
+                };
+                self.expect(&TokenKind::Semicolon)?;
+                StatementKind::Return(value)
+            }
+            _ => {
+                let statement = self.parse_simple_statement()?;
+                self.expect(&TokenKind::Semicolon)?;
+                return Ok(statement);
+            }
+        };
+        Ok(Statement { kind, position })
+    }
+
+    fn parse_condition(&mut self) -> ParseResult<Expression> {
+        self.expect(&TokenKind::LeftParen)?;
+        let condition = self.parse_expression()?;
+        self.expect(&TokenKind::RightParen)?;
+        Ok(condition)
+    }
+
+    fn parse_optional_simple_statement(&mut self, terminator: &TokenKind) -> ParseResult<Option<Box<Statement>>> {
+        if self.check(terminator) {
+            Ok(None)
+        } else {
+            Ok(Some(Box::new(self.parse_simple_statement()?)))
+        }
+    }
+
+    // 代入か式. 代入は文としてのみ書ける
+    fn parse_simple_statement(&mut self) -> ParseResult<Statement> {
+        let position = self.peek().position;
+        let expression = self.parse_expression()?;
+        if !self.check(&TokenKind::Assign) {
+            return Ok(Statement {
+                kind: StatementKind::Expression(expression),
+                position,
+            });
+        }
+
+        let (name, index) = match expression.kind {
+            ExpressionKind::Variable(name) => (name, None),
+            ExpressionKind::Index { name, index } => (name, Some(*index)),
+            _ => return Err(self.error(expression.position, "invalid left-hand side of assignment".to_string())),
+        };
+        self.advance();
+        let value = self.parse_expression()?;
+        Ok(Statement {
+            kind: StatementKind::Assign { name, index, value },
+            position,
+        })
+    }
+
+    pub fn parse_expression(&mut self) -> ParseResult<Expression> {
+        self.nested("expression", |parser| parser.parse_binary(0))
+    }
+
+    // 優先順位の低い順に並べる
+    fn binary_operator(kind: &TokenKind) -> Option<(BinaryOperator, usize)> {
+        Some(match kind {
+            TokenKind::OrOr => (BinaryOperator::Or, 0),
+            TokenKind::AndAnd => (BinaryOperator::And, 1),
+            TokenKind::Equal => (BinaryOperator::Equal, 2),
+            TokenKind::NotEqual => (BinaryOperator::NotEqual, 2),
+            TokenKind::Less => (BinaryOperator::Less, 3),
+            TokenKind::Greater => (BinaryOperator::Greater, 3),
+            TokenKind::LessEqual => (BinaryOperator::LessEqual, 3),
+            TokenKind::GreaterEqual => (BinaryOperator::GreaterEqual, 3),
+            TokenKind::Plus => (BinaryOperator::Add, 4),
+            TokenKind::Minus => (BinaryOperator::Sub, 4),
+            TokenKind::Star => (BinaryOperator::Mul, 5),
+            TokenKind::Slash => (BinaryOperator::Div, 5),
+            TokenKind::Percent => (BinaryOperator::Mod, 5),
+            _ => return None,
+        })
+    }
+
+    // 左結合の連鎖はループで組み立てるが AST は深くなるので, 折り込んだ演算子も入れ子の深さに数える
+    fn parse_binary(&mut self, min_precedence: usize) -> ParseResult<Expression> {
+        let depth = self.depth;
+        let mut left = self.parse_unary()?;
+        while let Some((operator, precedence)) = Parser::binary_operator(self.peek_kind(0)) {
+            if precedence < min_precedence {
+                break;
+            }
+            if self.depth >= MAX_NESTING_DEPTH {
+                return Err(self.error(self.peek().position, "expression nested too deeply".to_string()));
+            }
+            self.depth += 1;
+            let operator_position = self.advance().position;
+            let right = self.parse_binary(precedence + 1)?;
+            left = Expression {
+                kind: ExpressionKind::Binary {
+                    operator,
+                    left: Box::new(left),
+                    right: Box::new(right),
+                },
+                position: operator_position,
+            };
+        }
+        self.depth = depth;
+        Ok(left)
+    }
+
+    fn parse_unary(&mut self) -> ParseResult<Expression> {
+        let operator = match self.peek_kind(0) {
+            TokenKind::Minus => UnaryOperator::Negate,
+            TokenKind::Not => UnaryOperator::Not,
+            _ => return self.parse_primary(),
+        };
+        let position = self.advance().position;
+        let operand = self.nested("expression", Parser::parse_unary)?;
+        Ok(Expression {
+            kind: ExpressionKind::Unary {
+                operator,
+                operand: Box::new(operand),
+            },
+            position,
+        })
+    }
+
+    fn parse_primary(&mut self) -> ParseResult<Expression> {
+        match self.peek_kind(0).clone() {
+            TokenKind::Number(value) => {
+                let position = self.advance().position;
+                Ok(Expression {
+                    kind: ExpressionKind::Number(value),
+                    position,
+                })
+            }
+            TokenKind::LeftParen => {
+                self.advance();
+                let expression = self.parse_expression()?;
+                self.expect(&TokenKind::RightParen)?;
+                Ok(expression)
+            }
+            TokenKind::Identifier(name) => {
+                let position = self.advance().position;
+                let kind = if self.eat(&TokenKind::LeftParen) {
+                    let mut arguments = Vec::new();
+                    if !self.check(&TokenKind::RightParen) {
+                        loop {
+                            arguments.push(self.parse_expression()?);
+                            if !self.eat(&TokenKind::Comma) {
+                                break;
+                            }
+                        }
+                    }
+                    self.expect(&TokenKind::RightParen)?;
+                    ExpressionKind::Call { name, arguments }
+                } else if self.eat(&TokenKind::LeftBracket) {
+                    let index = self.parse_expression()?;
+                    self.expect(&TokenKind::RightBracket)?;
+                    ExpressionKind::Index {
+                        name,
+                        index: Box::new(index),
+                    }
+                } else {
+                    ExpressionKind::Variable(name)
+                };
+                Ok(Expression { kind, position })
+            }
+            _ => Err(self.unexpected("an expression")),
+        }
+    }
+}
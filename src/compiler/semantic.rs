@@ -0,0 +1,272 @@
+use std::collections::HashMap;
+
+use super::ast::{Declaration, Expression, ExpressionKind, Function, Position, Program, Statement, StatementKind, UnaryOperator};
+use super::{builtin, CompileError};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Symbol {
+    Scalar,
+    Array,
+}
+
+// 名前の解決, 引数の数, 配列とスカラーの取り違えを調べる.
+// エラーは最初の 1 つで止めずに全て集める
+struct Checker<'a> {
+    source: &'a str,
+    file_path: &'a str,
+    functions: HashMap<&'a str, usize>,
+    scopes: Vec<HashMap<&'a str, Symbol>>,
+    errors: Vec<CompileError>,
+}
+
+pub fn check(program: &Program, source: &str, file_path: &str) -> Result<(), Vec<CompileError>> {
+    let mut checker = Checker {
+        source,
+        file_path,
+        functions: HashMap::new(),
+        scopes: vec![HashMap::new()],
+        errors: Vec::new(),
+    };
+
+    for declaration in &program.globals {
+        checker.global(declaration);
+    }
+    for function in &program.functions {
+        checker.signature(function);
+    }
+    match program.functions.iter().find(|function| function.name == "main") {
+        Some(main) if !main.parameters.is_empty() => {
+            checker.error(main.position, "`main` must not take parameters".to_string());
+        }
+        Some(_) => {}
+        None => checker.errors.push(CompileError {
+            span: None,
+            message: "no `main` function".to_string(),
+        }),
+    }
+    for function in &program.functions {
+        checker.function(function);
+    }
+
+    if checker.errors.is_empty() {
+        Ok(())
+    } else {
+        checker
+            .errors
+            .sort_by_key(|error| error.span.as_ref().map(|span| (span.line, span.column_start)));
+        Err(checker.errors)
+    }
+}
+
+// 大域変数の初期値に書ける定数 (数値と符号付きの数値)
+pub fn constant_value(expression: &Expression) -> Option<i32> {
+    match &expression.kind {
+        ExpressionKind::Number(value) => Some(*value),
+        ExpressionKind::Unary {
+            operator: UnaryOperator::Negate,
+            operand,
+        } => constant_value(operand).map(i32::wrapping_neg),
+        _ => None,
+    }
+}
+
+impl<'a> Checker<'a> {
+    fn error(&mut self, position: Position, message: String) {
+        self.errors
+            .push(CompileError::new(self.source, self.file_path, position, message));
+    }
+
+    fn lookup(&self, name: &str) -> Option<Symbol> {
+        self.scopes.iter().rev().find_map(|scope| scope.get(name).copied())
+    }
+
+    fn declare(&mut self, declaration: &'a Declaration) {
+        if let Some(size) = declaration.array_size {
+            if size <= 0 {
+                self.error(
+                    declaration.position,
+                    format!("array `{}` must have a positive size", declaration.name),
+                );
+            }
+            if declaration.initializer.is_some() {
+                self.error(
+                    declaration.position,
+                    format!("array `{}` cannot have an initializer", declaration.name),
+                );
+            }
+        }
+        let symbol = match declaration.array_size {
+            Some(_) => Symbol::Array,
+            None => Symbol::Scalar,
+        };
+        let scope = self.scopes.last_mut().unwrap();
+        if scope.insert(&declaration.name, symbol).is_some() {
+            self.error(
+                declaration.position,
+                format!("`{}` is already defined in this scope", declaration.name),
+            );
+        }
+    }
+
+    fn global(&mut self, declaration: &'a Declaration) {
+        if let Some(initializer) = &declaration.initializer {
+            if constant_value(initializer).is_none() {
+                self.error(
+                    initializer.position,
+                    format!("the initializer of global `{}` must be a constant", declaration.name),
+                );
+            }
+        }
+        self.declare(declaration);
+    }
+
+    fn signature(&mut self, function: &'a Function) {
+        if builtin(&function.name).is_some() {
+            self.error(
+                function.position,
+                format!("`{}` is a builtin function", function.name),
+            );
+        } else if self.functions.contains_key(function.name.as_str()) || self.scopes[0].contains_key(function.name.as_str()) {
+            self.error(function.position, format!("`{}` is already defined", function.name));
+        } else {
+            self.functions.insert(&function.name, function.parameters.len());
+        }
+    }
+
+    fn function(&mut self, function: &'a Function) {
+        let mut parameters = HashMap::new();
+        for parameter in &function.parameters {
+            if parameters.insert(parameter.name.as_str(), Symbol::Scalar).is_some() {
+                self.error(
+                    parameter.position,
+                    format!("duplicate parameter `{}`", parameter.name),
+                );
+            }
+        }
+        self.scopes.push(parameters);
+        self.block(&function.body);
+        self.scopes.pop();
+    }
+
+    fn block(&mut self, statements: &'a [Statement]) {
+        self.scopes.push(HashMap::new());
+        statements.iter().for_each(|statement| self.statement(statement));
+        self.scopes.pop();
+    }
+
+    fn statement(&mut self, statement: &'a Statement) {
+        match &statement.kind {
+            StatementKind::Declaration(declarations) => {
+                for declaration in declarations {
+                    if let Some(initializer) = &declaration.initializer {
+                        self.expression(initializer);
+                    }
+                    self.declare(declaration);
+                }
+            }
+            StatementKind::Assign { name, index, value } => {
+                self.variable(name, index.is_some(), statement.position);
+                if let Some(index) = index {
+                    self.expression(index);
+                }
+                self.expression(value);
+            }
+            StatementKind::Expression(expression) => self.expression(expression),
+            StatementKind::If {
+                condition,
+                then_branch,
+                else_branch,
+            } => {
+                self.expression(condition);
+                self.nested(then_branch);
+                if let Some(else_branch) = else_branch {
+                    self.nested(else_branch);
+                }
+            }
+            StatementKind::While { condition, body } => {
+                self.expression(condition);
+                self.nested(body);
+            }
+            StatementKind::For {
+                init,
+                condition,
+                step,
+                body,
+            } => {
+                if let Some(init) = init {
+                    self.statement(init);
+                }
+                if let Some(condition) = condition {
+                    self.expression(condition);
+                }
+                if let Some(step) = step {
+                    self.statement(step);
+                }
+                self.nested(body);
+            }
+            StatementKind::Return(value) => {
+                if let Some(value) = value {
+                    self.expression(value);
+                }
+            }
+            StatementKind::Block(statements) => self.block(statements),
+        }
+    }
+
+    // if / while の本体は単文でもブロックと同じく新しいスコープになる
+    fn nested(&mut self, statement: &'a Statement) {
+        self.block(std::slice::from_ref(statement));
+    }
+
+    fn variable(&mut self, name: &str, indexed: bool, position: Position) {
+        match (self.lookup(name), indexed) {
+            (Some(Symbol::Scalar), false) | (Some(Symbol::Array), true) => {}
+            (Some(Symbol::Scalar), true) => self.error(position, format!("`{}` is not an array", name)),
+            (Some(Symbol::Array), false) => {
+                self.error(position, format!("array `{}` must be indexed", name))
+            }
+            (None, _) if self.functions.contains_key(name) || builtin(name).is_some() => {
+                self.error(position, format!("`{}` is a function, not a variable", name))
+            }
+            (None, _) => self.error(position, format!("undefined variable `{}`", name)),
+        }
+    }
+
+    fn expression(&mut self, expression: &'a Expression) {
+        match &expression.kind {
+            ExpressionKind::Number(_) => {}
+            ExpressionKind::Variable(name) => self.variable(name, false, expression.position),
+            ExpressionKind::Index { name, index } => {
+                self.variable(name, true, expression.position);
+                self.expression(index);
+            }
+            ExpressionKind::Call { name, arguments } => {
+                let arity = builtin(name)
+                    .map(|(_, arity)| arity)
+                    .or_else(|| self.functions.get(name.as_str()).copied());
+                match arity {
+                    Some(arity) if arity != arguments.len() => self.error(
+                        expression.position,
+                        format!(
+                            "`{}` takes {} argument(s) but {} were given",
+                            name,
+                            arity,
+                            arguments.len()
+                        ),
+                    ),
+                    Some(_) => {}
+                    None if self.lookup(name).is_some() => {
+                        self.error(expression.position, format!("`{}` is not a function", name))
+                    }
+                    None => self.error(expression.position, format!("undefined function `{}`", name)),
+                }
+                arguments.iter().for_each(|argument| self.expression(argument));
+            }
+            ExpressionKind::Unary { operand, .. } => self.expression(operand),
+            ExpressionKind::Binary { left, right, .. } => {
+                self.expression(left);
+                self.expression(right);
+            }
+        }
+    }
+}
@@ -0,0 +1,166 @@
+use core::fmt;
+use std::collections::BTreeMap;
+
+use crate::code::{Code, OperationCode};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BranchCoverage {
+    pub taken: u64,
+    pub not_taken: u64,
+}
+
+impl BranchCoverage {
+    // 分岐した/しなかったの 2 通りのうち実行された数
+    pub fn covered_outcomes(&self) -> usize {
+        (self.taken > 0) as usize + (self.not_taken > 0) as usize
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CoverageSummary {
+    pub instructions: usize,
+    pub covered_instructions: usize,
+    pub branch_outcomes: usize,
+    pub covered_branch_outcomes: usize,
+}
+
+fn percent(covered: usize, total: usize) -> f64 {
+    if total == 0 {
+        100.0
+    } else {
+        covered as f64 * 100.0 / total as f64
+    }
+}
+
+impl fmt::Display for CoverageSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "coverage: {}/{} instructions ({:.1}%), {}/{} branch outcomes ({:.1}%)",
+            self.covered_instructions,
+            self.instructions,
+            percent(self.covered_instructions, self.instructions),
+            self.covered_branch_outcomes,
+            self.branch_outcomes,
+            percent(self.covered_branch_outcomes, self.branch_outcomes)
+        )
+    }
+}
+
+// 命令ごとの実行回数と, BZ ごとに分岐した/しなかった回数を記録する
+#[derive(Debug, Clone, Default)]
+pub struct Coverage {
+    hits: Vec<u64>,
+    branches: BTreeMap<usize, BranchCoverage>,
+}
+
+impl Coverage {
+    pub fn new() -> Coverage {
+        Coverage::default()
+    }
+
+    pub(crate) fn record(&mut self, program_counter: usize) {
+        if program_counter >= self.hits.len() {
+            self.hits.resize(program_counter + 1, 0);
+        }
+        self.hits[program_counter] += 1;
+    }
+
+    pub(crate) fn record_branch(&mut self, program_counter: usize, taken: bool) {
+        let branch = self.branches.entry(program_counter).or_default();
+        if taken {
+            branch.taken += 1;
+        } else {
+            branch.not_taken += 1;
+        }
+    }
+
+    pub fn hits(&self, program_counter: usize) -> u64 {
+        self.hits.get(program_counter).copied().unwrap_or(0)
+    }
+
+    pub fn branch(&self, program_counter: usize) -> BranchCoverage {
+        self.branches.get(&program_counter).copied().unwrap_or_default()
+    }
+
+    fn is_branch(code: &Code, program_counter: usize) -> bool {
+        code.get_instruction(program_counter).operation_code == OperationCode::Bz
+    }
+
+    // 一度も実行されなかった命令のアドレス
+    pub fn uncovered(&self, code: &Code) -> Vec<usize> {
+        (0..code.len()).filter(|address| self.hits(*address) == 0).collect()
+    }
+
+    pub fn summary(&self, code: &Code) -> CoverageSummary {
+        let branches = (0..code.len()).filter(|address| Coverage::is_branch(code, *address));
+        CoverageSummary {
+            instructions: code.len(),
+            covered_instructions: code.len() - self.uncovered(code).len(),
+            branch_outcomes: branches.clone().count() * 2,
+            covered_branch_outcomes: branches.map(|address| self.branch(address).covered_outcomes()).sum(),
+        }
+    }
+
+    fn branch_line(&self, program_counter: usize) -> String {
+        let branch = self.branch(program_counter);
+        let note = match (branch.taken, branch.not_taken) {
+            (0, 0) => " (never executed)",
+            (0, _) => " (never taken)",
+            (_, 0) => " (always taken)",
+            _ => "",
+        };
+        format!(
+            "{:>9}  branch {:04}: taken {}, not taken {}{}\n",
+            "",
+            program_counter,
+            branch.taken,
+            branch.not_taken,
+            note
+        )
+    }
+
+    // ソースの各行に実行回数を付けて表示する. 一度も実行されなかった行は #####,
+    // 一部の命令だけ実行された行は回数の後ろに * を付ける. 命令のない行は -
+    pub fn annotate(&self, code: &Code, source: &str) -> String {
+        let mut lines: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+        for address in 0..code.len() {
+            if let Some(line) = code.line_number(address) {
+                lines.entry(line).or_default().push(address);
+            }
+        }
+
+        let mut listing = format!("{}\n", self.summary(code));
+        for (index, text) in source.lines().enumerate() {
+            let line = index + 1;
+            let addresses = lines.remove(&line).unwrap_or_default();
+            let count = match addresses.iter().map(|address| self.hits(*address)).max() {
+                None => "-".to_string(),
+                Some(0) => "#####".to_string(),
+                Some(count) if addresses.iter().any(|address| self.hits(*address) == 0) => format!("{}*", count),
+                Some(count) => count.to_string(),
+            };
+            listing += &format!("{:>9}:{:>5}: {}\n", count, line, text);
+            for address in addresses.into_iter().filter(|address| Coverage::is_branch(code, *address)) {
+                listing += &self.branch_line(address);
+            }
+        }
+        listing
+    }
+
+    // ソースがない場合は命令ごとに表示する
+    pub fn annotate_instructions(&self, code: &Code) -> String {
+        let mut listing = format!("{}\n", self.summary(code));
+        for address in 0..code.len() {
+            let count = match self.hits(address) {
+                0 => "#####".to_string(),
+                count => count.to_string(),
+            };
+            listing += &format!("{:>9}: {:04}: {}\n", count, address, code.get_instruction(address));
+            if Coverage::is_branch(code, address) {
+                listing += &self.branch_line(address);
+            }
+        }
+        listing
+    }
+}
@@ -0,0 +1,414 @@
+use std::collections::BTreeSet;
+use std::io::{self, BufRead, Write};
+
+use crate::disasm::Disassembler;
+use crate::vsm::{RuntimeError, Vsm, WatchKind};
+
+// -d で起動したときに取り消せる命令数
+pub const DEFAULT_HISTORY_SIZE: usize = 100_000;
+
+const HELP: &str = "\
+commands:
+  step [N]          (s)  execute N instructions (default 1)
+  continue          (c)  run until a breakpoint or the end of the program
+  step-back [N]     (sb) undo the last N instructions (default 1)
+  reverse-continue  (rc) run backwards until a breakpoint or the beginning of the history
+  last-write <addr> (lw) show the last instruction that wrote S[addr]
+  history [N]            show the size of the history, or keep the last N instructions
+  break <pc|label>  (b)  set a breakpoint
+  delete [pc|label] (d)  delete a breakpoint (all breakpoints without argument)
+  watch [addr[..addr] [read|write|change]]
+                    (w)  watch stack cells (default: write), list watchpoints without argument
+  unwatch <id>           delete a watchpoint
+  stack [from [to]] (x)  print stack cells S[from]..S[to]
+  registers         (r)  print PC, SP, B0 and B1
+  heap                   print the heap blocks that have not been freed
+  disas [N]         (l)  disassemble N instructions around PC (default 5)
+  quit              (q)  quit the debugger
+  help              (h)  print this message
+";
+
+enum DebuggerState {
+    Running,
+    Exited(i32),
+    Faulted(RuntimeError),
+}
+
+enum CommandResult {
+    Continue,
+    Quit,
+}
+
+// デバッガのコマンドはプログラムの入力 (GETC/GETI) とは別のストリームから読む
+pub struct Debugger<'a> {
+    vsm: &'a mut Vsm,
+    commands: Box<dyn BufRead>,
+    out: Box<dyn Write>,
+    breakpoints: BTreeSet<usize>,
+    state: DebuggerState,
+}
+
+impl<'a> Debugger<'a> {
+    pub fn new(vsm: &'a mut Vsm, commands: Box<dyn BufRead>, out: Box<dyn Write>) -> Debugger<'a> {
+        Debugger {
+            vsm,
+            commands,
+            out,
+            breakpoints: BTreeSet::new(),
+            state: DebuggerState::Running,
+        }
+    }
+
+    pub fn breakpoints(&self) -> &BTreeSet<usize> {
+        &self.breakpoints
+    }
+
+    // プログラムが EXIT で終了していればその終了コードを返す
+    pub fn run(&mut self) -> io::Result<Result<Option<i32>, RuntimeError>> {
+        writeln!(self.out, "VSM debugger. Type 'help' for a list of commands.")?;
+        self.print_current_instruction()?;
+
+        loop {
+            write!(self.out, "(vsm) ")?;
+            self.out.flush()?;
+
+            let mut line = String::new();
+            if self.commands.read_line(&mut line)? == 0 {
+                writeln!(self.out)?;
+                break;
+            }
+
+            let result = self.exec_command(&line);
+            self.vsm.flush_output()?;
+            if let CommandResult::Quit = result? {
+                break;
+            }
+        }
+
+        Ok(match &self.state {
+            DebuggerState::Running => Ok(None),
+            DebuggerState::Exited(return_code) => Ok(Some(*return_code)),
+            DebuggerState::Faulted(err) => Err(err.clone()),
+        })
+    }
+
+    fn exec_command(&mut self, line: &str) -> io::Result<CommandResult> {
+        let words = line.split_whitespace().collect::<Vec<_>>();
+        let (command, arguments) = match words.split_first() {
+            Some((command, arguments)) => (*command, arguments),
+            None => return Ok(CommandResult::Continue),
+        };
+
+        match command {
+            "step" | "s" => match Debugger::parse_count(arguments.first(), 1) {
+                Some(count) => self.resume(Some(count))?,
+                None => writeln!(self.out, "invalid step count '{}'", arguments[0])?,
+            },
+            "continue" | "c" => self.resume(None)?,
+            "step-back" | "sb" => match Debugger::parse_count(arguments.first(), 1) {
+                Some(count) => self.reverse(Some(count))?,
+                None => writeln!(self.out, "invalid step count '{}'", arguments[0])?,
+            },
+            "reverse-continue" | "rc" => self.reverse(None)?,
+            "last-write" | "lw" => match arguments.first().map(|address| address.parse::<usize>()) {
+                Some(Ok(address)) => self.print_last_write(address)?,
+                _ => writeln!(self.out, "usage: last-write <addr>")?,
+            },
+            "history" => match arguments.first().map(|count| count.parse::<usize>()) {
+                Some(Ok(count)) => {
+                    self.vsm.enable_history(count);
+                    self.print_history()?;
+                }
+                Some(Err(_)) => writeln!(self.out, "invalid history size '{}'", arguments[0])?,
+                None => self.print_history()?,
+            },
+            "break" | "b" => match arguments.first().map(|location| self.parse_location(location)) {
+                Some(Some(address)) => {
+                    self.breakpoints.insert(address);
+                    writeln!(self.out, "breakpoint at {:04}", address)?;
+                }
+                Some(None) => writeln!(self.out, "unknown location '{}'", arguments[0])?,
+                None => writeln!(self.out, "usage: break <pc|label>")?,
+            },
+            "delete" | "d" => match arguments.first().map(|location| self.parse_location(location)) {
+                Some(Some(address)) => {
+                    if self.breakpoints.remove(&address) {
+                        writeln!(self.out, "deleted breakpoint at {:04}", address)?;
+                    } else {
+                        writeln!(self.out, "no breakpoint at {:04}", address)?;
+                    }
+                }
+                Some(None) => writeln!(self.out, "unknown location '{}'", arguments[0])?,
+                None => {
+                    self.breakpoints.clear();
+                    writeln!(self.out, "deleted all breakpoints")?;
+                }
+            },
+            "watch" | "w" => self.watch(arguments)?,
+            "unwatch" => match arguments.first().map(|id| id.parse::<usize>()) {
+                Some(Ok(id)) => {
+                    if self.vsm.remove_watchpoint(id) {
+                        writeln!(self.out, "deleted watchpoint {}", id)?;
+                    } else {
+                        writeln!(self.out, "no watchpoint {}", id)?;
+                    }
+                }
+                _ => writeln!(self.out, "usage: unwatch <id>")?,
+            },
+            "stack" | "x" => self.print_stack(arguments)?,
+            "registers" | "r" => self.print_registers()?,
+            "heap" => self.print_heap()?,
+            "disas" | "l" => match Debugger::parse_count(arguments.first(), 5) {
+                Some(count) => self.print_disassembly(count)?,
+                None => writeln!(self.out, "invalid instruction count '{}'", arguments[0])?,
+            },
+            "quit" | "q" => return Ok(CommandResult::Quit),
+            "help" | "h" => write!(self.out, "{}", HELP)?,
+            _ => writeln!(self.out, "unknown command '{}'. Type 'help' for a list of commands.", command)?,
+        }
+        Ok(CommandResult::Continue)
+    }
+
+    fn parse_count(argument: Option<&&str>, default: usize) -> Option<usize> {
+        match argument {
+            Some(argument) => argument.parse::<usize>().ok(),
+            None => Some(default),
+        }
+    }
+
+    fn parse_location(&self, location: &str) -> Option<usize> {
+        match location.parse::<usize>() {
+            Ok(address) => Some(address),
+            Err(_) => self.vsm.code.label_address(location),
+        }
+    }
+
+    fn watch(&mut self, arguments: &[&str]) -> io::Result<()> {
+        let range = match arguments.first() {
+            Some(range) => range,
+            None => {
+                let watchpoints = self
+                    .vsm
+                    .watchpoints()
+                    .iter()
+                    .map(|(id, watchpoint)| format!("{}: S[{}..{}] {}", id, watchpoint.from, watchpoint.to, watchpoint.kind))
+                    .collect::<Vec<_>>();
+                if watchpoints.is_empty() {
+                    return writeln!(self.out, "no watchpoints");
+                }
+                return writeln!(self.out, "{}", watchpoints.join("\n"));
+            }
+        };
+
+        let addresses = match range.split_once("..") {
+            Some((from, to)) => from.parse::<usize>().ok().zip(to.parse::<usize>().ok()),
+            None => range.parse::<usize>().ok().map(|address| (address, address)),
+        };
+        let kind = match arguments.get(1).copied() {
+            None | Some("write") => Some(WatchKind::Write),
+            Some("read") => Some(WatchKind::Read),
+            Some("change") => Some(WatchKind::Change),
+            Some(_) => None,
+        };
+        match (addresses, kind) {
+            (Some((from, to)), Some(kind)) => {
+                let id = self.vsm.add_watchpoint(from, to, kind);
+                writeln!(self.out, "watchpoint {}: S[{}..{}] {}", id, from.min(to), from.max(to), kind)
+            }
+            _ => writeln!(self.out, "usage: watch <addr>[..<addr>] [read|write|change]"),
+        }
+    }
+
+    // count が None の場合はブレークポイントかプログラムの終了まで実行する
+    fn resume(&mut self, count: Option<usize>) -> io::Result<()> {
+        match &self.state {
+            DebuggerState::Exited(return_code) => {
+                return writeln!(self.out, "the program has exited with code {}", return_code);
+            }
+            DebuggerState::Faulted(err) => {
+                return writeln!(self.out, "the program has stopped: {}", err);
+            }
+            DebuggerState::Running => {}
+        }
+
+        let mut executed = 0;
+        while count.is_none_or(|count| executed < count) {
+            let result = self.vsm.step_instruction();
+            let watch_hits = self.vsm.watch_hits().to_vec();
+            if !watch_hits.is_empty() {
+                self.vsm.flush_output()?;
+                for watch_hit in &watch_hits {
+                    writeln!(self.out, "{}", watch_hit)?;
+                }
+            }
+            match result {
+                Ok(Some(return_code)) => {
+                    self.vsm.flush_output()?;
+                    writeln!(self.out, "the program exited with code {}", return_code)?;
+                    write!(self.out, "{}", self.vsm.heap().leak_report(self.vsm.code()))?;
+                    self.state = DebuggerState::Exited(return_code);
+                    return Ok(());
+                }
+                Ok(None) => {}
+                Err(err) => {
+                    self.vsm.flush_output()?;
+                    writeln!(self.out, "runtime error: {}", err)?;
+                    self.state = DebuggerState::Faulted(err);
+                    return Ok(());
+                }
+            }
+            executed += 1;
+
+            if !watch_hits.is_empty() {
+                break;
+            }
+            if self.breakpoints.contains(&self.vsm.program_counter) {
+                self.vsm.flush_output()?;
+                writeln!(self.out, "breakpoint at {:04}", self.vsm.program_counter)?;
+                break;
+            }
+        }
+
+        self.vsm.flush_output()?;
+        self.print_current_instruction()
+    }
+
+    // count が None の場合はブレークポイントか記録の先頭まで戻る
+    fn reverse(&mut self, count: Option<usize>) -> io::Result<()> {
+        if self.vsm.history().is_none() {
+            return writeln!(self.out, "the history is disabled. Use 'history <N>' to record instructions");
+        }
+
+        let mut undone = 0;
+        while count.is_none_or(|count| undone < count) {
+            if !self.vsm.step_back() {
+                writeln!(self.out, "reached the beginning of the history")?;
+                break;
+            }
+            undone += 1;
+            self.state = DebuggerState::Running;
+
+            if count.is_none() && self.breakpoints.contains(&self.vsm.program_counter) {
+                writeln!(self.out, "breakpoint at {:04}", self.vsm.program_counter)?;
+                break;
+            }
+        }
+        self.print_current_instruction()
+    }
+
+    fn print_last_write(&mut self, address: usize) -> io::Result<()> {
+        match self.vsm.history().and_then(|history| history.last_write(address)) {
+            Some(last_write) => writeln!(
+                self.out,
+                "S[{}] was last written at {:04} '{}' ({} instructions ago): {} -> {}",
+                address,
+                last_write.program_counter,
+                last_write.instruction,
+                last_write.steps_ago,
+                last_write.old_value,
+                last_write.new_value
+            ),
+            None => writeln!(self.out, "S[{}] has not been written in the history", address),
+        }
+    }
+
+    fn print_history(&mut self) -> io::Result<()> {
+        match self.vsm.history() {
+            Some(history) => writeln!(
+                self.out,
+                "history: {} of {} instructions recorded",
+                history.len(),
+                history.capacity()
+            ),
+            None => writeln!(self.out, "the history is disabled"),
+        }
+    }
+
+    fn print_current_instruction(&mut self) -> io::Result<()> {
+        let program_counter = self.vsm.program_counter;
+        if program_counter < self.vsm.code.len() {
+            let instruction = self.vsm.code.get_instruction(program_counter);
+            writeln!(self.out, "=> {:04}: {}", program_counter, instruction)
+        } else {
+            writeln!(self.out, "=> {:04}: <out of code>", program_counter)
+        }
+    }
+
+    fn print_registers(&mut self) -> io::Result<()> {
+        let sp = match self.vsm.stack_pointer {
+            Some(sp) => sp.to_string(),
+            None => "-1".to_string(),
+        };
+        writeln!(
+            self.out,
+            "PC = {}  SP = {}  B0 = {}  B1 = {}",
+            self.vsm.program_counter, sp, self.vsm.global_top_address, self.vsm.frame_top_address
+        )
+    }
+
+    // 大きなブロックは先頭の 16 セルだけ表示する
+    fn print_heap(&mut self) -> io::Result<()> {
+        let heap = self.vsm.heap();
+        let blocks = heap.leaks();
+        let mut lines = format!("heap: {} block(s), {} of {} cells used\n", blocks.len(), heap.used(), heap.max_size());
+        for block in blocks {
+            let mut values = block.values.iter().take(16).map(|value| value.to_string()).collect::<Vec<_>>();
+            if block.size > 16 {
+                values.push("...".to_string());
+            }
+            lines += &format!(
+                "  address {} ({} cells, allocated at {:04}): {}\n",
+                block.address,
+                block.size,
+                block.allocated_at,
+                values.join(" ")
+            );
+        }
+        write!(self.out, "{}", lines)
+    }
+
+    fn print_stack(&mut self, arguments: &[&str]) -> io::Result<()> {
+        let top = self.vsm.stack_pointer.unwrap_or(0).max(self.vsm.frame_top_address);
+        let from = arguments.first().map(|argument| argument.parse::<usize>());
+        let to = arguments.get(1).map(|argument| argument.parse::<usize>());
+        let (from, to) = match (from, to) {
+            (None, _) => (0, top),
+            (Some(Ok(from)), None) => (from, from),
+            (Some(Ok(from)), Some(Ok(to))) if from <= to => (from, to),
+            _ => return writeln!(self.out, "usage: stack [from [to]]"),
+        };
+        if from >= self.vsm.max_stack_size() {
+            return writeln!(self.out, "address {} is out of the stack (size {})", from, self.vsm.max_stack_size());
+        }
+        let stack = self.vsm.format_stack(from, to);
+        write!(self.out, "{}", stack)
+    }
+
+    fn print_disassembly(&mut self, count: usize) -> io::Result<()> {
+        let code = &self.vsm.code;
+        if code.is_empty() {
+            return writeln!(self.out, "no code");
+        }
+        let disassembler = Disassembler::new(code);
+        let program_counter = self.vsm.program_counter;
+        let from = program_counter.saturating_sub(count);
+        let to = program_counter.saturating_add(count).min(code.len() - 1);
+
+        for address in from..=to {
+            for label in disassembler.labels_at(address) {
+                writeln!(self.out, "{}:", label)?;
+            }
+            let marker = if address == program_counter { "=>" } else { "  " };
+            let breakpoint = if self.breakpoints.contains(&address) { "*" } else { " " };
+            writeln!(
+                self.out,
+                "{}{} {:04}: {}",
+                marker,
+                breakpoint,
+                address,
+                disassembler.format_instruction(address)
+            )?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,243 @@
+use std::collections::{BTreeMap, HashSet};
+
+use crate::code::{Code, Instruction, OperationCode, SourceLocation};
+
+// 分岐先のアドレスにラベルを付けて逆アセンブルする.
+// 出力はそのままアセンブラで読み直せる形式になっている
+pub struct Disassembler<'a> {
+    code: &'a Code,
+    labels: BTreeMap<usize, Vec<String>>,
+    data_labels: BTreeMap<usize, Vec<String>>,
+    show_source: bool,
+}
+
+impl<'a> Disassembler<'a> {
+    pub fn new(code: &'a Code) -> Disassembler<'a> {
+        let mut labels: BTreeMap<usize, Vec<String>> = BTreeMap::new();
+        for (label, address) in code.labels() {
+            labels.entry(*address).or_default().push(label.clone());
+        }
+        labels.values_mut().for_each(|names| names.sort());
+
+        let mut data_labels: BTreeMap<usize, Vec<String>> = BTreeMap::new();
+        for (label, offset) in code.data_labels() {
+            data_labels.entry(*offset).or_default().push(label.clone());
+        }
+        data_labels.values_mut().for_each(|names| names.sort());
+
+        let mut used_names = code.labels().keys().chain(code.data_labels().keys()).cloned().collect::<HashSet<_>>();
+        let mut synthesize = |labels: &mut BTreeMap<usize, Vec<String>>, address: usize, name: String| {
+            let names = labels.entry(address).or_default();
+            if !names.is_empty() {
+                return;
+            }
+            let mut unique_name = name.clone();
+            let mut suffix = 1;
+            while used_names.contains(&unique_name) {
+                unique_name = format!("{}_{}", name, suffix);
+                suffix += 1;
+            }
+            used_names.insert(unique_name.clone());
+            names.push(unique_name);
+        };
+
+        // 関数名を優先するため CALL の分岐先から先に名前を付ける
+        for address in 0..code.len() {
+            if let Some(target) = Disassembler::call_target(code, address) {
+                synthesize(&mut labels, target, format!("func_{}", target));
+            }
+        }
+        for address in 0..code.len() {
+            if let Some(target) = Disassembler::branch_target(code, address) {
+                synthesize(&mut labels, target, format!("L{:04}", target));
+            }
+        }
+
+        Disassembler {
+            code,
+            labels,
+            data_labels,
+            show_source: false,
+        }
+    }
+
+    // 命令の前に元のソースの行をコメントとして挟む
+    pub fn with_source(mut self) -> Disassembler<'a> {
+        self.show_source = true;
+        self
+    }
+
+    fn in_range(code: &Code, target: i64) -> Option<usize> {
+        if 0 <= target && target <= code.len() as i64 {
+            Some(target as usize)
+        } else {
+            None
+        }
+    }
+
+    // B / BZ の相対オフセットを絶対アドレスに変換する
+    fn branch_target(code: &Code, address: usize) -> Option<usize> {
+        match code.get_instruction(address) {
+            Instruction {
+                operation_code: OperationCode::B | OperationCode::Bz,
+                operand: [Some(offset), _],
+            } => Disassembler::in_range(code, address as i64 + 1 + offset as i64),
+            _ => None,
+        }
+    }
+
+    fn call_target(code: &Code, address: usize) -> Option<usize> {
+        match code.get_instruction(address) {
+            Instruction {
+                operation_code: OperationCode::Call,
+                operand: [Some(target), _],
+            } => Disassembler::in_range(code, target as i64),
+            _ => None,
+        }
+    }
+
+    pub fn target(&self, address: usize) -> Option<usize> {
+        Disassembler::branch_target(self.code, address).or_else(|| Disassembler::call_target(self.code, address))
+    }
+
+    pub fn labels_at(&self, address: usize) -> &[String] {
+        self.labels.get(&address).map(|names| names.as_slice()).unwrap_or(&[])
+    }
+
+    // B0 からのオフセットにデータのラベルがあれば LA / LV / SV 0 のオペランドをラベル名で表示する
+    fn data_label(&self, instruction: Instruction) -> Option<&String> {
+        match instruction {
+            Instruction {
+                operation_code: OperationCode::La | OperationCode::Lv | OperationCode::Sv,
+                operand: [Some(0), Some(offset)],
+            } => usize::try_from(offset).ok().and_then(|offset| self.data_labels.get(&offset)?.first()),
+            _ => None,
+        }
+    }
+
+    // 分岐先にラベルがあればオペランドをラベル名で表示する
+    pub fn format_instruction(&self, address: usize) -> String {
+        let instruction = self.code.get_instruction(address);
+        if let Some(label) = self.data_label(instruction) {
+            return format!("{} 0 {}", instruction.operation_code, label);
+        }
+        match self.target(address).and_then(|target| self.labels_at(target).first()) {
+            Some(label) => format!("{} {}", instruction.operation_code, label),
+            None => instruction.to_string(),
+        }
+    }
+
+    fn format_comment(&self, address: usize) -> String {
+        let instruction = self.code.get_instruction(address);
+        match (instruction.operation_code, self.target(address)) {
+            (OperationCode::B | OperationCode::Bz | OperationCode::Call, Some(target)) => {
+                format!("{:04} -> {:04}", address, target)
+            }
+            (OperationCode::B | OperationCode::Bz | OperationCode::Call, None) => {
+                format!("{:04} -> out of range", address)
+            }
+            _ => format!("{:04}", address),
+        }
+    }
+
+    pub fn format_line(&self, address: usize) -> String {
+        format!("        {:<24}// {}", self.format_instruction(address), self.format_comment(address))
+    }
+
+    // 同じ行から生成された命令が続く場合は最初の命令の前にだけ表示する
+    fn source_line(&self, address: usize) -> Option<&SourceLocation> {
+        let location = self.code.source_location(address).filter(|_| self.show_source)?;
+        let previous = address.checked_sub(1).and_then(|previous| self.code.source_location(previous));
+        match previous {
+            Some(previous) if previous.file_path == location.file_path && previous.line == location.line => None,
+            _ => Some(location),
+        }
+    }
+
+    // ラベルで区切った範囲ごとに, 0 だけなら .zero, 0 で終わる文字の並びなら .string, それ以外は .word にする
+    fn format_data(values: &[i32]) -> Vec<String> {
+        if values.iter().all(|value| *value == 0) {
+            return vec![format!(".zero {}", values.len())];
+        }
+        let text = match values.split_last() {
+            Some((0, chars)) => chars
+                .iter()
+                .map(|value| u32::try_from(*value).ok().and_then(char::from_u32).filter(|c| *c != '\0'))
+                .collect::<Option<String>>(),
+            _ => None,
+        };
+        match text.filter(|text| text.chars().all(|c| !c.is_control() || c == '\n' || c == '\t')) {
+            Some(text) => {
+                let escaped = text
+                    .chars()
+                    .map(|c| match c {
+                        '\n' => "\\n".to_string(),
+                        '\t' => "\\t".to_string(),
+                        '\\' => "\\\\".to_string(),
+                        '"' => "\\\"".to_string(),
+                        c => c.to_string(),
+                    })
+                    .collect::<String>();
+                vec![format!(".string \"{}\"", escaped)]
+            }
+            None => values
+                .chunks(8)
+                .map(|chunk| {
+                    let words = chunk.iter().map(|value| value.to_string()).collect::<Vec<_>>();
+                    format!(".word {}", words.join(" "))
+                })
+                .collect(),
+        }
+    }
+
+    pub(crate) fn disassemble_data(&self) -> String {
+        let data = self.code.data();
+        if data.is_empty() && self.data_labels.is_empty() {
+            return String::new();
+        }
+        let mut lines = "        .data\n".to_string();
+        let mut boundaries = self.data_labels.keys().copied().filter(|offset| *offset < data.len()).collect::<Vec<_>>();
+        boundaries.insert(0, 0);
+        boundaries.push(data.len());
+        boundaries.dedup();
+        for range in boundaries.windows(2) {
+            for label in self.data_labels.get(&range[0]).into_iter().flatten() {
+                lines += &format!("{}:\n", label);
+            }
+            for line in Disassembler::format_data(&data[range[0]..range[1]]) {
+                lines += &format!("        {}\n", line);
+            }
+        }
+        for (_, names) in self.data_labels.range(data.len()..) {
+            for label in names {
+                lines += &format!("{}:\n", label);
+            }
+        }
+        lines + "        .text\n"
+    }
+
+    pub fn disassemble(&self) -> String {
+        let mut lines = self.disassemble_data();
+        for address in 0..=self.code.len() {
+            for label in self.labels_at(address) {
+                lines += &format!("{}:\n", label);
+            }
+            if address < self.code.len() {
+                if let Some(location) = self.source_line(address) {
+                    lines += &format!("        // {}\n", location);
+                }
+                lines += &self.format_line(address);
+                lines.push('\n');
+            }
+        }
+        lines
+    }
+}
+
+pub fn disassemble(code: &Code) -> String {
+    Disassembler::new(code).disassemble()
+}
+
+pub fn disassemble_with_source(code: &Code) -> String {
+    Disassembler::new(code).with_source().disassemble()
+}
@@ -0,0 +1,456 @@
+use crate::code::{Code, Instruction, OperationCode};
+use crate::vsm::{ArithmeticMode, RuntimeError, Vsm, VsmError};
+
+// 実行時間の制限があるときは, この命令数ごとにインタプリタで経過時間を確認する
+const TIME_CHECK_INTERVAL: u64 = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Base {
+    Global,
+    Frame,
+}
+
+impl Base {
+    fn from_operand(operand: i32) -> Option<Base> {
+        match operand {
+            0 => Some(Base::Global),
+            1 => Some(Base::Frame),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Binary {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Eq,
+    Ne,
+    Gt,
+    Lt,
+    Ge,
+    Le,
+}
+
+impl Binary {
+    fn from_operation_code(operation_code: OperationCode) -> Option<Binary> {
+        match operation_code {
+            OperationCode::Add => Some(Binary::Add),
+            OperationCode::Sub => Some(Binary::Sub),
+            OperationCode::Mul => Some(Binary::Mul),
+            OperationCode::Div => Some(Binary::Div),
+            OperationCode::Mod => Some(Binary::Mod),
+            OperationCode::Eq => Some(Binary::Eq),
+            OperationCode::Ne => Some(Binary::Ne),
+            OperationCode::Gt => Some(Binary::Gt),
+            OperationCode::Lt => Some(Binary::Lt),
+            OperationCode::Ge => Some(Binary::Ge),
+            OperationCode::Le => Some(Binary::Le),
+            _ => None,
+        }
+    }
+
+    fn is_comparison(self) -> bool {
+        matches!(self, Binary::Eq | Binary::Ne | Binary::Gt | Binary::Lt | Binary::Ge | Binary::Le)
+    }
+
+    // インタプリタと同じ関数で計算する
+    #[inline(always)]
+    fn apply(self, arithmetic_mode: ArithmeticMode, a: i32, b: i32) -> Result<i32, VsmError> {
+        match self {
+            Binary::Add => arithmetic_mode.add(a, b),
+            Binary::Sub => arithmetic_mode.sub(a, b),
+            Binary::Mul => arithmetic_mode.mul(a, b),
+            Binary::Div => arithmetic_mode.div(a, b),
+            Binary::Mod => arithmetic_mode.rem(a, b),
+            Binary::Eq => Vsm::eq_fn(a, b),
+            Binary::Ne => Vsm::ne_fn(a, b),
+            Binary::Gt => Vsm::gt_fn(a, b),
+            Binary::Lt => Vsm::lt_fn(a, b),
+            Binary::Ge => Vsm::ge_fn(a, b),
+            Binary::Le => Vsm::le_fn(a, b),
+        }
+    }
+}
+
+// オペランドと分岐先を解決済みの命令. 分岐先は絶対アドレスで持つ
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Isp(isize),
+    La(Base, i32),
+    Lv(Base, i32),
+    Lc(i32),
+    Li,
+    Dup,
+    Si,
+    Sv(Base, usize),
+    Sb(Base),
+    B(usize),
+    Bz(usize),
+    Call(usize),
+    Ret,
+    Binary(Binary),
+    Inv,
+    // 2 命令をまとめて実行する
+    LcBinary(i32, Binary),
+    LvBinary(Base, i32, Binary),
+    CompareBz(Binary, usize),
+    // 入出力, ヒープ, EXIT, 不正なオペランドの命令はインタプリタで実行する
+    Interpret,
+}
+
+impl Op {
+    fn decode(program_counter: usize, instruction: Instruction) -> Op {
+        let operand1 = instruction.operand[0].unwrap_or(-1);
+        let operand2 = instruction.operand[1].unwrap_or(-1);
+        // B / BZ は PC を進めてからオフセットを足す
+        let branch_target = (program_counter as i32 + 1)
+            .checked_add(operand1)
+            .and_then(|target| usize::try_from(target).ok());
+
+        let op = match instruction.operation_code {
+            OperationCode::Isp => Some(Op::Isp(operand1 as isize)),
+            OperationCode::La => Base::from_operand(operand1).map(|base| Op::La(base, operand2)),
+            OperationCode::Lv => Base::from_operand(operand1).map(|base| Op::Lv(base, operand2)),
+            OperationCode::Lc => Some(Op::Lc(operand1)),
+            OperationCode::Li => Some(Op::Li),
+            OperationCode::Dup => Some(Op::Dup),
+            OperationCode::Si => Some(Op::Si),
+            OperationCode::Sv if operand2 >= 0 => {
+                Base::from_operand(operand1).map(|base| Op::Sv(base, operand2 as usize))
+            }
+            OperationCode::Sb => Base::from_operand(operand1).map(Op::Sb),
+            OperationCode::B => branch_target.map(Op::B),
+            OperationCode::Bz => branch_target.map(Op::Bz),
+            OperationCode::Call => usize::try_from(operand1).ok().map(Op::Call),
+            OperationCode::Ret => Some(Op::Ret),
+            OperationCode::Inv => Some(Op::Inv),
+            operation_code => Binary::from_operation_code(operation_code).map(Op::Binary),
+        };
+        op.unwrap_or(Op::Interpret)
+    }
+
+    // 続けて実行される 2 命令をまとめる. 2 つ目の命令へ直接分岐してきた場合は
+    // 元の命令がそのまま実行されるので, 分岐先かどうかは気にしなくてよい
+    fn fuse(self, next: Op) -> Option<Op> {
+        match (self, next) {
+            (Op::Lc(value), Op::Binary(binary)) => Some(Op::LcBinary(value, binary)),
+            (Op::Lv(base, offset), Op::Binary(binary)) => Some(Op::LvBinary(base, offset, binary)),
+            (Op::Binary(binary), Op::Bz(target)) if binary.is_comparison() => Some(Op::CompareBz(binary, target)),
+            _ => None,
+        }
+    }
+}
+
+// Code を事前に変換したもの. ops[pc] が PC の命令に対応する
+pub struct DecodedCode {
+    ops: Vec<Op>,
+}
+
+impl DecodedCode {
+    pub fn new(code: &Code) -> DecodedCode {
+        let mut ops = (0..code.len())
+            .map(|program_counter| Op::decode(program_counter, code.get_instruction(program_counter)))
+            .collect::<Vec<_>>();
+        for program_counter in 0..ops.len().saturating_sub(1) {
+            if let Some(fused) = ops[program_counter].fuse(ops[program_counter + 1]) {
+                ops[program_counter] = fused;
+            }
+        }
+        DecodedCode { ops }
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    // 2 命令をまとめた命令の数
+    pub fn superinstructions(&self) -> usize {
+        self.ops
+            .iter()
+            .filter(|op| matches!(op, Op::LcBinary(..) | Op::LvBinary(..) | Op::CompareBz(..)))
+            .count()
+    }
+
+    // EXIT で指定された終了コードを返す. 高速に実行できない命令や, エラーになりうる命令は
+    // インタプリタで 1 命令ずつ実行するので, 結果とエラーはインタプリタと同じになる
+    pub(crate) fn exec(&self, vsm: &mut Vsm) -> Result<i32, RuntimeError> {
+        loop {
+            let limits = vsm.limits();
+            let mut budget = match limits.max_instructions {
+                Some(max_instructions) => max_instructions.saturating_sub(vsm.executed_instructions()),
+                None => u64::MAX,
+            };
+            if limits.max_duration.is_some() {
+                budget = budget.min(TIME_CHECK_INTERVAL);
+            }
+            self.run(vsm, budget);
+            if let Some(return_code) = vsm.step_instruction()? {
+                return Ok(return_code);
+            }
+        }
+    }
+
+    // 最大 budget 命令を実行し, インタプリタに任せる命令の手前で止まる
+    fn run(&self, vsm: &mut Vsm, mut budget: u64) {
+        let mut program_counter = vsm.program_counter;
+        let mut sp = vsm.stack_pointer.map_or(-1, |sp| sp as isize);
+        let mut b0 = vsm.global_top_address;
+        let mut b1 = vsm.frame_top_address;
+        let mut max_sp = vsm.max_stack_pointer as isize;
+        let mut executed = 0;
+        let arithmetic_mode = vsm.arithmetic_mode();
+
+        let length = vsm.stack.len();
+        // これより上に積む, または書き込む命令はインタプリタでオーバーフローや制限超過を報告する
+        let capacity = vsm.limits().max_stack_depth.map_or(length, |depth| depth.min(length)) as isize;
+        let stack = &mut vsm.stack[..];
+
+        while budget > 0 {
+            let op = match self.ops.get(program_counter) {
+                Some(op) => *op,
+                None => break,
+            };
+            let steps = match op {
+                Op::Isp(value) => {
+                    let next = sp + value;
+                    if next < -1 || next >= capacity {
+                        break;
+                    }
+                    sp = next;
+                    program_counter += 1;
+                    1
+                }
+                Op::La(base, offset) => {
+                    let base = if base == Base::Global { b0 } else { b1 };
+                    let address = match offset.checked_add(base as i32) {
+                        Some(address) if address >= 0 => address,
+                        _ => break,
+                    };
+                    if sp + 1 >= capacity {
+                        break;
+                    }
+                    sp += 1;
+                    stack[sp as usize] = address;
+                    program_counter += 1;
+                    1
+                }
+                Op::Lv(base, offset) => {
+                    let base = if base == Base::Global { b0 } else { b1 };
+                    let value = match offset.checked_add(base as i32).and_then(|address| usize::try_from(address).ok()).and_then(|address| stack.get(address)) {
+                        Some(value) => *value,
+                        None => break,
+                    };
+                    if sp + 1 >= capacity {
+                        break;
+                    }
+                    sp += 1;
+                    stack[sp as usize] = value;
+                    program_counter += 1;
+                    1
+                }
+                Op::Lc(value) => {
+                    if sp + 1 >= capacity {
+                        break;
+                    }
+                    sp += 1;
+                    stack[sp as usize] = value;
+                    program_counter += 1;
+                    1
+                }
+                Op::Li => {
+                    if sp < 0 {
+                        break;
+                    }
+                    let value = match usize::try_from(stack[sp as usize]).ok().and_then(|address| stack.get(address)) {
+                        Some(value) => *value,
+                        None => break,
+                    };
+                    stack[sp as usize] = value;
+                    program_counter += 1;
+                    1
+                }
+                Op::Dup => {
+                    if sp < 0 || sp + 1 >= capacity {
+                        break;
+                    }
+                    stack[sp as usize + 1] = stack[sp as usize];
+                    sp += 1;
+                    program_counter += 1;
+                    1
+                }
+                Op::Si => {
+                    if sp < 1 {
+                        break;
+                    }
+                    let value = stack[sp as usize];
+                    let address = match usize::try_from(stack[sp as usize - 1]) {
+                        Ok(address) if (address as isize) < capacity => address,
+                        _ => break,
+                    };
+                    stack[address] = value;
+                    sp -= 2;
+                    program_counter += 1;
+                    1
+                }
+                Op::Sv(base, offset) => {
+                    let base = if base == Base::Global { b0 } else { b1 };
+                    let address = match offset.checked_add(base) {
+                        Some(address) if (address as isize) < capacity && sp >= 0 => address,
+                        _ => break,
+                    };
+                    stack[address] = stack[sp as usize];
+                    sp -= 1;
+                    program_counter += 1;
+                    1
+                }
+                Op::Sb(base) => {
+                    if sp < 0 {
+                        break;
+                    }
+                    let value = match usize::try_from(stack[sp as usize]) {
+                        Ok(value) => value,
+                        Err(_) => break,
+                    };
+                    match base {
+                        Base::Global => b0 = value,
+                        Base::Frame => b1 = value,
+                    }
+                    sp -= 1;
+                    program_counter += 1;
+                    1
+                }
+                Op::B(target) => {
+                    program_counter = target;
+                    1
+                }
+                Op::Bz(target) => {
+                    if sp < 0 {
+                        break;
+                    }
+                    let value = stack[sp as usize];
+                    sp -= 1;
+                    program_counter = if value == 0 { target } else { program_counter + 1 };
+                    1
+                }
+                Op::Call(target) => {
+                    if sp + 3 >= capacity {
+                        break;
+                    }
+                    stack[(sp + 2) as usize] = b1 as i32;
+                    stack[(sp + 3) as usize] = (program_counter + 1) as i32;
+                    b1 = (sp + 1) as usize;
+                    program_counter = target;
+                    1
+                }
+                Op::Ret => {
+                    match b1.checked_add(2) {
+                        Some(address) if address < length && (b1 as isize) < capacity => {}
+                        _ => break,
+                    }
+                    let (frame, target) = match (usize::try_from(stack[b1 + 1]), usize::try_from(stack[b1 + 2])) {
+                        (Ok(frame), Ok(target)) => (frame, target),
+                        _ => break,
+                    };
+                    sp = b1 as isize;
+                    program_counter = target;
+                    b1 = frame;
+                    1
+                }
+                Op::Binary(binary) => {
+                    if sp < 1 {
+                        break;
+                    }
+                    let top = sp as usize;
+                    let value = match binary.apply(arithmetic_mode, stack[top - 1], stack[top]) {
+                        Ok(value) => value,
+                        Err(_) => break,
+                    };
+                    stack[top - 1] = value;
+                    sp -= 1;
+                    program_counter += 1;
+                    1
+                }
+                Op::Inv => {
+                    if sp < 0 {
+                        break;
+                    }
+                    match arithmetic_mode.neg(stack[sp as usize]) {
+                        Ok(value) => stack[sp as usize] = value,
+                        Err(_) => break,
+                    }
+                    program_counter += 1;
+                    1
+                }
+                // 途中で積んだ値もスタックに残す
+                Op::LcBinary(constant, binary) => {
+                    if budget < 2 || sp < 0 || sp + 1 >= capacity {
+                        break;
+                    }
+                    let top = sp as usize;
+                    let value = match binary.apply(arithmetic_mode, stack[top], constant) {
+                        Ok(value) => value,
+                        Err(_) => break,
+                    };
+                    stack[top + 1] = constant;
+                    stack[top] = value;
+                    max_sp = max_sp.max(sp + 1);
+                    program_counter += 2;
+                    2
+                }
+                Op::LvBinary(base, offset, binary) => {
+                    if budget < 2 || sp < 0 || sp + 1 >= capacity {
+                        break;
+                    }
+                    let base = if base == Base::Global { b0 } else { b1 };
+                    let operand = match offset.checked_add(base as i32).and_then(|address| usize::try_from(address).ok()).and_then(|address| stack.get(address)) {
+                        Some(operand) => *operand,
+                        None => break,
+                    };
+                    let top = sp as usize;
+                    let value = match binary.apply(arithmetic_mode, stack[top], operand) {
+                        Ok(value) => value,
+                        Err(_) => break,
+                    };
+                    stack[top + 1] = operand;
+                    stack[top] = value;
+                    max_sp = max_sp.max(sp + 1);
+                    program_counter += 2;
+                    2
+                }
+                Op::CompareBz(binary, target) => {
+                    if budget < 2 || sp < 1 {
+                        break;
+                    }
+                    let top = sp as usize;
+                    let value = match binary.apply(arithmetic_mode, stack[top - 1], stack[top]) {
+                        Ok(value) => value,
+                        Err(_) => break,
+                    };
+                    stack[top - 1] = value;
+                    sp -= 2;
+                    program_counter = if value == 0 { target } else { program_counter + 2 };
+                    2
+                }
+                Op::Interpret => break,
+            };
+            max_sp = max_sp.max(sp);
+            executed += steps;
+            budget -= steps;
+        }
+
+        vsm.program_counter = program_counter;
+        vsm.stack_pointer = if sp < 0 { None } else { Some(sp as usize) };
+        vsm.global_top_address = b0;
+        vsm.frame_top_address = b1;
+        vsm.max_stack_pointer = max_sp as usize;
+        vsm.executed_instructions += executed;
+    }
+}
@@ -0,0 +1,234 @@
+use std::collections::{BTreeMap, VecDeque};
+
+use crate::code::Code;
+use crate::vsm::VsmError;
+
+// ALLOC で確保できるセルの合計 (解放済みのブロックは含まない)
+pub const DEFAULT_MAX_HEAP_SIZE: usize = 1 << 20;
+
+// 解放後のアクセスを検出するために残す解放済みのブロックの数. 超えたら古いものから忘れる
+pub const MAX_FREED_BLOCKS: usize = 1024;
+
+// ALLOC で確保した領域. 解放後もアドレスと大きさは残し, 解放済みの領域へのアクセスを検出する
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HeapBlock {
+    pub address: usize,
+    pub size: usize,
+    pub allocated_at: usize,
+    pub freed_at: Option<usize>,
+    pub values: Vec<i32>,
+}
+
+impl HeapBlock {
+    pub fn is_freed(&self) -> bool {
+        self.freed_at.is_some()
+    }
+}
+
+// 取り消し用に記録するヒープの変更
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HeapChange {
+    Write { address: usize, old_value: i32 },
+    Allocate { address: usize },
+    Free { address: usize, values: Vec<i32>, forgotten: Option<HeapBlock> },
+}
+
+// スタックとは別のアドレス空間. アドレスは 1 から順に割り当てて再利用しないので, 0 は NULL として使える.
+// ブロックの間には 1 セルの隙間を空け, 直前や直後のセルへのはみ出しを範囲外のアクセスとして検出する.
+// 解放済みのブロックは最近の MAX_FREED_BLOCKS 個だけ残し, それより古いものへのアクセスは範囲外として扱う
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Heap {
+    blocks: BTreeMap<usize, HeapBlock>,
+    // 残している解放済みのブロックのアドレス (解放した順)
+    freed: VecDeque<usize>,
+    next_address: usize,
+    used: usize,
+    max_size: usize,
+}
+
+impl Default for Heap {
+    fn default() -> Heap {
+        Heap::new(DEFAULT_MAX_HEAP_SIZE)
+    }
+}
+
+impl Heap {
+    pub fn new(max_size: usize) -> Heap {
+        Heap {
+            blocks: BTreeMap::new(),
+            freed: VecDeque::new(),
+            next_address: 1,
+            used: 0,
+            max_size,
+        }
+    }
+
+    // スナップショットから戻す. used は解放されていないブロックから, 解放した順は freed_at から求め直す
+    pub(crate) fn from_blocks(next_address: usize, blocks: Vec<HeapBlock>, max_size: usize) -> Heap {
+        let mut blocks = blocks.into_iter().map(|block| (block.address, block)).collect::<BTreeMap<_, _>>();
+        let used = blocks.values().filter(|block| !block.is_freed()).map(|block| block.size).sum();
+        let mut freed = blocks.values().filter_map(|block| Some((block.freed_at?, block.address))).collect::<Vec<_>>();
+        freed.sort();
+        let forgotten = freed.len().saturating_sub(MAX_FREED_BLOCKS);
+        for (_, address) in freed.drain(..forgotten) {
+            blocks.remove(&address);
+        }
+        Heap {
+            blocks,
+            freed: freed.into_iter().map(|(_, address)| address).collect(),
+            next_address: next_address.max(1),
+            used,
+            max_size,
+        }
+    }
+
+    pub fn max_size(&self) -> usize {
+        self.max_size
+    }
+
+    pub(crate) fn set_max_size(&mut self, max_size: usize) {
+        self.max_size = max_size;
+    }
+
+    // 解放されていないセルの合計
+    pub fn used(&self) -> usize {
+        self.used
+    }
+
+    pub fn next_address(&self) -> usize {
+        self.next_address
+    }
+
+    // 残している解放済みのものも含めてアドレス順に返す
+    pub fn blocks(&self) -> impl Iterator<Item = &HeapBlock> {
+        self.blocks.values()
+    }
+
+    // 解放されていないブロック
+    pub fn leaks(&self) -> Vec<&HeapBlock> {
+        self.blocks.values().filter(|block| !block.is_freed()).collect()
+    }
+
+    pub fn allocate(&mut self, size: i32, program_counter: usize) -> Result<usize, VsmError> {
+        let size = usize::try_from(size).map_err(|_| VsmError::InvalidAllocationSize(size))?;
+        let address = self.next_address;
+        let next_address = address + size + 1;
+        if self.used + size > self.max_size {
+            return Err(VsmError::HeapExhausted(self.max_size));
+        }
+        if next_address > i32::MAX as usize {
+            return Err(VsmError::HeapAddressSpaceExhausted);
+        }
+        self.blocks.insert(
+            address,
+            HeapBlock {
+                address,
+                size,
+                allocated_at: program_counter,
+                freed_at: None,
+                values: vec![0; size],
+            },
+        );
+        self.next_address = next_address;
+        self.used += size;
+        Ok(address)
+    }
+
+    // 取り消し用の変更を返す. NULL の解放は何もしない
+    pub fn free(&mut self, address: i32, program_counter: usize) -> Result<Option<HeapChange>, VsmError> {
+        if address == 0 {
+            return Ok(None);
+        }
+        let block = usize::try_from(address)
+            .ok()
+            .and_then(|key| self.blocks.get_mut(&key))
+            .ok_or(VsmError::InvalidFree(address))?;
+        if block.is_freed() {
+            return Err(VsmError::DoubleFree(address));
+        }
+        block.freed_at = Some(program_counter);
+        self.used -= block.size;
+        let values = std::mem::take(&mut block.values);
+        let address = block.address;
+        self.freed.push_back(address);
+        let forgotten = if self.freed.len() > MAX_FREED_BLOCKS {
+            self.freed.pop_front().and_then(|oldest| self.blocks.remove(&oldest))
+        } else {
+            None
+        };
+        Ok(Some(HeapChange::Free { address, values, forgotten }))
+    }
+
+    // address を含むブロックの先頭アドレスと, その中での位置
+    fn locate(&self, address: i32) -> Result<(usize, usize), VsmError> {
+        let key = usize::try_from(address).map_err(|_| VsmError::HeapOutOfBounds(address))?;
+        match self.blocks.range(..=key).next_back() {
+            Some((_, block)) if key - block.address >= block.size => Err(VsmError::HeapOutOfBounds(address)),
+            Some((_, block)) if block.is_freed() => Err(VsmError::UseAfterFree(address)),
+            Some((_, block)) => Ok((block.address, key - block.address)),
+            None => Err(VsmError::HeapOutOfBounds(address)),
+        }
+    }
+
+    pub fn read(&self, address: i32) -> Result<i32, VsmError> {
+        let (block, offset) = self.locate(address)?;
+        Ok(self.blocks[&block].values[offset])
+    }
+
+    // 書き込む前の値を返す
+    pub fn write(&mut self, address: i32, value: i32) -> Result<i32, VsmError> {
+        let (block, offset) = self.locate(address)?;
+        let block = self.blocks.get_mut(&block).ok_or(VsmError::HeapOutOfBounds(address))?;
+        Ok(std::mem::replace(&mut block.values[offset], value))
+    }
+
+    // 新しい変更から順に戻す
+    pub(crate) fn undo(&mut self, change: HeapChange) {
+        match change {
+            HeapChange::Write { address, old_value } => {
+                if let Some((_, block)) = self.blocks.range_mut(..=address).next_back() {
+                    block.values[address - block.address] = old_value;
+                }
+            }
+            HeapChange::Allocate { address } => {
+                if let Some(block) = self.blocks.remove(&address) {
+                    self.used -= block.size;
+                    self.next_address = address;
+                }
+            }
+            HeapChange::Free { address, values, forgotten } => {
+                if let Some(block) = self.blocks.get_mut(&address) {
+                    block.freed_at = None;
+                    block.values = values;
+                    self.used += block.size;
+                    self.freed.pop_back();
+                }
+                if let Some(block) = forgotten {
+                    self.freed.push_front(block.address);
+                    self.blocks.insert(block.address, block);
+                }
+            }
+        }
+    }
+
+    // EXIT の時点で解放されていないブロックの一覧. なければ空文字列
+    pub fn leak_report(&self, code: &Code) -> String {
+        let leaks = self.leaks();
+        if leaks.is_empty() {
+            return String::new();
+        }
+        let cells = leaks.iter().map(|block| block.size).sum::<usize>();
+        let mut report = format!("leak: {} heap block(s) ({} cells) not freed at EXIT\n", leaks.len(), cells);
+        for block in leaks {
+            report += &format!("  address {}: {} cells allocated at {:04}", block.address, block.size, block.allocated_at);
+            if block.allocated_at < code.len() {
+                report += &format!(" '{}'", code.get_instruction(block.allocated_at));
+            }
+            if let Some(location) = code.source_location(block.allocated_at) {
+                report += &format!("  // {}", location);
+            }
+            report.push('\n');
+        }
+        report
+    }
+}
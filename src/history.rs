@@ -0,0 +1,117 @@
+use std::collections::VecDeque;
+
+use crate::code::Instruction;
+use crate::heap::HeapChange;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StackWrite {
+    pub address: usize,
+    pub old_value: i32,
+    pub new_value: i32,
+}
+
+// 一命令の実行前のレジスタと, 実行中に書き込んだスタックのセルやヒープの変更
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UndoRecord {
+    pub program_counter: usize,
+    pub instruction: Instruction,
+    pub stack_pointer: Option<usize>,
+    pub global_top_address: usize,
+    pub frame_top_address: usize,
+    pub max_stack_pointer: usize,
+    pub input_position: usize,
+    pub writes: Vec<StackWrite>,
+    pub heap_changes: Vec<HeapChange>,
+}
+
+// S[address] に最後に書き込んだ命令. steps_ago は何命令前に実行したか (直前の命令が 1)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LastWrite {
+    pub steps_ago: usize,
+    pub program_counter: usize,
+    pub instruction: Instruction,
+    pub old_value: i32,
+    pub new_value: i32,
+}
+
+// 直近 capacity 命令分の取り消し用の記録. 溢れた分は古いものから捨てる
+#[derive(Debug, Clone)]
+pub struct History {
+    records: VecDeque<UndoRecord>,
+    capacity: usize,
+}
+
+impl History {
+    pub fn new(capacity: usize) -> History {
+        History {
+            records: VecDeque::new(),
+            capacity,
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    pub fn records(&self) -> impl DoubleEndedIterator<Item = &UndoRecord> {
+        self.records.iter()
+    }
+
+    pub(crate) fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        while self.records.len() > capacity {
+            self.records.pop_front();
+        }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.records.clear();
+    }
+
+    pub(crate) fn push(&mut self, record: UndoRecord) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.records.len() == self.capacity {
+            self.records.pop_front();
+        }
+        self.records.push_back(record);
+    }
+
+    // 実行中の命令 (最後に push した記録) に書き込みを追加する
+    pub(crate) fn record_write(&mut self, write: StackWrite) {
+        if let Some(record) = self.records.back_mut() {
+            record.writes.push(write);
+        }
+    }
+
+    pub(crate) fn record_heap_change(&mut self, change: HeapChange) {
+        if let Some(record) = self.records.back_mut() {
+            record.heap_changes.push(change);
+        }
+    }
+
+    pub(crate) fn pop(&mut self) -> Option<UndoRecord> {
+        self.records.pop_back()
+    }
+
+    pub fn last_write(&self, address: usize) -> Option<LastWrite> {
+        self.records.iter().rev().enumerate().find_map(|(index, record)| {
+            record.writes.iter().rev().find(|write| write.address == address).map(|write| LastWrite {
+                steps_ago: index + 1,
+                program_counter: record.program_counter,
+                instruction: record.instruction,
+                old_value: write.old_value,
+                new_value: write.new_value,
+            })
+        })
+    }
+}
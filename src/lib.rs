@@ -1,2 +1,14 @@
+pub mod bytecode;
 pub mod code;
+pub mod compiler;
+pub mod coverage;
+pub mod debugger;
+pub mod disasm;
+pub mod engine;
+pub mod heap;
+pub mod history;
+pub mod optimizer;
+pub mod profiler;
+pub mod snapshot;
+pub mod verifier;
 pub mod vsm;
@@ -1,24 +1,327 @@
+use virtual_stack_machine::compiler;
+use virtual_stack_machine::debugger::{Debugger, DEFAULT_HISTORY_SIZE};
+use virtual_stack_machine::disasm;
+use virtual_stack_machine::heap::DEFAULT_MAX_HEAP_SIZE;
+use virtual_stack_machine::optimizer;
+use virtual_stack_machine::profiler::ProfileFormat;
+use virtual_stack_machine::snapshot::{self, Snapshot};
+use virtual_stack_machine::verifier;
 use virtual_stack_machine::vsm::*;
 use std::env;
+use std::fs::{self, File};
+use std::io::{self, BufRead};
+use std::time::Duration;
+
+const USAGE: &str = "\
+options:
+  -t                        trace the stack after every instruction
+  -d                        start the interactive debugger (commands are read from stdin)
+  --history-size <n>        let the debugger step back up to <n> instructions (default 100000)
+  -O                        optimize the program with peephole rules before running it
+  --fast                    run the program with the pre-decoded execution engine
+  --profile                 print instruction and function counts to stderr after running
+  --profile-output <file>   write the profile to <file> (.csv and .json select the format)
+  --coverage                print the source annotated with execution counts to stderr
+  --coverage-output <file>  write the annotated source to <file>
+  --input <file>            read program input (GETC/GETI) from <file>
+  --arith <mode>            handle arithmetic overflow with trap (default), wrapping or saturating
+  --assemble <file>         write the program as bytecode to <file> and exit
+  --disassemble             print the disassembled program and exit
+  --verify                  check the stack usage of the program before running it
+  --save-snapshot <file>    save the machine state to <file> when the program stops
+  --max-steps <n>           abort after executing <n> instructions
+  --timeout-ms <n>          abort after <n> milliseconds
+  --max-stack-depth <n>     abort when the stack grows beyond <n> cells
+  --stack-size <n>          allocate <n> stack cells at start (default 1024)
+  --max-stack-size <n>      let the stack grow up to <n> cells before overflowing (default 1048576)
+  --max-heap-size <n>       let ALLOC allocate up to <n> cells in total (default 1048576)";
+struct Options {
+    vsm_file: String,
+    trace_type: TraceType,
+    debug: bool,
+    history_size: usize,
+    optimize: bool,
+    fast: bool,
+    profile: bool,
+    profile_file: Option<String>,
+    coverage: bool,
+    coverage_file: Option<String>,
+    input_file: Option<String>,
+    arithmetic_mode: ArithmeticMode,
+    limits: ExecutionLimits,
+    stack_size: usize,
+    max_stack_size: usize,
+    max_heap_size: usize,
+    assemble_file: Option<String>,
+    disassemble: bool,
+    verify: bool,
+    snapshot_file: Option<String>,
+}
+
+fn parse_number<T: std::str::FromStr>(option: &str, value: Option<&String>) -> Result<T, String> {
+    match value {
+        Some(value) => value
+            .parse::<T>()
+            .map_err(|_| format!("'{}' requires a non-negative integer but '{}' is given", option, value)),
+        None => Err(format!("'{}' requires a non-negative integer", option)),
+    }
+}
+
+fn parse_args(args: &[String]) -> Result<Options, String> {
+    let mut vsm_file = None;
+    let mut trace_type = TraceType::No;
+    let mut debug = false;
+    let mut history_size = DEFAULT_HISTORY_SIZE;
+    let mut optimize = false;
+    let mut fast = false;
+    let mut profile = false;
+    let mut profile_file = None;
+    let mut coverage = false;
+    let mut coverage_file = None;
+    let mut input_file = None;
+    let mut arithmetic_mode = ArithmeticMode::default();
+    let mut limits = ExecutionLimits::default();
+    let mut stack_size = DEFAULT_STACK_SIZE;
+    let mut max_stack_size = DEFAULT_MAX_STACK_SIZE;
+    let mut max_heap_size = DEFAULT_MAX_HEAP_SIZE;
+    let mut assemble_file = None;
+    let mut disassemble = false;
+    let mut verify = false;
+    let mut snapshot_file = None;
+
+    let mut iter = args.iter().skip(1);
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "-t" => trace_type = TraceType::TraceStack,
+            "-d" => debug = true,
+            "--history-size" => history_size = parse_number(arg, iter.next())?,
+            "-O" => optimize = true,
+            "--fast" => fast = true,
+            "--profile" => profile = true,
+            "--profile-output" => match iter.next() {
+                Some(file) => profile_file = Some(file.clone()),
+                None => return Err("'--profile-output' requires a file".to_string()),
+            },
+            "--coverage" => coverage = true,
+            "--coverage-output" => match iter.next() {
+                Some(file) => coverage_file = Some(file.clone()),
+                None => return Err("'--coverage-output' requires a file".to_string()),
+            },
+            "--input" => match iter.next() {
+                Some(file) => input_file = Some(file.clone()),
+                None => return Err("'--input' requires a file".to_string()),
+            },
+            "--arith" => match iter.next() {
+                Some(mode) => arithmetic_mode = mode.parse()?,
+                None => return Err("'--arith' requires a mode".to_string()),
+            },
+            "--assemble" => match iter.next() {
+                Some(file) => assemble_file = Some(file.clone()),
+                None => return Err("'--assemble' requires a file".to_string()),
+            },
+            "--disassemble" => disassemble = true,
+            "--verify" => verify = true,
+            "--save-snapshot" => match iter.next() {
+                Some(file) => snapshot_file = Some(file.clone()),
+                None => return Err("'--save-snapshot' requires a file".to_string()),
+            },
+            "--max-steps" => limits.max_instructions = Some(parse_number(arg, iter.next())?),
+            "--timeout-ms" => {
+                limits.max_duration = Some(Duration::from_millis(parse_number(arg, iter.next())?))
+            }
+            "--max-stack-depth" => limits.max_stack_depth = Some(parse_number(arg, iter.next())?),
+            "--stack-size" => stack_size = parse_number(arg, iter.next())?,
+            "--max-stack-size" => max_stack_size = parse_number(arg, iter.next())?,
+            "--max-heap-size" => max_heap_size = parse_number(arg, iter.next())?,
+            _ if arg.starts_with('-') => return Err(format!("unknown option '{}'", arg)),
+            _ if vsm_file.is_none() => vsm_file = Some(arg.clone()),
+            _ => return Err(format!("unexpected argument '{}'", arg)),
+        }
+    }
+
+    match vsm_file {
+        Some(vsm_file) => Ok(Options { vsm_file, trace_type, debug, history_size, optimize, fast, profile, profile_file, coverage, coverage_file, input_file, arithmetic_mode, limits, stack_size, max_stack_size, max_heap_size, assemble_file, disassemble, verify, snapshot_file }),
+        None => Err("no vsm_file is given".to_string()),
+    }
+}
 
 fn main() {
 
     let args: Vec<String> = env::args().collect();
-    if args.len() < 1 {
-        eprintln!("Usage: {} <vsm_file> <option>", &args[0]);
+    let options = match parse_args(&args) {
+        Ok(options) => options,
+        Err(err) => {
+            eprintln!("error: {}", err);
+            eprintln!("Usage: {} <vsm_file> <option>", &args[0]);
+            eprintln!("{}", USAGE);
+            std::process::exit(1);
+        }
+    };
+    let vsm_file = &options.vsm_file;
+
+    // デバッグ時は stdin をデバッガのコマンドに使うので, プログラムの入力は --input で与える
+    let input: Box<dyn BufRead> = match &options.input_file {
+        Some(input_file) => match File::open(input_file) {
+            Ok(file) => Box::new(io::BufReader::new(file)),
+            Err(err) => {
+                eprintln!("error: cannot open input file '{}': {}", input_file, err);
+                std::process::exit(1);
+            }
+        },
+        None if options.debug => Box::new(io::empty()),
+        None => Box::new(io::BufReader::new(io::stdin())),
+    };
+    let mut vsm = Vsm::with_io(options.trace_type, input, Box::new(io::stdout()));
+    vsm.set_limits(options.limits);
+    vsm.set_stack_size(options.stack_size, options.max_stack_size);
+    vsm.set_arithmetic_mode(options.arithmetic_mode);
+    vsm.set_max_heap_size(options.max_heap_size);
+
+    // スナップショットのファイルは保存した状態から再開する. 命令のアドレスが変わるので最適化はできない
+    let snapshot = match fs::read(vsm_file) {
+        Ok(bytes) if snapshot::is_snapshot(&bytes) => {
+            Some(Snapshot::from_bytes_with_max_stack_size(&bytes, options.max_stack_size))
+        }
+        _ => None,
+    };
+    if let Some(snapshot) = snapshot {
+        match snapshot {
+            Ok(_) if options.optimize => {
+                eprintln!("error: '-O' cannot be used when resuming a snapshot");
+                std::process::exit(1);
+            }
+            Ok(snapshot) => vsm.restore(&snapshot),
+            Err(err) => {
+                eprintln!("error: cannot resume `{}`: {}", vsm_file, err);
+                std::process::exit(1);
+            }
+        }
+    // .c のファイルはコンパイルしてから実行する
+    } else if vsm_file.ends_with(".c") {
+        match compiler::compile_file(vsm_file) {
+            Ok(code) => vsm.load_code(code),
+            Err(errors) => {
+                errors.iter().for_each(|error| eprintln!("{}", error.render()));
+                eprintln!("error: could not compile `{}` due to {} previous error(s)", vsm_file, errors.len());
+                std::process::exit(1);
+            }
+        }
+    } else if let Err(errors) = vsm.read_code(vsm_file) {
+        errors.iter().for_each(|error| eprintln!("{}", error.render()));
+        eprintln!("error: could not assemble `{}` due to {} previous error(s)", vsm_file, errors.len());
         std::process::exit(1);
     }
 
-    let vsm_file = &args[1];
-    let trace_type = if args.iter().any(|arg| arg == "-t") {
-        TraceType::TraceStack
-    }else {
-        TraceType::No
+    // 最適化の結果は標準エラー出力に表示する
+    if options.optimize {
+        let (code, report) = optimizer::optimize(vsm.code());
+        eprintln!("{}", report);
+        vsm.load_code(code);
+    }
+
+    // 警告だけなら実行を続ける
+    if options.verify {
+        let diagnostics = verifier::verify(vsm.code());
+        diagnostics.iter().for_each(|diagnostic| eprintln!("{}", diagnostic));
+        let error_count = diagnostics.iter().filter(|diagnostic| diagnostic.is_error()).count();
+        if error_count > 0 {
+            eprintln!("error: `{}` failed verification with {} error(s)", vsm_file, error_count);
+            std::process::exit(1);
+        }
+    }
+
+    if let Some(assemble_file) = &options.assemble_file {
+        if let Err(err) = vsm.code().write_bytes(assemble_file) {
+            eprintln!("error: cannot write bytecode '{}': {}", assemble_file, err);
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if options.disassemble {
+        print!("{}", disasm::disassemble_with_source(vsm.code()));
+        return;
+    }
+
+    if options.profile || options.profile_file.is_some() {
+        vsm.enable_profiling();
+    }
+    if options.coverage || options.coverage_file.is_some() {
+        vsm.enable_coverage();
+    }
+
+    let result = if options.debug {
+        vsm.enable_history(options.history_size);
+        let mut debugger = Debugger::new(
+            &mut vsm,
+            Box::new(io::BufReader::new(io::stdin())),
+            Box::new(io::stdout()),
+        );
+        match debugger.run() {
+            Ok(result) => result.map(|return_code| return_code.unwrap_or(0)),
+            Err(err) => {
+                eprintln!("error: debugger I/O error: {}", err);
+                std::process::exit(1);
+            }
+        }
+    } else if options.fast {
+        vsm.exec_code_fast()
+    } else {
+        vsm.exec_code()
     };
 
+    // 実行時エラーや制限で止まった場合もその時点の状態を保存する
+    if let Some(snapshot_file) = &options.snapshot_file {
+        if let Err(err) = vsm.snapshot().write(snapshot_file) {
+            eprintln!("error: cannot write snapshot '{}': {}", snapshot_file, err);
+            std::process::exit(1);
+        }
+    }
+
+    // 実行時エラーで止まった場合もそこまでの結果を出力する
+    if let Some(profile) = vsm.profile() {
+        if options.profile {
+            eprint!("{}", profile);
+        }
+        if let Some(profile_file) = &options.profile_file {
+            let format = ProfileFormat::from_path(profile_file);
+            if let Err(err) = fs::write(profile_file, profile.render(format)) {
+                eprintln!("error: cannot write profile '{}': {}", profile_file, err);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    // ソースを読めない場合 (バイトコードだけがある場合など) は命令ごとに表示する
+    if let Some(coverage) = vsm.coverage() {
+        let listing = match vsm.code().source_file().map(fs::read_to_string) {
+            Some(Ok(source)) => coverage.annotate(vsm.code(), &source),
+            _ => coverage.annotate_instructions(vsm.code()),
+        };
+        if options.coverage {
+            eprint!("{}", listing);
+        }
+        if let Some(coverage_file) = &options.coverage_file {
+            if let Err(err) = fs::write(coverage_file, listing) {
+                eprintln!("error: cannot write coverage '{}': {}", coverage_file, err);
+                std::process::exit(1);
+            }
+        }
+    }
 
-    let mut vsm = Vsm::new(trace_type);
+    // EXIT まで解放されなかったヒープのブロックを報告する. デバッガは終了時に自分で表示する
+    if result.is_ok() && !options.debug {
+        eprint!("{}", vsm.heap().leak_report(vsm.code()));
+    }
 
-    vsm.read_code(vsm_file).expect(&format!("File cannot be read filepath='{}'", vsm_file));
-    vsm.exec_code().expect(&format!("Runtime error filepath='{}'", vsm_file));
+    match result {
+        Ok(return_code) => std::process::exit(return_code),
+        Err(err) => {
+            eprintln!("Runtime error filepath='{}': {}", vsm_file, err);
+            if let Some(source) = &err.source {
+                eprint!("{}", source.span().snippet(None));
+            }
+            std::process::exit(1);
+        }
+    }
 }
@@ -0,0 +1,351 @@
+use core::fmt;
+use std::collections::BTreeMap;
+
+use crate::code::{Code, Instruction, OperationCode, SourceLocation};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Rule {
+    ConstantFolding,
+    AlgebraicIdentity,
+    RedundantStackOperation,
+    MergeIsp,
+    JumpThreading,
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Rule::ConstantFolding => write!(f, "constant folding"),
+            Rule::AlgebraicIdentity => write!(f, "algebraic identity"),
+            Rule::RedundantStackOperation => write!(f, "redundant DUP/ISP"),
+            Rule::MergeIsp => write!(f, "ISP merging"),
+            Rule::JumpThreading => write!(f, "jump threading"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OptimizationReport {
+    pub before: usize,
+    pub after: usize,
+    // 規則ごとの適用回数
+    pub applied: BTreeMap<Rule, usize>,
+}
+
+impl OptimizationReport {
+    pub fn removed(&self) -> usize {
+        self.before - self.after
+    }
+}
+
+impl fmt::Display for OptimizationReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "optimized {} -> {} instructions ({} removed)",
+            self.before,
+            self.after,
+            self.removed()
+        )?;
+        for (rule, count) in &self.applied {
+            write!(f, "\n  {}: {}", rule, count)?;
+        }
+        Ok(())
+    }
+}
+
+// 分岐先は絶対アドレスで持ち, 最後に B / BZ の相対オフセットに戻す.
+// コードの外を指す分岐先はそのまま残す
+#[derive(Debug, Clone)]
+struct Item {
+    instruction: Instruction,
+    target: Option<i64>,
+    source: Option<SourceLocation>,
+}
+
+impl Item {
+    fn operation_code(&self) -> OperationCode {
+        self.instruction.operation_code
+    }
+
+    fn operand(&self) -> i32 {
+        self.instruction.operand[0].unwrap_or(0)
+    }
+
+    fn constant(&self) -> Option<i32> {
+        match self.operation_code() {
+            OperationCode::Lc => Some(self.operand()),
+            _ => None,
+        }
+    }
+}
+
+fn instruction(operation_code: OperationCode, operand: Option<i32>) -> Instruction {
+    Instruction {
+        operation_code,
+        operand: [operand, None],
+    }
+}
+
+fn fold(operation_code: OperationCode, a: i32, b: i32) -> Option<i32> {
+    match operation_code {
+        OperationCode::Add => a.checked_add(b),
+        OperationCode::Sub => a.checked_sub(b),
+        OperationCode::Mul => a.checked_mul(b),
+        OperationCode::Div => a.checked_div(b),
+        OperationCode::Mod => a.checked_rem(b),
+        OperationCode::Eq => Some((a == b) as i32),
+        OperationCode::Ne => Some((a != b) as i32),
+        OperationCode::Gt => Some((a > b) as i32),
+        OperationCode::Lt => Some((a < b) as i32),
+        OperationCode::Ge => Some((a >= b) as i32),
+        OperationCode::Le => Some((a <= b) as i32),
+        _ => None,
+    }
+}
+
+// 覗き穴最適化. 分岐先をまたぐ書き換えはしない
+pub struct Optimizer<'a> {
+    code: &'a Code,
+    items: Vec<Item>,
+    labels: Vec<(String, usize)>,
+    applied: BTreeMap<Rule, usize>,
+}
+
+impl<'a> Optimizer<'a> {
+    pub fn new(code: &'a Code) -> Optimizer<'a> {
+        let items = (0..code.len())
+            .map(|address| {
+                let instruction = code.get_instruction(address);
+                let target = match instruction {
+                    Instruction {
+                        operation_code: OperationCode::B | OperationCode::Bz,
+                        operand: [Some(offset), _],
+                    } => Some(address as i64 + 1 + offset as i64),
+                    Instruction {
+                        operation_code: OperationCode::Call,
+                        operand: [Some(target), _],
+                    } => Some(target as i64),
+                    _ => None,
+                };
+                Item {
+                    instruction,
+                    target,
+                    source: code.source_location(address).cloned(),
+                }
+            })
+            .collect();
+        let mut labels = code
+            .labels()
+            .iter()
+            .map(|(label, address)| (label.clone(), *address))
+            .collect::<Vec<_>>();
+        labels.sort();
+
+        Optimizer {
+            code,
+            items,
+            labels,
+            applied: BTreeMap::new(),
+        }
+    }
+
+    pub fn optimize(mut self) -> (Code, OptimizationReport) {
+        let before = self.items.len();
+        loop {
+            let threaded = self.thread_jumps();
+            let rewritten = self.rewrite();
+            if !threaded && !rewritten {
+                break;
+            }
+        }
+
+        let mut code = Code::new();
+        for (address, item) in self.items.iter().enumerate() {
+            let operand = match (item.operation_code(), item.target) {
+                (OperationCode::B | OperationCode::Bz, Some(target)) => Some((target - (address as i64 + 1)) as i32),
+                (OperationCode::Call, Some(target)) => Some(target as i32),
+                _ => item.instruction.operand[0],
+            };
+            code.append_instruction(item.operation_code(), operand, item.instruction.operand[1]);
+        }
+        code.set_source_map(
+            self.code.source_file().map(|file| file.to_string()),
+            self.items.iter().map(|item| item.source.clone()).collect(),
+        );
+        for (label, address) in self.labels {
+            code.insert_label(label, address);
+        }
+        code.set_data(self.code.data().to_vec(), self.code.data_labels().clone());
+
+        let report = OptimizationReport {
+            before,
+            after: code.len(),
+            applied: self.applied,
+        };
+        (code, report)
+    }
+
+    fn count(&mut self, rule: Rule) {
+        *self.applied.entry(rule).or_default() += 1;
+    }
+
+    fn in_code(&self, target: Option<i64>) -> Option<usize> {
+        target
+            .filter(|target| 0 <= *target && *target < self.items.len() as i64)
+            .map(|target| target as usize)
+    }
+
+    // B / BZ の分岐先が B なら, その先へ直接分岐する
+    fn thread_jumps(&mut self) -> bool {
+        let mut changed = false;
+        for address in 0..self.items.len() {
+            if !matches!(self.items[address].operation_code(), OperationCode::B | OperationCode::Bz) {
+                continue;
+            }
+            let mut target = self.items[address].target;
+            let mut visited = vec![address];
+            while let Some(next) = self.in_code(target) {
+                if self.items[next].operation_code() != OperationCode::B {
+                    break;
+                }
+                // B だけの無限ループはそのまま残す
+                if visited.contains(&next) {
+                    target = self.items[address].target;
+                    break;
+                }
+                visited.push(next);
+                target = self.items[next].target;
+            }
+            if target != self.items[address].target {
+                self.items[address].target = target;
+                self.count(Rule::JumpThreading);
+                changed = true;
+            }
+        }
+        changed
+    }
+
+    fn branch_targets(&self) -> Vec<bool> {
+        let mut is_target = vec![false; self.items.len() + 1];
+        for item in &self.items {
+            if let Some(target) = self.in_code(item.target) {
+                is_target[target] = true;
+            }
+        }
+        for (_, address) in &self.labels {
+            if let Some(is_target) = is_target.get_mut(*address) {
+                *is_target = true;
+            }
+        }
+        is_target
+    }
+
+    fn rewrite(&mut self) -> bool {
+        let is_target = self.branch_targets();
+        let mut items = Vec::with_capacity(self.items.len());
+        let mut address_map = vec![0; self.items.len() + 1];
+        let mut changed = false;
+
+        let mut address = 0;
+        while address < self.items.len() {
+            match self.match_rule(address, &is_target) {
+                Some((rule, length, replacement)) => {
+                    address_map[address..address + length].fill(items.len());
+                    let source = &self.items[address].source;
+                    items.extend(replacement.into_iter().map(|instruction| Item {
+                        instruction,
+                        target: None,
+                        source: source.clone(),
+                    }));
+                    self.count(rule);
+                    changed = true;
+                    address += length;
+                }
+                None => {
+                    address_map[address] = items.len();
+                    items.push(self.items[address].clone());
+                    address += 1;
+                }
+            }
+        }
+        address_map[self.items.len()] = items.len();
+
+        let length = self.items.len() as i64;
+        for item in &mut items {
+            item.target = item.target.map(|target| match target {
+                0.. if target <= length => address_map[target as usize] as i64,
+                _ => target,
+            });
+        }
+        for (_, address) in &mut self.labels {
+            if let Some(new_address) = address_map.get(*address) {
+                *address = *new_address;
+            }
+        }
+        self.items = items;
+        changed
+    }
+
+    // address から始まる命令列に当てはまる規則と, 置き換える命令の数と置き換え後の命令列を返す.
+    // 途中の命令が分岐先になっている場合は書き換えない
+    fn match_rule(&self, address: usize, is_target: &[bool]) -> Option<(Rule, usize, Vec<Instruction>)> {
+        let window = |length: usize| -> &[Item] {
+            if address + length > self.items.len() || is_target[address + 1..address + length].contains(&true) {
+                &[]
+            } else {
+                &self.items[address..address + length]
+            }
+        };
+
+        if let [a, b, operation] = window(3) {
+            if let (Some(a), Some(b)) = (a.constant(), b.constant()) {
+                if let Some(value) = fold(operation.operation_code(), a, b) {
+                    return Some((Rule::ConstantFolding, 3, vec![instruction(OperationCode::Lc, Some(value))]));
+                }
+            }
+            if a.operation_code() == OperationCode::Dup
+                && matches!(b.operation_code(), OperationCode::Putc | OperationCode::Puti)
+                && operation.operation_code() == OperationCode::Isp
+                && operation.operand() == -1
+            {
+                return Some((Rule::RedundantStackOperation, 3, vec![b.instruction]));
+            }
+        }
+
+        if let [first, second] = window(2) {
+            match (first.operation_code(), first.operand(), second.operation_code()) {
+                (OperationCode::Lc, value, OperationCode::Inv) => {
+                    if let Some(value) = value.checked_neg() {
+                        return Some((Rule::ConstantFolding, 2, vec![instruction(OperationCode::Lc, Some(value))]));
+                    }
+                }
+                (OperationCode::Lc, 1, OperationCode::Mul | OperationCode::Div)
+                | (OperationCode::Lc, 0, OperationCode::Add | OperationCode::Sub) => {
+                    return Some((Rule::AlgebraicIdentity, 2, vec![]));
+                }
+                (OperationCode::Isp, a, OperationCode::Isp) => {
+                    if let Some(value) = a.checked_add(second.operand()) {
+                        return Some((Rule::MergeIsp, 2, vec![instruction(OperationCode::Isp, Some(value))]));
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let item = &self.items[address];
+        match item.operation_code() {
+            OperationCode::Isp if item.operand() == 0 => Some((Rule::RedundantStackOperation, 1, vec![])),
+            // 次の命令への分岐
+            OperationCode::B if item.target == Some(address as i64 + 1) => Some((Rule::JumpThreading, 1, vec![])),
+            OperationCode::Bz if item.target == Some(address as i64 + 1) => {
+                Some((Rule::JumpThreading, 1, vec![instruction(OperationCode::Isp, Some(-1))]))
+            }
+            _ => None,
+        }
+    }
+}
+
+pub fn optimize(code: &Code) -> (Code, OptimizationReport) {
+    Optimizer::new(code).optimize()
+}
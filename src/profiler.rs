@@ -0,0 +1,319 @@
+use core::fmt;
+use std::collections::BTreeMap;
+
+use crate::code::{Code, Instruction, OperationCode};
+use crate::disasm::Disassembler;
+
+// テキストの報告に載せる命令の数
+const HOT_INSTRUCTIONS: usize = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProfileFormat {
+    Text,
+    Csv,
+    Json,
+}
+
+impl ProfileFormat {
+    // 出力ファイルの拡張子から形式を決める
+    pub fn from_path(file_path: &str) -> ProfileFormat {
+        if file_path.ends_with(".csv") {
+            ProfileFormat::Csv
+        } else if file_path.ends_with(".json") {
+            ProfileFormat::Json
+        } else {
+            ProfileFormat::Text
+        }
+    }
+}
+
+// 実行中に命令ごとの実行回数と関数の呼び出しを記録する
+#[derive(Debug, Clone, Default)]
+pub struct Profiler {
+    counts: Vec<u64>,
+    total: u64,
+    calls: BTreeMap<usize, u64>,
+    inclusive: BTreeMap<usize, u64>,
+    // 呼び出し中の関数と, 呼び出した時点の実行命令数
+    frames: Vec<(usize, u64)>,
+    active: BTreeMap<usize, usize>,
+}
+
+impl Profiler {
+    pub fn new() -> Profiler {
+        Profiler::default()
+    }
+
+    pub(crate) fn record(&mut self, program_counter: usize) {
+        if program_counter >= self.counts.len() {
+            self.counts.resize(program_counter + 1, 0);
+        }
+        self.counts[program_counter] += 1;
+        self.total += 1;
+    }
+
+    pub(crate) fn enter(&mut self, target: usize) {
+        *self.calls.entry(target).or_default() += 1;
+        *self.active.entry(target).or_default() += 1;
+        self.frames.push((target, self.total));
+    }
+
+    // 再帰呼び出しは一番外側から戻ったときだけ inclusive に足す
+    pub(crate) fn leave(&mut self) {
+        if let Some((target, entered_at)) = self.frames.pop() {
+            let active = self.active.entry(target).or_default();
+            *active -= 1;
+            if *active == 0 {
+                *self.inclusive.entry(target).or_default() += self.total - entered_at;
+            }
+        }
+    }
+
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    pub fn count(&self, program_counter: usize) -> u64 {
+        self.counts.get(program_counter).copied().unwrap_or(0)
+    }
+
+    // 命令は CALL の分岐先で区切った範囲の関数に属するものとして集計する
+    pub fn report(&self, code: &Code) -> Profile {
+        let disassembler = Disassembler::new(code);
+        let mut entries = (0..code.len())
+            .filter_map(|address| match code.get_instruction(address) {
+                Instruction {
+                    operation_code: OperationCode::Call,
+                    operand: [Some(target), _],
+                } if 0 <= target && (target as usize) < code.len() => Some(target as usize),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        entries.sort();
+        entries.dedup();
+
+        let name = |address: usize| disassembler.labels_at(address).first().cloned().unwrap_or_default();
+        let function_index = |program_counter: usize| entries.partition_point(|entry| *entry <= program_counter);
+
+        // 先頭は最初の関数より前のトップレベル
+        let mut functions = vec![FunctionProfile {
+            name: "(top level)".to_string(),
+            address: 0,
+            calls: 0,
+            inclusive: self.total,
+            exclusive: 0,
+        }];
+        if entries.first() == Some(&0) {
+            functions.clear();
+        }
+        functions.extend(entries.iter().map(|entry| {
+            // まだ戻っていない呼び出しは現在までの分を数える
+            let pending = self
+                .frames
+                .iter()
+                .find(|(target, _)| target == entry)
+                .map_or(0, |(_, entered_at)| self.total - entered_at);
+            FunctionProfile {
+                name: name(*entry),
+                address: *entry,
+                calls: self.calls.get(entry).copied().unwrap_or(0),
+                inclusive: self.inclusive.get(entry).copied().unwrap_or(0) + pending,
+                exclusive: 0,
+            }
+        }));
+        let offset = functions.len() - entries.len();
+
+        let mut instructions = Vec::new();
+        for program_counter in 0..code.len() {
+            let count = self.count(program_counter);
+            if count == 0 {
+                continue;
+            }
+            let function = function_index(program_counter) + offset - 1;
+            functions[function].exclusive += count;
+            instructions.push(InstructionProfile {
+                program_counter,
+                instruction: code.get_instruction(program_counter),
+                line: code.line_number(program_counter),
+                function: functions[function].name.clone(),
+                count,
+            });
+        }
+
+        functions.sort_by(|a, b| b.exclusive.cmp(&a.exclusive).then(a.address.cmp(&b.address)));
+        instructions.sort_by(|a, b| b.count.cmp(&a.count).then(a.program_counter.cmp(&b.program_counter)));
+        Profile {
+            total: self.total,
+            functions,
+            instructions,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FunctionProfile {
+    pub name: String,
+    pub address: usize,
+    pub calls: u64,
+    // 呼び出した関数の中で実行した命令を含む数と含まない数
+    pub inclusive: u64,
+    pub exclusive: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InstructionProfile {
+    pub program_counter: usize,
+    pub instruction: Instruction,
+    pub line: Option<usize>,
+    pub function: String,
+    pub count: u64,
+}
+
+// 関数は exclusive, 命令は実行回数の多い順に並べる
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Profile {
+    pub total: u64,
+    pub functions: Vec<FunctionProfile>,
+    pub instructions: Vec<InstructionProfile>,
+}
+
+fn percent(count: u64, total: u64) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        count as f64 * 100.0 / total as f64
+    }
+}
+
+fn json_string(value: &str) -> String {
+    let mut escaped = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => escaped += "\\\"",
+            '\\' => escaped += "\\\\",
+            '\n' => escaped += "\\n",
+            c if (c as u32) < 0x20 => escaped += &format!("\\u{:04x}", c as u32),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+impl Profile {
+    pub fn render(&self, format: ProfileFormat) -> String {
+        match format {
+            ProfileFormat::Text => self.to_string(),
+            ProfileFormat::Csv => self.to_csv(),
+            ProfileFormat::Json => self.to_json(),
+        }
+    }
+
+    // 関数の表と命令の表を空行で区切って並べる
+    pub fn to_csv(&self) -> String {
+        let mut csv = String::from("function,address,calls,inclusive,exclusive\n");
+        for function in &self.functions {
+            csv += &format!(
+                "{},{},{},{},{}\n",
+                csv_field(&function.name),
+                function.address,
+                function.calls,
+                function.inclusive,
+                function.exclusive
+            );
+        }
+        csv += "\npc,instruction,line,function,count\n";
+        for instruction in &self.instructions {
+            csv += &format!(
+                "{},{},{},{},{}\n",
+                instruction.program_counter,
+                csv_field(&instruction.instruction.to_string()),
+                instruction.line.map(|line| line.to_string()).unwrap_or_default(),
+                csv_field(&instruction.function),
+                instruction.count
+            );
+        }
+        csv
+    }
+
+    pub fn to_json(&self) -> String {
+        let functions = self
+            .functions
+            .iter()
+            .map(|function| {
+                format!(
+                    "    {{\"name\": {}, \"address\": {}, \"calls\": {}, \"inclusive\": {}, \"exclusive\": {}}}",
+                    json_string(&function.name),
+                    function.address,
+                    function.calls,
+                    function.inclusive,
+                    function.exclusive
+                )
+            })
+            .collect::<Vec<_>>();
+        let instructions = self
+            .instructions
+            .iter()
+            .map(|instruction| {
+                format!(
+                    "    {{\"pc\": {}, \"instruction\": {}, \"line\": {}, \"function\": {}, \"count\": {}}}",
+                    instruction.program_counter,
+                    json_string(&instruction.instruction.to_string()),
+                    instruction.line.map(|line| line.to_string()).unwrap_or("null".to_string()),
+                    json_string(&instruction.function),
+                    instruction.count
+                )
+            })
+            .collect::<Vec<_>>();
+        format!(
+            "{{\n  \"total\": {},\n  \"functions\": [\n{}\n  ],\n  \"instructions\": [\n{}\n  ]\n}}\n",
+            self.total,
+            functions.join(",\n"),
+            instructions.join(",\n")
+        )
+    }
+}
+
+impl fmt::Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "profile: {} instructions executed", self.total)?;
+        writeln!(f, "{:>8} {:>10} {:>10} {:>7}  function", "calls", "inclusive", "exclusive", "%")?;
+        for function in &self.functions {
+            writeln!(
+                f,
+                "{:>8} {:>10} {:>10} {:>6.1}%  {}",
+                function.calls,
+                function.inclusive,
+                function.exclusive,
+                percent(function.exclusive, self.total),
+                function.name
+            )?;
+        }
+        writeln!(f, "hot instructions:")?;
+        writeln!(f, "{:>8} {:>7}  {:<5} {:<16}function", "count", "%", "pc", "instruction")?;
+        for instruction in self.instructions.iter().take(HOT_INSTRUCTIONS) {
+            write!(
+                f,
+                "{:>8} {:>6.1}%  {:04}  {:<16}{}",
+                instruction.count,
+                percent(instruction.count, self.total),
+                instruction.program_counter,
+                instruction.instruction.to_string(),
+                instruction.function
+            )?;
+            match instruction.line {
+                Some(line) => writeln!(f, " (line {})", line)?,
+                None => writeln!(f)?,
+            }
+        }
+        Ok(())
+    }
+}
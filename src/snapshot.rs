@@ -0,0 +1,267 @@
+//! VSM の実行状態のスナップショット
+//!
+//! 数値の表し方は src/bytecode.rs と同じ (リトルエンディアン, varint, svarint).
+//!
+//! ```text
+//! header
+//!   magic                  4 bytes  "VSMS"
+//!   version                u16      SNAPSHOT_VERSION
+//!   flags                  u16      予約 (0)
+//! registers
+//!   program_counter        varint
+//!   stack_pointer          varint   SP + 1 (0 はスタックが空)
+//!   global_top_address     varint   B0
+//!   frame_top_address      varint   B1
+//!   max_stack_pointer      varint
+//!   executed_instructions  varint
+//!   exit_code              u8       EXIT を実行していれば 1 で, 続けて svarint の終了コード
+//! I/O
+//!   input_position         varint   GETC/GETI で読み込んだ入力のバイト数
+//!   output_position        varint   PUTC/PUTI で書き出した出力のバイト数
+//! stack
+//!   size                   varint   スタックの大きさ
+//!   count                  varint   保存するセルの数 (それより上のセルは 0)
+//!   value                  svarint * count
+//! heap (version 2 以降. version 1 のスナップショットはヒープが空として読む)
+//!   next_address           varint   次に ALLOC で割り当てるアドレス
+//!   count                  varint   ブロックの数 (解放済みも含む)
+//!   (address varint, size varint, allocated_at varint, freed_at varint, value svarint * size) * count
+//!                                   freed_at は解放した PC + 1 (0 は解放されていない). 解放済みなら value はない
+//! code
+//!   length                 u32
+//!   bytecode               length bytes (src/bytecode.rs の形式)
+//! ```
+//!
+//! 末尾に続くデータは読み飛ばす.
+
+use core::fmt;
+use std::fs;
+
+use crate::bytecode::{write_svarint, write_varint, ByteReader, BytecodeError};
+use crate::code::Code;
+use crate::heap::{Heap, HeapBlock, DEFAULT_MAX_HEAP_SIZE};
+use crate::vsm::DEFAULT_MAX_STACK_SIZE;
+
+pub const MAGIC: &[u8; 4] = b"VSMS";
+pub const SNAPSHOT_VERSION: u16 = 2;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SnapshotError {
+    Io(String),
+    InvalidMagic,
+    UnsupportedVersion(u16),
+    Malformed(BytecodeError),
+    InvalidStack { size: usize, count: usize },
+    InvalidHeap,
+    InvalidRegisters,
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotError::Io(message) => write!(f, "{}", message),
+            SnapshotError::InvalidMagic => write!(f, "invalid magic number"),
+            SnapshotError::UnsupportedVersion(version) => write!(f, "unsupported snapshot version {}", version),
+            SnapshotError::Malformed(error) => write!(f, "malformed snapshot: {}", error),
+            SnapshotError::InvalidStack { size, count } => {
+                write!(f, "invalid stack ({} cells saved for a stack of {} cells)", count, size)
+            }
+            SnapshotError::InvalidHeap => write!(f, "invalid heap blocks"),
+            SnapshotError::InvalidRegisters => write!(f, "registers out of the stack or the code"),
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+impl From<BytecodeError> for SnapshotError {
+    fn from(error: BytecodeError) -> SnapshotError {
+        SnapshotError::Malformed(error)
+    }
+}
+
+pub fn is_snapshot(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+// Vsm::snapshot() で取り出し, Vsm::restore() で戻す
+#[derive(Clone)]
+pub struct Snapshot {
+    pub code: Code,
+    pub program_counter: usize,
+    pub stack_pointer: Option<usize>,
+    pub global_top_address: usize,
+    pub frame_top_address: usize,
+    pub max_stack_pointer: usize,
+    pub stack: Vec<i32>,
+    pub executed_instructions: u64,
+    pub exit_code: Option<i32>,
+    pub input_position: u64,
+    pub output_position: u64,
+    pub heap: Heap,
+}
+
+impl Snapshot {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&SNAPSHOT_VERSION.to_le_bytes());
+        bytes.extend_from_slice(&0u16.to_le_bytes());
+
+        write_varint(&mut bytes, self.program_counter as u64);
+        write_varint(&mut bytes, self.stack_pointer.map_or(0, |sp| sp as u64 + 1));
+        write_varint(&mut bytes, self.global_top_address as u64);
+        write_varint(&mut bytes, self.frame_top_address as u64);
+        write_varint(&mut bytes, self.max_stack_pointer as u64);
+        write_varint(&mut bytes, self.executed_instructions);
+        match self.exit_code {
+            Some(exit_code) => {
+                bytes.push(1);
+                write_svarint(&mut bytes, exit_code);
+            }
+            None => bytes.push(0),
+        }
+
+        write_varint(&mut bytes, self.input_position);
+        write_varint(&mut bytes, self.output_position);
+
+        // 使われていない上の方のセルは保存しない
+        let count = self.stack.iter().rposition(|value| *value != 0).map_or(0, |index| index + 1);
+        write_varint(&mut bytes, self.stack.len() as u64);
+        write_varint(&mut bytes, count as u64);
+        self.stack[..count].iter().for_each(|value| write_svarint(&mut bytes, *value));
+
+        write_varint(&mut bytes, self.heap.next_address() as u64);
+        write_varint(&mut bytes, self.heap.blocks().count() as u64);
+        for block in self.heap.blocks() {
+            write_varint(&mut bytes, block.address as u64);
+            write_varint(&mut bytes, block.size as u64);
+            write_varint(&mut bytes, block.allocated_at as u64);
+            write_varint(&mut bytes, block.freed_at.map_or(0, |pc| pc as u64 + 1));
+            block.values.iter().for_each(|value| write_svarint(&mut bytes, *value));
+        }
+
+        let code = self.code.to_bytes();
+        bytes.extend_from_slice(&(code.len() as u32).to_le_bytes());
+        bytes.extend(code);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Snapshot, SnapshotError> {
+        Snapshot::from_bytes_with_max_stack_size(bytes, DEFAULT_MAX_STACK_SIZE)
+    }
+
+    // スタックの大きさはファイルの値を信用せず, max_stack_size を超えれば読まない
+    pub fn from_bytes_with_max_stack_size(bytes: &[u8], max_stack_size: usize) -> Result<Snapshot, SnapshotError> {
+        let mut reader = ByteReader::new(bytes);
+        if reader.take(4).map_err(|_| SnapshotError::InvalidMagic)? != MAGIC {
+            return Err(SnapshotError::InvalidMagic);
+        }
+        let version = reader.u16()?;
+        if version == 0 || version > SNAPSHOT_VERSION {
+            return Err(SnapshotError::UnsupportedVersion(version));
+        }
+        let _flags = reader.u16()?;
+
+        let program_counter = reader.usize()?;
+        let stack_pointer = reader.usize()?.checked_sub(1);
+        let global_top_address = reader.usize()?;
+        let frame_top_address = reader.usize()?;
+        let max_stack_pointer = reader.usize()?;
+        let executed_instructions = reader.varint()?;
+        let exit_code = match reader.u8()? {
+            0 => None,
+            _ => Some(reader.svarint()?),
+        };
+
+        let input_position = reader.varint()?;
+        let output_position = reader.varint()?;
+
+        let size = reader.usize()?;
+        let count = reader.usize()?;
+        if count > size || size > max_stack_size {
+            return Err(SnapshotError::InvalidStack { size, count });
+        }
+        let mut stack = (0..count).map(|_| reader.svarint()).collect::<Result<Vec<_>, _>>()?;
+        stack.resize(size, 0);
+
+        let heap = match version {
+            1 => Heap::default(),
+            _ => Snapshot::read_heap(&mut reader)?,
+        };
+
+        let length = reader.u32()? as usize;
+        let code = Code::from_bytes(reader.take(length)?)?;
+
+        // B0 / B1 は SB で伸ばす前のスタックの外を指しうるので, 上限だけ確かめる
+        let in_stack = |address: usize| address < size;
+        if !stack_pointer.is_none_or(in_stack)
+            || !(in_stack(max_stack_pointer) || max_stack_pointer == 0)
+            || global_top_address > max_stack_size
+            || frame_top_address > max_stack_size
+            || program_counter > code.len()
+        {
+            return Err(SnapshotError::InvalidRegisters);
+        }
+
+        Ok(Snapshot {
+            code,
+            program_counter,
+            stack_pointer,
+            global_top_address,
+            frame_top_address,
+            max_stack_pointer,
+            stack,
+            executed_instructions,
+            exit_code,
+            input_position,
+            output_position,
+            heap,
+        })
+    }
+
+    // ブロックはアドレス順に, 隙間を空けて next_address より前に並んでいなければならない
+    fn read_heap(reader: &mut ByteReader) -> Result<Heap, SnapshotError> {
+        let next_address = reader.usize()?;
+        if next_address == 0 || next_address > i32::MAX as usize {
+            return Err(SnapshotError::InvalidHeap);
+        }
+        let count = reader.usize()?;
+        let mut blocks = Vec::new();
+        let mut free_from = 1;
+        for _ in 0..count {
+            let address = reader.usize()?;
+            let size = reader.usize()?;
+            let allocated_at = reader.usize()?;
+            let freed_at = reader.usize()?.checked_sub(1);
+            match address.checked_add(size) {
+                Some(end) if free_from <= address && end < next_address => free_from = end + 1,
+                _ => return Err(SnapshotError::InvalidHeap),
+            }
+            let values = match freed_at {
+                Some(_) => Vec::new(),
+                None => (0..size).map(|_| reader.svarint()).collect::<Result<Vec<_>, _>>()?,
+            };
+            blocks.push(HeapBlock {
+                address,
+                size,
+                allocated_at,
+                freed_at,
+                values,
+            });
+        }
+        Ok(Heap::from_blocks(next_address, blocks, DEFAULT_MAX_HEAP_SIZE))
+    }
+
+    pub fn write(&self, file_path: &str) -> std::io::Result<()> {
+        self.code
+            .check_call_targets()
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?;
+        fs::write(file_path, self.to_bytes())
+    }
+
+    pub fn read(file_path: &str) -> Result<Snapshot, SnapshotError> {
+        let bytes = fs::read(file_path).map_err(|err| SnapshotError::Io(format!("cannot read '{}': {}", file_path, err)))?;
+        Snapshot::from_bytes(&bytes)
+    }
+}
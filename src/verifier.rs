@@ -0,0 +1,273 @@
+use core::fmt;
+use std::collections::BTreeSet;
+
+use crate::code::{Code, Instruction, OperationCode};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DiagnosticKind {
+    StackUnderflow { required: i64, height: i64 },
+    InconsistentHeight { first: i64, second: i64 },
+    BranchOutOfRange { target: i64 },
+    FallOffEnd,
+    ReturnOutsideFunction,
+    Unreachable { to: usize },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub program_counter: usize,
+    pub kind: DiagnosticKind,
+    pub instruction: Instruction,
+    pub line: Option<usize>,
+}
+
+impl Diagnostic {
+    pub fn severity(&self) -> Severity {
+        match self.kind {
+            DiagnosticKind::Unreachable { .. } => Severity::Warning,
+            _ => Severity::Error,
+        }
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.severity() == Severity::Error
+    }
+
+    pub fn message(&self) -> String {
+        match &self.kind {
+            DiagnosticKind::StackUnderflow { required, height } => format!(
+                "stack underflow: needs {} value(s) but the stack has {}",
+                required, height
+            ),
+            DiagnosticKind::InconsistentHeight { first, second } => {
+                format!("inconsistent stack height at join point: {} and {}", first, second)
+            }
+            DiagnosticKind::BranchOutOfRange { target } => {
+                format!("branch target {} is outside the code", target)
+            }
+            DiagnosticKind::FallOffEnd => "execution falls off the end of the code".to_string(),
+            DiagnosticKind::ReturnOutsideFunction => "RET is reachable outside a function".to_string(),
+            DiagnosticKind::Unreachable { to } if *to == self.program_counter => "unreachable instruction".to_string(),
+            DiagnosticKind::Unreachable { to } => format!("unreachable instructions up to {:04}", to),
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: {:04} '{}': {}",
+            self.severity(),
+            self.program_counter,
+            self.instruction,
+            self.message()
+        )?;
+        if let Some(line) = self.line {
+            write!(f, " (line {})", line)?;
+        }
+        Ok(())
+    }
+}
+
+// 命令が必要とする値の数と実行後の高さの変化
+fn stack_effect(instruction: &Instruction) -> (i64, i64) {
+    match instruction.operation_code {
+        OperationCode::Isp => {
+            let operand = instruction.operand[0].unwrap_or(0) as i64;
+            (-operand.min(0), operand)
+        }
+        OperationCode::La | OperationCode::Lv | OperationCode::Lc => (0, 1),
+        OperationCode::Getc | OperationCode::Geti => (0, 1),
+        OperationCode::Li | OperationCode::Inv => (1, 0),
+        OperationCode::Alloc | OperationCode::Lh => (1, 0),
+        OperationCode::Free => (1, -1),
+        OperationCode::Sh => (2, -2),
+        OperationCode::Dup => (1, 1),
+        OperationCode::Si => (2, -2),
+        OperationCode::Sv | OperationCode::Sb | OperationCode::Bz => (1, -1),
+        OperationCode::Putc | OperationCode::Puti | OperationCode::Puts => (1, -1),
+        OperationCode::Add
+        | OperationCode::Sub
+        | OperationCode::Mul
+        | OperationCode::Div
+        | OperationCode::Mod
+        | OperationCode::Eq
+        | OperationCode::Ne
+        | OperationCode::Gt
+        | OperationCode::Lt
+        | OperationCode::Ge
+        | OperationCode::Le => (2, -1),
+        // 呼び出し元から見ると返り値が 1 つ積まれる
+        OperationCode::Call => (0, 1),
+        OperationCode::B | OperationCode::Ret | OperationCode::Exit => (0, 0),
+    }
+}
+
+// スタックの高さを命令ごとに抽象的に追跡して, 実行前に検出できる誤りを報告する.
+// 高さはプログラムの先頭では空のスタック, 関数 (CALL の分岐先) では B1 からの相対で数える
+pub struct Verifier<'a> {
+    code: &'a Code,
+    reachable: Vec<bool>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl<'a> Verifier<'a> {
+    pub fn new(code: &'a Code) -> Verifier<'a> {
+        Verifier {
+            code,
+            reachable: vec![false; code.len()],
+            diagnostics: Vec::new(),
+        }
+    }
+
+    fn report(&mut self, program_counter: usize, kind: DiagnosticKind) {
+        let diagnostic = Diagnostic {
+            program_counter,
+            kind,
+            instruction: self.code.get_instruction(program_counter),
+            line: self.code.line_number(program_counter),
+        };
+        // 複数の関数から同じ命令に到達した場合も 1 度だけ報告する
+        if !self.diagnostics.contains(&diagnostic) {
+            self.diagnostics.push(diagnostic);
+        }
+    }
+
+    fn target(&self, program_counter: usize) -> Option<i64> {
+        match self.code.get_instruction(program_counter) {
+            Instruction {
+                operation_code: OperationCode::B | OperationCode::Bz,
+                operand: [Some(offset), _],
+            } => Some(program_counter as i64 + 1 + offset as i64),
+            Instruction {
+                operation_code: OperationCode::Call,
+                operand: [Some(target), _],
+            } => Some(target as i64),
+            _ => None,
+        }
+    }
+
+    fn in_code(&self, address: i64) -> Option<usize> {
+        if 0 <= address && address < self.code.len() as i64 {
+            Some(address as usize)
+        } else {
+            None
+        }
+    }
+
+    pub fn verify(mut self) -> Vec<Diagnostic> {
+        if self.code.is_empty() {
+            return Vec::new();
+        }
+
+        for program_counter in 0..self.code.len() {
+            if let Some(target) = self.target(program_counter) {
+                if self.in_code(target).is_none() {
+                    self.report(program_counter, DiagnosticKind::BranchOutOfRange { target });
+                }
+            }
+        }
+
+        let mut functions = BTreeSet::new();
+        let mut pending = self.analyze(0, false);
+        while let Some(entry) = pending.pop() {
+            if functions.insert(entry) {
+                pending.extend(self.analyze(entry, true));
+            }
+        }
+
+        let mut program_counter = 0;
+        while program_counter < self.code.len() {
+            if self.reachable[program_counter] {
+                program_counter += 1;
+                continue;
+            }
+            let mut to = program_counter;
+            while to + 1 < self.code.len() && !self.reachable[to + 1] {
+                to += 1;
+            }
+            self.report(program_counter, DiagnosticKind::Unreachable { to });
+            program_counter = to + 1;
+        }
+
+        self.diagnostics.sort_by_key(|diagnostic| diagnostic.program_counter);
+        self.diagnostics
+    }
+
+    // entry から到達できる命令を調べ, 見つかった CALL の分岐先を返す
+    fn analyze(&mut self, entry: usize, in_function: bool) -> Vec<usize> {
+        let mut heights: Vec<Option<i64>> = vec![None; self.code.len()];
+        let mut calls = Vec::new();
+        let mut worklist = vec![(entry, 0i64)];
+
+        while let Some((program_counter, height)) = worklist.pop() {
+            match heights[program_counter] {
+                Some(first) if first != height => {
+                    self.report(
+                        program_counter,
+                        DiagnosticKind::InconsistentHeight {
+                            first: first.min(height),
+                            second: first.max(height),
+                        },
+                    );
+                    continue;
+                }
+                Some(_) => continue,
+                None => heights[program_counter] = Some(height),
+            }
+            self.reachable[program_counter] = true;
+
+            let instruction = self.code.get_instruction(program_counter);
+            let (required, delta) = stack_effect(&instruction);
+            if height < required {
+                self.report(program_counter, DiagnosticKind::StackUnderflow { required, height });
+                continue;
+            }
+            let next_height = height + delta;
+
+            let falls_through = match instruction.operation_code {
+                OperationCode::Exit | OperationCode::B => false,
+                OperationCode::Ret => {
+                    if !in_function {
+                        self.report(program_counter, DiagnosticKind::ReturnOutsideFunction);
+                    }
+                    false
+                }
+                _ => true,
+            };
+            // 範囲外への分岐は先に報告済みなのでここでは辿らない
+            match (instruction.operation_code, self.target(program_counter).and_then(|target| self.in_code(target))) {
+                (OperationCode::B | OperationCode::Bz, Some(target)) => worklist.push((target, next_height)),
+                (OperationCode::Call, Some(target)) => calls.push(target),
+                _ => {}
+            }
+            if falls_through {
+                match self.in_code(program_counter as i64 + 1) {
+                    Some(next) => worklist.push((next, next_height)),
+                    None => self.report(program_counter, DiagnosticKind::FallOffEnd),
+                }
+            }
+        }
+        calls
+    }
+}
+
+pub fn verify(code: &Code) -> Vec<Diagnostic> {
+    Verifier::new(code).verify()
+}
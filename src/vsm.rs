@@ -1,6 +1,127 @@
+use core::fmt;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
 use std::io;
-use std::io::BufRead;
-use crate::code::{Code, Instruction, OperationCode};
+use std::io::{BufRead, Write};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+use crate::code::{AssembleError, Code, Instruction, OperationCode, SourceLocation};
+use crate::coverage::Coverage;
+use crate::engine::DecodedCode;
+use crate::heap::{Heap, HeapChange};
+use crate::history::{History, StackWrite, UndoRecord};
+use crate::profiler::{Profile, Profiler};
+use crate::snapshot::Snapshot;
+
+// スタックは最初に DEFAULT_STACK_SIZE セル確保し, 必要になったら DEFAULT_MAX_STACK_SIZE セルまで伸ばす
+pub const DEFAULT_STACK_SIZE: usize = 1024;
+pub const DEFAULT_MAX_STACK_SIZE: usize = 1 << 20;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VsmError {
+    StackUnderflow,
+    StackOverflow,
+    OutOfBoundsRead { address: usize },
+    OutOfBoundsWrite { address: usize },
+    InvalidBaseRegister(i32),
+    DivisionByZero,
+    PcOutOfRange,
+    InputError(String),
+    OutputError(String),
+    InvalidCharacter(i32),
+    InstructionLimitExceeded(u64),
+    TimeLimitExceeded(Duration),
+    StackLimitExceeded(usize),
+    IntegerOverflow,
+    InvalidAddress(i64),
+    InvalidBranchTarget(i64),
+    InvalidAllocationSize(i32),
+    HeapExhausted(usize),
+    HeapAddressSpaceExhausted,
+    HeapOutOfBounds(i32),
+    UseAfterFree(i32),
+    InvalidFree(i32),
+    DoubleFree(i32),
+}
+
+impl fmt::Display for VsmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VsmError::StackUnderflow => write!(f, "stack underflow"),
+            VsmError::StackOverflow => write!(f, "stack overflow"),
+            VsmError::OutOfBoundsRead { address } => write!(f, "stack read out of bounds (address = {})", address),
+            VsmError::OutOfBoundsWrite { address } => write!(f, "stack write out of bounds (address = {})", address),
+            VsmError::InvalidBaseRegister(register) => write!(f, "invalid base register '{}'", register),
+            VsmError::DivisionByZero => write!(f, "division by zero"),
+            VsmError::PcOutOfRange => write!(f, "PC out of range"),
+            VsmError::InputError(message) => write!(f, "input error: {}", message),
+            VsmError::OutputError(message) => write!(f, "output error: {}", message),
+            VsmError::InvalidCharacter(value) => write!(f, "invalid character code {}", value),
+            VsmError::InstructionLimitExceeded(limit) => write!(f, "instruction limit exceeded ({} instructions)", limit),
+            VsmError::TimeLimitExceeded(limit) => write!(f, "time limit exceeded ({} ms)", limit.as_millis()),
+            VsmError::StackLimitExceeded(limit) => write!(f, "stack limit exceeded ({} cells)", limit),
+            VsmError::IntegerOverflow => write!(f, "integer overflow"),
+            VsmError::InvalidAddress(address) => write!(f, "invalid address {}", address),
+            VsmError::InvalidBranchTarget(target) => write!(f, "invalid branch target {}", target),
+            VsmError::InvalidAllocationSize(size) => write!(f, "invalid allocation size {}", size),
+            VsmError::HeapExhausted(limit) => write!(f, "heap exhausted ({} cells)", limit),
+            VsmError::HeapAddressSpaceExhausted => write!(f, "heap address space exhausted"),
+            VsmError::HeapOutOfBounds(address) => write!(f, "heap access out of bounds (address = {})", address),
+            VsmError::UseAfterFree(address) => write!(f, "heap use after free (address = {})", address),
+            VsmError::InvalidFree(address) => write!(f, "free of an unallocated address {}", address),
+            VsmError::DoubleFree(address) => write!(f, "double free (address = {})", address),
+        }
+    }
+}
+
+impl std::error::Error for VsmError {}
+
+// エラー発生時のレジスタと命令を保持する
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RuntimeError {
+    pub error: VsmError,
+    pub program_counter: usize,
+    pub instruction: Option<Instruction>,
+    pub stack_pointer: Option<usize>,
+    pub global_top_address: usize,
+    pub frame_top_address: usize,
+    pub source: Option<Box<SourceLocation>>,
+}
+
+impl RuntimeError {
+    // ソースの位置が分かる場合は rustc 風に該当行を示す
+    pub fn render(&self) -> String {
+        match &self.source {
+            Some(source) => source.span().render(&self.to_string(), None),
+            None => format!("error: {}\n", self),
+        }
+    }
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at PC={}", self.error, self.program_counter)?;
+        if let Some(instruction) = self.instruction {
+            write!(f, " '{}'", instruction)?;
+        }
+        let sp = match self.stack_pointer {
+            Some(sp) => sp.to_string(),
+            None => "-1".to_string(),
+        };
+        write!(f, " (SP={}, B0={}, B1={})", sp, self.global_top_address, self.frame_top_address)
+    }
+}
+
+impl std::error::Error for RuntimeError {}
+
+// step() の結果
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StepStatus {
+    Continue,
+    Halted(i32),
+    WaitingForInput,
+    Error(RuntimeError),
+}
 
 #[derive(PartialEq)]
 pub enum TraceType{
@@ -9,50 +130,568 @@ pub enum TraceType{
 }
 
 pub struct Vsm {
-    code: Code,
-    program_counter: usize,
-    global_top_address: usize,
-    frame_top_address: usize,
-    stack: Vec<i32>,
-    stack_pointer: Option<usize>,
-    max_stack_pointer: usize,
-    trace_type : TraceType
+    pub(crate) code: Code,
+    pub(crate) program_counter: usize,
+    pub(crate) global_top_address: usize,
+    pub(crate) frame_top_address: usize,
+    pub(crate) stack: Vec<i32>,
+    max_stack_size: usize,
+    // .data がスタックに収まらなかったときのエラー
+    load_error: Option<VsmError>,
+    pub(crate) stack_pointer: Option<usize>,
+    pub(crate) max_stack_pointer: usize,
+    trace_type : TraceType,
+    input: Box<dyn BufRead>,
+    output: Box<dyn Write>,
+    current_instruction: Option<(usize, Instruction)>,
+    watchpoints: BTreeMap<usize, Watchpoint>,
+    next_watchpoint_id: usize,
+    watch_hits: Vec<WatchHit>,
+    limits: ExecutionLimits,
+    pub(crate) executed_instructions: u64,
+    started_at: Option<Instant>,
+    profiler: Option<Profiler>,
+    coverage: Option<Coverage>,
+    exit_code: Option<i32>,
+    input_log: Vec<u8>,
+    output_position: u64,
+    history: Option<History>,
+    arithmetic_mode: ArithmeticMode,
+    heap: Heap,
+}
+
+// 無限ループ等で止まらないプログラムを打ち切るための制限
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ExecutionLimits {
+    pub max_instructions: Option<u64>,
+    pub max_duration: Option<Duration>,
+    pub max_stack_depth: Option<usize>,
+}
+
+// ADD/SUB/MUL/DIV/MOD/INV の結果が i32 に収まらない場合の扱い.
+// 0 による除算はどのモードでもエラーになる
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ArithmeticMode {
+    #[default]
+    Trap,
+    Wrapping,
+    Saturating,
+}
+
+impl ArithmeticMode {
+    pub fn add(self, a: i32, b: i32) -> Result<i32, VsmError> {
+        match self {
+            ArithmeticMode::Trap => a.checked_add(b).ok_or(VsmError::IntegerOverflow),
+            ArithmeticMode::Wrapping => Ok(a.wrapping_add(b)),
+            ArithmeticMode::Saturating => Ok(a.saturating_add(b)),
+        }
+    }
+
+    pub fn sub(self, a: i32, b: i32) -> Result<i32, VsmError> {
+        match self {
+            ArithmeticMode::Trap => a.checked_sub(b).ok_or(VsmError::IntegerOverflow),
+            ArithmeticMode::Wrapping => Ok(a.wrapping_sub(b)),
+            ArithmeticMode::Saturating => Ok(a.saturating_sub(b)),
+        }
+    }
+
+    pub fn mul(self, a: i32, b: i32) -> Result<i32, VsmError> {
+        match self {
+            ArithmeticMode::Trap => a.checked_mul(b).ok_or(VsmError::IntegerOverflow),
+            ArithmeticMode::Wrapping => Ok(a.wrapping_mul(b)),
+            ArithmeticMode::Saturating => Ok(a.saturating_mul(b)),
+        }
+    }
+
+    // i32::MIN / -1 だけが溢れる
+    pub fn div(self, a: i32, b: i32) -> Result<i32, VsmError> {
+        if b == 0 {
+            return Err(VsmError::DivisionByZero);
+        }
+        match self {
+            ArithmeticMode::Trap => a.checked_div(b).ok_or(VsmError::IntegerOverflow),
+            ArithmeticMode::Wrapping => Ok(a.wrapping_div(b)),
+            ArithmeticMode::Saturating => Ok(a.saturating_div(b)),
+        }
+    }
+
+    // i32::MIN % -1 は 0 で表せるのでどのモードでも 0 にする
+    pub fn rem(self, a: i32, b: i32) -> Result<i32, VsmError> {
+        if b == 0 {
+            return Err(VsmError::DivisionByZero);
+        }
+        Ok(a.wrapping_rem(b))
+    }
+
+    pub fn neg(self, a: i32) -> Result<i32, VsmError> {
+        match self {
+            ArithmeticMode::Trap => a.checked_neg().ok_or(VsmError::IntegerOverflow),
+            ArithmeticMode::Wrapping => Ok(a.wrapping_neg()),
+            ArithmeticMode::Saturating => Ok(a.saturating_neg()),
+        }
+    }
+}
+
+impl std::str::FromStr for ArithmeticMode {
+    type Err = String;
+
+    fn from_str(mode: &str) -> Result<ArithmeticMode, String> {
+        match mode {
+            "trap" => Ok(ArithmeticMode::Trap),
+            "wrapping" => Ok(ArithmeticMode::Wrapping),
+            "saturating" => Ok(ArithmeticMode::Saturating),
+            _ => Err(format!("unknown arithmetic mode '{}' (trap, wrapping or saturating)", mode)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    Change,
+}
+
+impl fmt::Display for WatchKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WatchKind::Read => write!(f, "read"),
+            WatchKind::Write => write!(f, "write"),
+            WatchKind::Change => write!(f, "change"),
+        }
+    }
+}
+
+// S[from]..=S[to] へのアクセスを監視する
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub from: usize,
+    pub to: usize,
+    pub kind: WatchKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WatchHit {
+    pub id: usize,
+    pub kind: WatchKind,
+    pub address: usize,
+    pub program_counter: usize,
+    pub instruction: Instruction,
+    pub old_value: i32,
+    pub new_value: i32,
+}
+
+impl fmt::Display for WatchHit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            WatchKind::Read => write!(
+                f,
+                "watchpoint {}: S[{}] read at {:04} '{}': value = {}",
+                self.id, self.address, self.program_counter, self.instruction, self.new_value
+            ),
+            WatchKind::Write | WatchKind::Change => write!(
+                f,
+                "watchpoint {}: S[{}] written at {:04} '{}': {} -> {}",
+                self.id, self.address, self.program_counter, self.instruction, self.old_value, self.new_value
+            ),
+        }
+    }
+}
+
+// テスト等で出力を文字列として取り出すための Write 実装
+#[derive(Clone, Default)]
+pub struct SharedOutput {
+    buffer: Rc<RefCell<Vec<u8>>>,
+}
+
+impl SharedOutput {
+    pub fn new() -> SharedOutput {
+        SharedOutput::default()
+    }
+
+    pub fn contents(&self) -> String {
+        String::from_utf8_lossy(&self.buffer.borrow()).to_string()
+    }
+}
+
+impl Write for SharedOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
 }
 
 impl Vsm {
     pub fn new(trace_type : TraceType) -> Vsm {
+        Vsm::with_io(
+            trace_type,
+            Box::new(io::BufReader::new(io::stdin())),
+            Box::new(io::stdout()),
+        )
+    }
+
+    pub fn with_io(trace_type : TraceType, input: Box<dyn BufRead>, output: Box<dyn Write>) -> Vsm {
         Vsm {
             code: Code::new(),
             program_counter: 0,
             global_top_address: 0,
             frame_top_address: 0,
-            stack: vec![i32::default(); 1024],
+            stack: vec![i32::default(); DEFAULT_STACK_SIZE],
+            max_stack_size: DEFAULT_MAX_STACK_SIZE,
+            load_error: None,
             stack_pointer: None,
             max_stack_pointer: 0,
-            trace_type : trace_type
+            trace_type,
+            input,
+            output,
+            current_instruction: None,
+            watchpoints: BTreeMap::new(),
+            next_watchpoint_id: 0,
+            watch_hits: Vec::new(),
+            limits: ExecutionLimits::default(),
+            executed_instructions: 0,
+            started_at: None,
+            profiler: None,
+            coverage: None,
+            exit_code: None,
+            input_log: Vec::new(),
+            output_position: 0,
+            history: None,
+            arithmetic_mode: ArithmeticMode::default(),
+            heap: Heap::default(),
         }
     }
 
+    // 伸びない固定の大きさのスタックにする
     pub fn allocation_stack(&mut self, size: usize){
-        self.stack = vec![i32::default(); size];
+        self.set_stack_size(size, size);
+    }
+
+    // 最初に size セル確保し, 積んだりアドレスで書き込んだりするときに max_size セルまで伸ばす.
+    // max_size を超えるとスタックオーバーフローになる
+    pub fn set_stack_size(&mut self, size: usize, max_size: usize) {
+        self.stack = vec![i32::default(); size.min(max_size)];
+        self.max_stack_size = max_size;
+        if let Some(history) = &mut self.history {
+            history.clear();
+        }
+    }
+
+    pub fn max_stack_size(&self) -> usize {
+        self.max_stack_size
+    }
+
+    // address のセルが確保されるまで倍々に伸ばす. 上限を超える場合は false を返す
+    fn grow_stack(&mut self, address: usize) -> bool {
+        if address < self.stack.len() {
+            return true;
+        }
+        if address >= self.max_stack_size {
+            return false;
+        }
+        let size = (address + 1).max(self.stack.len() * 2).min(self.max_stack_size);
+        self.stack.resize(size, i32::default());
+        true
+    }
+
+    // 経過時間は次に命令を実行したときから計測する
+    pub fn set_limits(&mut self, limits: ExecutionLimits) {
+        self.limits = limits;
+        self.started_at = None;
+    }
+
+    pub fn limits(&self) -> ExecutionLimits {
+        self.limits
+    }
+
+    pub fn set_arithmetic_mode(&mut self, arithmetic_mode: ArithmeticMode) {
+        self.arithmetic_mode = arithmetic_mode;
+    }
+
+    pub fn arithmetic_mode(&self) -> ArithmeticMode {
+        self.arithmetic_mode
+    }
+
+    // ALLOC で確保できるセルの合計の上限
+    pub fn set_max_heap_size(&mut self, max_size: usize) {
+        self.heap.set_max_size(max_size);
+    }
+
+    pub fn heap(&self) -> &Heap {
+        &self.heap
+    }
+
+    pub fn executed_instructions(&self) -> u64 {
+        self.executed_instructions
+    }
+
+    // 以降に実行した命令を数える
+    pub fn enable_profiling(&mut self) {
+        self.profiler = Some(Profiler::new());
+    }
+
+    pub fn profiler(&self) -> Option<&Profiler> {
+        self.profiler.as_ref()
+    }
+
+    pub fn profile(&self) -> Option<Profile> {
+        self.profiler.as_ref().map(|profiler| profiler.report(&self.code))
+    }
+
+    pub fn enable_coverage(&mut self) {
+        self.coverage = Some(Coverage::new());
+    }
+
+    pub fn coverage(&self) -> Option<&Coverage> {
+        self.coverage.as_ref()
+    }
+
+    // 以降に実行した命令を capacity 命令分まで取り消せるようにする. 0 なら記録しない
+    pub fn enable_history(&mut self, capacity: usize) {
+        match (&mut self.history, capacity) {
+            (_, 0) => self.history = None,
+            (Some(history), _) => history.set_capacity(capacity),
+            (None, _) => self.history = Some(History::new(capacity)),
+        }
     }
 
-    pub fn read_code(&mut self, file_path: &str)-> io::Result<()>{
+    pub fn history(&self) -> Option<&History> {
+        self.history.as_ref()
+    }
+
+    // 直前の命令を取り消す. 出力は取り消せない. 記録がなければ false を返す
+    pub fn step_back(&mut self) -> bool {
+        let record = match self.history.as_mut().and_then(History::pop) {
+            Some(record) => record,
+            None => return false,
+        };
+        for write in record.writes.iter().rev() {
+            self.stack[write.address] = write.old_value;
+        }
+        for change in record.heap_changes.into_iter().rev() {
+            self.heap.undo(change);
+        }
+        self.program_counter = record.program_counter;
+        self.stack_pointer = record.stack_pointer;
+        self.global_top_address = record.global_top_address;
+        self.frame_top_address = record.frame_top_address;
+        self.max_stack_pointer = record.max_stack_pointer;
+        self.executed_instructions -= 1;
+        self.exit_code = None;
+        self.current_instruction = None;
+        self.watch_hits.clear();
+        if record.input_position < self.input_log.len() {
+            self.seek_input(record.input_position as u64);
+        }
+        true
+    }
+
+    fn check_limits_before(&mut self) -> Result<(), VsmError> {
+        if let Some(max_instructions) = self.limits.max_instructions {
+            if self.executed_instructions >= max_instructions {
+                return Err(VsmError::InstructionLimitExceeded(max_instructions));
+            }
+        }
+        if let Some(max_duration) = self.limits.max_duration {
+            let started_at = *self.started_at.get_or_insert_with(Instant::now);
+            if started_at.elapsed() > max_duration {
+                return Err(VsmError::TimeLimitExceeded(max_duration));
+            }
+        }
+        Ok(())
+    }
+
+    fn check_limits_after(&self) -> Result<(), VsmError> {
+        if let (Some(max_stack_depth), Some(sp)) = (self.limits.max_stack_depth, self.stack_pointer) {
+            if sp >= max_stack_depth {
+                return Err(VsmError::StackLimitExceeded(max_stack_depth));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn code(&self) -> &Code {
+        &self.code
+    }
+
+    pub fn read_code(&mut self, file_path: &str)-> Result<(), Vec<AssembleError>>{
         self.code.read(file_path)?;
+        self.load_data();
         Ok(())
     }
 
-    fn display_config(&self, instruction : Instruction){
+    // コンパイラなどで作ったコードを読み込む
+    pub fn load_code(&mut self, code: Code) {
+        self.code = code;
+        self.exit_code = None;
+        if let Some(history) = &mut self.history {
+            history.clear();
+        }
+        self.load_data();
+    }
 
-        let dsp = match self.stack_pointer {
-            Some(sp) => format!("SP = {}", sp),
-            _ => format!("SP = -1"),
-        };
-        println!("{:02}:{} {}", self.program_counter, instruction.to_string(), dsp);
+    // .data の初期値を B0 からのセルに書き込み, SP をその上に置く. 大域変数を ISP で確保したのと同じになる.
+    // スタックの上限に収まらなければ何も書かず, 実行しようとしたときにスタックオーバーフローとして報告する
+    fn load_data(&mut self) {
+        self.load_error = None;
+        let size = self.code.data().len();
+        if size == 0 {
+            return;
+        }
+        let top = self.global_top_address + size - 1;
+        if !self.grow_stack(top) {
+            self.load_error = Some(VsmError::StackOverflow);
+            return;
+        }
+        self.stack[self.global_top_address..=top].copy_from_slice(self.code.data());
+        if self.stack_pointer.is_none_or(|sp| sp < top) {
+            self.stack_pointer = Some(top);
+            self.max_stack_pointer = self.max_stack_pointer.max(top);
+        }
+    }
 
-        let stack_slice = &self.stack[0..=self.max_stack_pointer];
-        stack_slice.iter().rev().enumerate().for_each(|(rev_index, value)|{
-            let index = stack_slice.len() - rev_index-1;
+    pub fn program_counter(&self) -> usize {
+        self.program_counter
+    }
+
+    // スタックが空なら None (SP = -1)
+    pub fn stack_pointer(&self) -> Option<usize> {
+        self.stack_pointer
+    }
+
+    pub fn global_top_address(&self) -> usize {
+        self.global_top_address
+    }
+
+    pub fn frame_top_address(&self) -> usize {
+        self.frame_top_address
+    }
+
+    // 確保済みのスタック (SP より上も含む). これより上のセルは 0 として扱う
+    pub fn stack(&self) -> &[i32] {
+        &self.stack
+    }
+
+    // EXIT を実行していれば終了コードを返す
+    pub fn exit_code(&self) -> Option<i32> {
+        self.exit_code
+    }
+
+    // 実行状態を保存する. トレース, 制限, ウォッチポイント等の設定は含まない
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            code: self.code.clone(),
+            program_counter: self.program_counter,
+            stack_pointer: self.stack_pointer,
+            global_top_address: self.global_top_address,
+            frame_top_address: self.frame_top_address,
+            max_stack_pointer: self.max_stack_pointer,
+            stack: self.stack.clone(),
+            executed_instructions: self.executed_instructions,
+            exit_code: self.exit_code,
+            input_position: self.input_log.len() as u64,
+            output_position: self.output_position,
+            heap: self.heap.clone(),
+        }
+    }
+
+    // 出力は取り消せないので位置だけを戻す
+    pub fn restore(&mut self, snapshot: &Snapshot) {
+        self.code = snapshot.code.clone();
+        self.program_counter = snapshot.program_counter;
+        self.stack_pointer = snapshot.stack_pointer;
+        self.global_top_address = snapshot.global_top_address;
+        self.frame_top_address = snapshot.frame_top_address;
+        self.max_stack_pointer = snapshot.max_stack_pointer;
+        self.stack = snapshot.stack.clone();
+        self.max_stack_size = self.max_stack_size.max(self.stack.len());
+        self.load_error = None;
+        self.executed_instructions = snapshot.executed_instructions;
+        self.exit_code = snapshot.exit_code;
+        self.output_position = snapshot.output_position;
+        let max_heap_size = self.heap.max_size();
+        self.heap = snapshot.heap.clone();
+        self.heap.set_max_size(max_heap_size);
+        if let Some(history) = &mut self.history {
+            history.clear();
+        }
+        self.started_at = None;
+        self.seek_input(snapshot.input_position);
+    }
+
+    // 読み込み済みの範囲なら読み直し, 先の位置なら入力を読み飛ばす
+    fn seek_input(&mut self, position: u64) {
+        let consumed = self.input_log.len() as u64;
+        if position <= consumed {
+            let replay = self.input_log.split_off(position as usize);
+            let previous = std::mem::replace(&mut self.input, Box::new(io::empty()));
+            self.input = Box::new(io::Read::chain(io::Cursor::new(replay), previous));
+        } else {
+            let mut skipped = Vec::new();
+            let _ = io::Read::read_to_end(&mut io::Read::take(&mut self.input, position - consumed), &mut skipped);
+            self.input_log.extend(skipped);
+        }
+    }
+
+    // 入力の末尾に追加する. WaitingForInput で止まった後に与えれば続きから実行できる
+    pub fn provide_input(&mut self, input: &str) {
+        let previous = std::mem::replace(&mut self.input, Box::new(io::empty()));
+        self.input = Box::new(io::Read::chain(previous, io::Cursor::new(input.as_bytes().to_vec())));
+    }
+
+    // 命令を一つ実行する. 入力が空の GETC/GETI は実行せずに WaitingForInput を返す
+    pub fn step(&mut self) -> StepStatus {
+        if let Some(exit_code) = self.exit_code {
+            return StepStatus::Halted(exit_code);
+        }
+        if self.waiting_for_input() {
+            return StepStatus::WaitingForInput;
+        }
+        match self.step_instruction() {
+            Ok(None) => StepStatus::Continue,
+            Ok(Some(exit_code)) => {
+                match self.finish(Ok(exit_code)) {
+                    Ok(exit_code) => StepStatus::Halted(exit_code),
+                    Err(err) => StepStatus::Error(err),
+                }
+            }
+            Err(err) => StepStatus::Error(err),
+        }
+    }
+
+    // PC が program_counter に達するまで実行する. 少なくとも一命令は実行し, 達した場合は Continue を返す
+    pub fn run_until(&mut self, program_counter: usize) -> StepStatus {
+        loop {
+            let status = self.step();
+            if status != StepStatus::Continue || self.program_counter == program_counter {
+                return status;
+            }
+        }
+    }
+
+    fn waiting_for_input(&mut self) -> bool {
+        if self.code.len() <= self.program_counter {
+            return false;
+        }
+        match self.code.get_instruction(self.program_counter).operation_code {
+            OperationCode::Getc | OperationCode::Geti => {
+                matches!(self.input.fill_buf(), Ok(buffer) if buffer.is_empty())
+            }
+            _ => false,
+        }
+    }
+
+    pub(crate) fn format_stack(&self, from: usize, to: usize) -> String {
+        let mut lines = String::new();
+        if self.max_stack_size == 0 || from > to {
+            return lines;
+        }
+        let to = to.min(self.max_stack_size - 1);
+        (from..=to).rev().for_each(|index| {
             let b0 = match self.global_top_address == index {
                 true => " <-B0",
                 false => "",
@@ -67,256 +706,444 @@ impl Vsm {
                 _ => "      ",
             };
 
-            println!("{} S[{: >3}] {: >4}{}{}", sp, index, *value, b0, b1);
+            let value = self.stack.get(index).copied().unwrap_or_default();
+            lines += &format!("{} S[{: >3}] {: >4}{}{}\n", sp, index, value, b0, b1);
         });
+        lines
+    }
 
+    fn display_config(&self, program_counter: usize, instruction : Instruction){
+
+        let dsp = match self.stack_pointer {
+            Some(sp) => format!("SP = {}", sp),
+            _ => "SP = -1".to_string(),
+        };
+        match self.code.source_location(program_counter) {
+            Some(location) => println!("{:02}:{} {}  // {}", program_counter, instruction, dsp, location),
+            None => println!("{:02}:{} {}", program_counter, instruction, dsp),
+        }
+        print!("{}", self.format_stack(0, self.max_stack_pointer));
         println!("\n");
     }
-    pub fn exec_code(&mut self) -> Result<(), String>{
+    fn runtime_error(&self, error: VsmError, program_counter: usize, instruction: Option<Instruction>) -> RuntimeError {
+        RuntimeError {
+            error,
+            program_counter,
+            instruction,
+            stack_pointer: self.stack_pointer,
+            global_top_address: self.global_top_address,
+            frame_top_address: self.frame_top_address,
+            source: self.code.source_location(program_counter).cloned().map(Box::new),
+        }
+    }
+
+    // EXIT で指定された終了コードを返す. 終了済みなら何も実行しない
+    pub fn exec_code(&mut self) -> Result<i32, RuntimeError>{
+        if let Some(exit_code) = self.exit_code {
+            return Ok(exit_code);
+        }
+        let result = self.exec_loop();
+        self.finish(result)
+    }
+
+    // 事前に変換したコードで実行する. トレース, ウォッチポイント, プロファイル, カバレッジ, 実行履歴はインタプリタでしか扱わない
+    pub fn exec_code_fast(&mut self) -> Result<i32, RuntimeError> {
+        if let Some(exit_code) = self.exit_code {
+            return Ok(exit_code);
+        }
+        let result = if self.load_error.is_some() || self.trace_type == TraceType::TraceStack || !self.watchpoints.is_empty() || self.profiler.is_some() || self.coverage.is_some() || self.history.is_some() {
+            self.exec_loop()
+        } else {
+            DecodedCode::new(&self.code).exec(self)
+        };
+        self.finish(result)
+    }
+
+    fn finish(&mut self, result: Result<i32, RuntimeError>) -> Result<i32, RuntimeError> {
+        let flush_result = self.output.flush();
+        let return_code = result?;
+        self.exit_code = Some(return_code);
+        flush_result.map_err(|err| {
+            self.runtime_error(VsmError::OutputError(err.to_string()), self.program_counter, None)
+        })?;
+        Ok(return_code)
+    }
+
+    fn exec_loop(&mut self) -> Result<i32, RuntimeError>{
 
         let mut return_code : Option<i32> = None;
 
         while return_code.is_none() {
-            
-            if self.code.len() <= self.program_counter {
-                return Err(format!("PC out of range (PC={})", self.program_counter))
-            }
-    
-            let instruction = self.code.get_instruction(self.program_counter);
-            self.program_counter += 1;
-    
-            let instruction_ecec_reuslt = self.exec_instruction(instruction);
-            match instruction_ecec_reuslt {
-                Ok(rc) => {
-                    return_code =rc; 
-                }
-                Err(err) => {
-                    return Err(err);
-                }
+            return_code = self.step_instruction()?;
+        }
+        Ok(return_code.unwrap_or_default())
+    }
+
+    pub(crate) fn flush_output(&mut self) -> io::Result<()> {
+        self.output.flush()
+    }
+
+    // 命令を一つ実行する. EXIT を実行した場合は終了コードを返す
+    pub(crate) fn step_instruction(&mut self) -> Result<Option<i32>, RuntimeError> {
+        if let Some(err) = self.load_error.clone() {
+            return Err(self.runtime_error(err, self.program_counter, None));
+        }
+        if self.code.len() <= self.program_counter {
+            return Err(self.runtime_error(VsmError::PcOutOfRange, self.program_counter, None));
+        }
+
+        let program_counter = self.program_counter;
+        let instruction = self.code.get_instruction(program_counter);
+        if let Err(err) = self.check_limits_before() {
+            return Err(self.runtime_error(err, program_counter, Some(instruction)));
+        }
+        if let Some(history) = &mut self.history {
+            history.push(UndoRecord {
+                program_counter,
+                instruction,
+                stack_pointer: self.stack_pointer,
+                global_top_address: self.global_top_address,
+                frame_top_address: self.frame_top_address,
+                max_stack_pointer: self.max_stack_pointer,
+                input_position: self.input_log.len(),
+                writes: Vec::new(),
+                heap_changes: Vec::new(),
+            });
+        }
+        self.program_counter += 1;
+        self.executed_instructions += 1;
+        self.current_instruction = Some((program_counter, instruction));
+        self.watch_hits.clear();
+        if let Some(profiler) = &mut self.profiler {
+            profiler.record(program_counter);
+        }
+        if let Some(coverage) = &mut self.coverage {
+            coverage.record(program_counter);
+        }
+        // BZ が分岐するかは実行前のスタックの先頭で決まる
+        let branch_taken = match (instruction.operation_code, self.stack_pointer) {
+            (OperationCode::Bz, Some(sp)) if self.coverage.is_some() => self.stack.get(sp).map(|value| *value == 0),
+            _ => None,
+        };
+
+        // 命令が途中まで変えたレジスタではなく, 実行前の値を報告する
+        let registers = (self.stack_pointer, self.global_top_address, self.frame_top_address);
+        let return_code = match self.exec_instruction(instruction).and_then(|rc| {
+            self.check_limits_after()?;
+            Ok(rc)
+        }) {
+            Ok(rc) => rc,
+            Err(err) => {
+                let mut err = self.runtime_error(err, program_counter, Some(instruction));
+                (err.stack_pointer, err.global_top_address, err.frame_top_address) = registers;
+                return Err(err);
             }
+        };
 
-            if self.stack_pointer.is_some() {
-                if self.stack_pointer.unwrap() > self.max_stack_pointer {
-                    self.max_stack_pointer = self.stack_pointer.unwrap();
-                }
+        if let (Some(coverage), Some(taken)) = (&mut self.coverage, branch_taken) {
+            coverage.record_branch(program_counter, taken);
+        }
+        if let Some(profiler) = &mut self.profiler {
+            match instruction.operation_code {
+                OperationCode::Call => profiler.enter(self.program_counter),
+                OperationCode::Ret => profiler.leave(),
+                _ => {}
             }
+        }
 
-            if self.trace_type == TraceType::TraceStack {
-                self.display_config(instruction);
-                let stdin = io::stdin();
-                let mut buffer = String::new();
-                stdin.lock().read_line(&mut buffer).expect("Failed to read line");                
+        if let Some(sp) = self.stack_pointer {
+            if sp > self.max_stack_pointer {
+                self.max_stack_pointer = sp;
             }
         }
-        Ok(())
+
+        if self.trace_type == TraceType::TraceStack {
+            self.display_config(program_counter, instruction);
+        }
+        Ok(return_code)
     }
 
-    fn stack_read(&self, address: Option<usize>) -> Result<i32, String> {
+    fn stack_read(&mut self, address: Option<usize>) -> Result<i32, VsmError> {
 
         match address  {
             Some(a) => {
-                if a < self.stack.len(){
-                    Ok(self.stack[a]) 
+                if a < self.max_stack_size {
+                    let value = self.stack.get(a).copied().unwrap_or_default();
+                    self.check_watchpoints(a, WatchKind::Read, value, value);
+                    Ok(value)
                 }else{  
-                    Err(format!("stack read error address = {}", a))
+                    Err(VsmError::OutOfBoundsRead { address: a })
                 }
             },
             None => {
-                Err(format!("stack read address is None"))
+                Err(VsmError::StackUnderflow)
             }
         }
     }
 
-    fn stack_write(&mut self, address : Option<usize>, value : i32)-> Result<(), String>
+    fn stack_write(&mut self, address : Option<usize>, value : i32)-> Result<(), VsmError>
     {
         match address  {
             Some(a) => {
-                if a< self.stack.len(){
+                // SP より上のセルにも書けるので, SP だけでなく書き込む位置も制限する
+                if let Some(max_stack_depth) = self.limits.max_stack_depth {
+                    if a >= max_stack_depth {
+                        return Err(VsmError::StackLimitExceeded(max_stack_depth));
+                    }
+                }
+                if self.grow_stack(a) {
+                    let old_value = self.stack[a];
                     self.stack[a] = value;
+                    if let Some(history) = &mut self.history {
+                        history.record_write(StackWrite { address: a, old_value, new_value: value });
+                    }
+                    self.check_watchpoints(a, WatchKind::Write, old_value, value);
                     Ok(())
                 }else{  
-                    Err(format!("stack write error address = {}", a))
+                    Err(VsmError::OutOfBoundsWrite { address: a })
                 }
             },
             None => {
-                Err(format!("stack write error address is None"))
+                Err(VsmError::StackUnderflow)
             }
         }
 
     }
 
 
-    fn stack_pointer_increment(&mut self){
-        self.stack_pointer = match self.stack_pointer {
-            Some(sp) => Some(sp + 1),
-            None => Some(0),
+    fn record_heap_change(&mut self, change: HeapChange) {
+        if let Some(history) = &mut self.history {
+            history.record_heap_change(change);
+        }
+    }
+
+    pub fn add_watchpoint(&mut self, from: usize, to: usize, kind: WatchKind) -> usize {
+        self.next_watchpoint_id += 1;
+        let id = self.next_watchpoint_id;
+        self.watchpoints.insert(id, Watchpoint { from: from.min(to), to: from.max(to), kind });
+        id
+    }
+
+    pub fn remove_watchpoint(&mut self, id: usize) -> bool {
+        self.watchpoints.remove(&id).is_some()
+    }
+
+    pub fn watchpoints(&self) -> &BTreeMap<usize, Watchpoint> {
+        &self.watchpoints
+    }
+
+    // 直前に実行した命令で発生したウォッチポイントのヒット
+    pub fn watch_hits(&self) -> &[WatchHit] {
+        &self.watch_hits
+    }
+
+    // Change は値が変わった書き込みのときだけヒットする
+    fn check_watchpoints(&mut self, address: usize, access: WatchKind, old_value: i32, new_value: i32) {
+        if self.watchpoints.is_empty() {
+            return;
+        }
+        let (program_counter, instruction) = match self.current_instruction {
+            Some(current_instruction) => current_instruction,
+            None => return,
+        };
+        for (id, watchpoint) in self.watchpoints.iter() {
+            if address < watchpoint.from || watchpoint.to < address {
+                continue;
+            }
+            let hit = match (watchpoint.kind, access) {
+                (WatchKind::Read, WatchKind::Read) | (WatchKind::Write, WatchKind::Write) => true,
+                (WatchKind::Change, WatchKind::Write) => old_value != new_value,
+                _ => false,
+            };
+            if hit {
+                self.watch_hits.push(WatchHit {
+                    id: *id,
+                    kind: watchpoint.kind,
+                    address,
+                    program_counter,
+                    instruction,
+                    old_value,
+                    new_value,
+                });
+            }
+        }
+    }
+
+    fn stack_pointer_increment(&mut self) -> Result<(), VsmError> {
+        let sp = match self.stack_pointer {
+            Some(sp) => sp + 1,
+            None => 0,
         };
+        if !self.grow_stack(sp) {
+            return Err(VsmError::StackOverflow);
+        }
+        self.stack_pointer = Some(sp);
+        Ok(())
     }
 
-    fn stack_pointer_decrement(&mut self) -> Result<(), String>  {
+    fn stack_pointer_decrement(&mut self) -> Result<(), VsmError>  {
         self.stack_pointer = match  self.stack_pointer {
             Some(0) => None,
             Some(sp) => Some(sp-1),
-            None => return Err("stack_pointer_decrement err stack_pointer is None".to_string())
+            None => return Err(VsmError::StackUnderflow)
         };
         Ok(())
     }
 
-    fn base_register_read(&self, value : i32) -> Result<usize, String>
+    fn base_register_read(&self, value : i32) -> Result<usize, VsmError>
     {
         match value {
             0 => Ok(self.global_top_address),
             1 => Ok(self.frame_top_address) ,
-            _ => Err(format!("error base register '{}'", value)),
+            _ => Err(VsmError::InvalidBaseRegister(value)),
         }        
     }
 
-    fn perform_operation<F>(&mut self, operation_fn: F) -> Result<(), String>
+    // 命令が扱うアドレスは全てここで検査する. 負の値を usize に変換すると巨大な値になるので先に弾き,
+    // スタックの範囲外かどうかは stack_read / stack_write で調べる
+    fn address(value: i64) -> Result<usize, VsmError> {
+        match usize::try_from(value) {
+            Ok(address) if value <= i32::MAX as i64 => Ok(address),
+            _ => Err(VsmError::InvalidAddress(value)),
+        }
+    }
+
+    // Bb + a
+    fn effective_address(&self, base_register: i32, offset: i32) -> Result<usize, VsmError> {
+        let base = self.base_register_read(base_register)?;
+        Vsm::address(base as i64 + offset as i64)
+    }
+
+    // 分岐先がコードの外でも実行するまではエラーにしないが, 負の値は PC にできない
+    fn branch_target(value: i64) -> Result<usize, VsmError> {
+        usize::try_from(value).map_err(|_| VsmError::InvalidBranchTarget(value))
+    }
+
+    fn perform_operation<F>(&mut self, operation_fn: F) -> Result<(), VsmError>
     where
-        F: Fn(i32, i32) -> i32,
+        F: Fn(i32, i32) -> Result<i32, VsmError>,
     {
         let top_value = self.stack_read(self.stack_pointer)?;
         self.stack_pointer_decrement()?;
         let bottom_value = self.stack_read(self.stack_pointer)?;
-        let result = operation_fn(bottom_value, top_value);
+        let result = operation_fn(bottom_value, top_value)?;
         self.stack_write(self.stack_pointer, result)?;
         Ok(())
     }    
-    fn add_fn(a: i32, b: i32) -> i32 {
-        a + b
-    }
-    
-    fn sub_fn(a: i32, b: i32) -> i32 {
-        a - b
+    pub(crate) fn eq_fn(a: i32, b: i32) -> Result<i32, VsmError> {
+        Ok((a == b) as i32)
     }
     
-    fn mul_fn(a: i32, b: i32) -> i32 {
-        a * b
+    pub(crate) fn ne_fn(a: i32, b: i32) -> Result<i32, VsmError> {
+        Ok((a != b) as i32)
     }
     
-    fn div_fn(a: i32, b: i32) -> i32 {
-        a / b
+    pub(crate) fn gt_fn(a: i32, b: i32) -> Result<i32, VsmError> {
+        Ok((a > b) as i32)
     }
     
-    fn mod_fn(a: i32, b: i32) -> i32 {
-        a % b
+    pub(crate) fn lt_fn(a: i32, b: i32) -> Result<i32, VsmError> {
+        Ok((a < b) as i32)
     }
     
-    fn eq_fn(a: i32, b: i32) -> i32 {
-        (a == b) as i32
+    pub(crate) fn ge_fn(a: i32, b: i32) -> Result<i32, VsmError> {
+        Ok((a >= b) as i32)
     }
     
-    fn ne_fn(a: i32, b: i32) -> i32 {
-        (a != b) as i32
+    pub(crate) fn le_fn(a: i32, b: i32) -> Result<i32, VsmError> {
+        Ok((a <= b) as i32)
     }
-    
-    fn gt_fn(a: i32, b: i32) -> i32 {
-        (a > b) as i32
-    }
-    
-    fn lt_fn(a: i32, b: i32) -> i32 {
-        (a < b) as i32
-    }
-    
-    fn ge_fn(a: i32, b: i32) -> i32 {
-        (a >= b) as i32
-    }
-    
-    fn le_fn(a: i32, b: i32) -> i32 {
-        (a <= b) as i32
-    }
-    fn exec_instruction(&mut self, instruction : Instruction) -> Result<Option<i32>, String> {
+    fn exec_instruction(&mut self, instruction : Instruction) -> Result<Option<i32>, VsmError> {
         let mut return_code : Option<i32> = None;
 
         
-        let operand1 = match instruction.operand[0] {
-            Some(operand) => operand,
-            None => -1,
-        };
-        let operand2 = match instruction.operand[1] {
-            Some(operand) => operand,
-            None => -1,
-        };
+        let operand1 = instruction.operand[0].unwrap_or(-1);
+        let operand2 = instruction.operand[1].unwrap_or(-1);
+        let arithmetic_mode = self.arithmetic_mode;
 
         match instruction.operation_code {
             OperationCode::Isp => {
                 if operand1 != 0{
-                    self.stack_pointer = match self.stack_pointer {
-                        Some(val) => Some((val as i32 + operand1) as usize),
-                        None => Some((-1+operand1) as usize),
-                    };                    
+                    let sp = match self.stack_pointer {
+                        Some(val) => val as i64 + operand1 as i64,
+                        None => -1 + operand1 as i64,
+                    };
+                    if sp < -1 {
+                        return Err(VsmError::StackUnderflow);
+                    }
+                    if sp >= 0 && !self.grow_stack(sp as usize) {
+                        return Err(VsmError::StackOverflow);
+                    }
+                    self.stack_pointer = if sp == -1 { None } else { Some(sp as usize) };
                 }
             },
 
             OperationCode::La | OperationCode::Lv => {
-                self.stack_pointer_increment();
-                let base_register =  self.base_register_read(operand1)?;
-                let address  = operand2 + base_register as i32;
+                self.stack_pointer_increment()?;
+                let address = self.effective_address(operand1, operand2)?;
                 match instruction.operation_code {
                     OperationCode::La => {
-                        self.stack_write(self.stack_pointer, address)?;
+                        self.stack_write(self.stack_pointer, address as i32)?;
                     },
                     OperationCode::Lv => {
-                        let value = self.stack_read(Some(address as usize))?;
+                        let value = self.stack_read(Some(address))?;
                         self.stack_write(self.stack_pointer, value)?;
                     }
                     _ => {}
                 }
             },
             OperationCode::Lc => {
-                self.stack_pointer_increment();
+                self.stack_pointer_increment()?;
                 self.stack_write(self.stack_pointer, operand1)?;
             },
             OperationCode::Li => {
-                let address = self.stack_read(self.stack_pointer)?;
-                let value = self.stack_read(Some(address as usize))?;
+                let address = Vsm::address(self.stack_read(self.stack_pointer)? as i64)?;
+                let value = self.stack_read(Some(address))?;
 
                 self.stack_write(self.stack_pointer, value)?;
                 
             },
             OperationCode::Dup => {
                 let value = self.stack_read(self.stack_pointer)?;
-                self.stack_pointer_increment();
+                self.stack_pointer_increment()?;
                 self.stack_write(self.stack_pointer, value)?;
             },
             OperationCode::Si => {
                 let value = self.stack_read(self.stack_pointer)?;
                 self.stack_pointer_decrement()?;
-                let address = self.stack_read(self.stack_pointer)?;
+                let address = Vsm::address(self.stack_read(self.stack_pointer)? as i64)?;
                 self.stack_pointer_decrement()?;
-                self.stack_write(Some(address as usize), value)?;
+                self.stack_write(Some(address), value)?;
             },
             OperationCode::Sv => {
-                let base_register =  self.base_register_read(operand1)?;
-                let address  = operand2 as usize + base_register;
+                let address = self.effective_address(operand1, operand2)?;
                 let value = self.stack_read(self.stack_pointer)?;
                 self.stack_pointer_decrement()?;
-                self.stack_write(Some(address as usize), value)?;
+                self.stack_write(Some(address), value)?;
             },
             OperationCode::Sb => {
                 let value = self.stack_read(self.stack_pointer)?;
+                let address = Vsm::address(value as i64)?;
                 match operand1 {
-                    0 => self.global_top_address = value as usize,
-                    1 => self.frame_top_address = value as usize,
+                    0 => self.global_top_address = address,
+                    1 => self.frame_top_address = address,
                     _ => {
-                        return Err(format!("invalid instruction '{}'", instruction.to_string()));
+                        return Err(VsmError::InvalidBaseRegister(operand1));
                     }
                 }
                 self.stack_pointer_decrement()?;
             },
             OperationCode::B | OperationCode::Bz => {
-                let perform_b_operation = |program_counter: &mut usize, operand1: i32| {
-                    *program_counter = ((*program_counter as i32) + operand1) as usize;
-                };
+                let target = Vsm::branch_target(self.program_counter as i64 + operand1 as i64);
 
                 match instruction.operation_code {
                     OperationCode::B => {
-                        perform_b_operation(&mut self.program_counter, operand1);
+                        self.program_counter = target?;
                     },
                     OperationCode::Bz => {
                         let value = self.stack_read(self.stack_pointer)?;
                         if value == 0 {
-                            perform_b_operation(&mut self.program_counter, operand1);
+                            self.program_counter = target?;
                         }
                         self.stack_pointer_decrement()?;
                     },
@@ -324,48 +1151,57 @@ impl Vsm {
                 }
             },
             OperationCode::Call => {
+                let target = Vsm::branch_target(operand1 as i64)?;
                 let stack_pointer = match self.stack_pointer {
                     Some(sp) => sp as i32,
                     None => -1,                    
                 }; 
                 
+                if !self.grow_stack((stack_pointer + 3) as usize) {
+                    return Err(VsmError::StackOverflow);
+                }
+
                 let frame_address = stack_pointer+2;
                 self.stack_write(Some(frame_address as usize), self.frame_top_address as i32)?;
 
                 let pc_address = stack_pointer+3;
                 self.stack_write(Some(pc_address as usize), self.program_counter as i32)?;
                 self.frame_top_address = (stack_pointer + 1) as usize;
-                self.program_counter = operand1 as usize;
+                self.program_counter = target;
             },
             OperationCode::Ret => {
                 self.stack_pointer = Some(self.frame_top_address);
 
 
                 let frame_address = self.stack_pointer.unwrap() + 1;
-                let frame_top_address_value = self.stack_read(Some(frame_address as usize))?;
-                self.frame_top_address = frame_top_address_value as usize;
+                let frame_top_address_value = self.stack_read(Some(frame_address))?;
+                self.frame_top_address = Vsm::address(frame_top_address_value as i64)?;
 
 
                 let pc_address = self.stack_pointer.unwrap() + 2;
                 let program_counter_value = self.stack_read(Some(pc_address))?;
-                self.program_counter = program_counter_value as usize;
+                self.program_counter = Vsm::branch_target(program_counter_value as i64)?;
             },
 
             OperationCode::Getc | OperationCode::Geti => {
-                let stdin = io::stdin();
+                self.output.flush().map_err(|err| VsmError::OutputError(err.to_string()))?;
                 let mut buffer = String::new();
-                stdin.lock().read_line(&mut buffer).unwrap();
+                let read_size = self.input.read_line(&mut buffer).map_err(|err| VsmError::InputError(err.to_string()))?;
+                if read_size == 0 {
+                    return Err(VsmError::InputError("unexpected end of input".to_string()));
+                }
+                self.input_log.extend_from_slice(buffer.as_bytes());
 
-                self.stack_pointer_increment();
+                self.stack_pointer_increment()?;
                 match instruction.operation_code {
                     OperationCode::Getc=> {
                         let buffer_trim = buffer.trim();
-                        if buffer_trim.len() != 1 {
-                            return Err(format!("error getc input is one character inputcharacter='{}'", buffer_trim));
+                        if buffer_trim.chars().count() != 1 {
+                            return Err(VsmError::InputError(format!("getc input is one character inputcharacter='{}'", buffer_trim)));
                         }
-                        match buffer.chars().next() {
+                        match buffer_trim.chars().next() {
                             Some(input_char) => self.stack_write(self.stack_pointer, input_char as i32)?,
-                            None => return Err(format!("error getc input is one character inputcharacter='{}'", buffer_trim)),
+                            None => return Err(VsmError::InputError(format!("getc input is one character inputcharacter='{}'", buffer_trim))),
                         }
                     }
                     OperationCode::Geti =>{
@@ -374,7 +1210,7 @@ impl Vsm {
                             Ok(number) => {
                                 self.stack_write(self.stack_pointer, number)?;
                             }
-                            Err(err) => return Err(err.to_string())
+                            Err(err) => return Err(VsmError::InputError(format!("'{}' {}", buffer.trim(), err)))
                         }
                     }
                     _ => {}
@@ -386,19 +1222,43 @@ impl Vsm {
                 self.stack_pointer_decrement()?;
 
                 let print_str = match instruction.operation_code {
-                    OperationCode::Putc => std::char::from_u32(value as u32).unwrap().to_string(),
+                    OperationCode::Putc => match std::char::from_u32(value as u32) {
+                        Some(c) => c.to_string(),
+                        None => return Err(VsmError::InvalidCharacter(value)),
+                    },
                     OperationCode::Puti => value.to_string(),
                     _ => {"".to_string()},
                 };
-                print!("{}", print_str);
+                write!(self.output, "{}", print_str).map_err(|err| VsmError::OutputError(err.to_string()))?;
+                self.output_position += print_str.len() as u64;
             },
-            OperationCode::Add => self.perform_operation(Vsm::add_fn)?,
-            OperationCode::Sub => self.perform_operation(Vsm::sub_fn)?,
-            OperationCode::Mul => self.perform_operation(Vsm::mul_fn)?,
-            OperationCode::Div => self.perform_operation(Vsm::div_fn)?,
-            OperationCode::Mod => self.perform_operation(Vsm::mod_fn)?,
+            OperationCode::Puts => {
+                let mut address = Vsm::address(self.stack_read(self.stack_pointer)? as i64)?;
+                self.stack_pointer_decrement()?;
+
+                // 0 のセルまでを一文字ずつ出力する
+                let mut print_str = String::new();
+                loop {
+                    let value = self.stack_read(Some(address))?;
+                    if value == 0 {
+                        break;
+                    }
+                    match std::char::from_u32(value as u32) {
+                        Some(c) => print_str.push(c),
+                        None => return Err(VsmError::InvalidCharacter(value)),
+                    }
+                    address += 1;
+                }
+                write!(self.output, "{}", print_str).map_err(|err| VsmError::OutputError(err.to_string()))?;
+                self.output_position += print_str.len() as u64;
+            },
+            OperationCode::Add => self.perform_operation(|a, b| arithmetic_mode.add(a, b))?,
+            OperationCode::Sub => self.perform_operation(|a, b| arithmetic_mode.sub(a, b))?,
+            OperationCode::Mul => self.perform_operation(|a, b| arithmetic_mode.mul(a, b))?,
+            OperationCode::Div => self.perform_operation(|a, b| arithmetic_mode.div(a, b))?,
+            OperationCode::Mod => self.perform_operation(|a, b| arithmetic_mode.rem(a, b))?,
             OperationCode::Inv => {
-                let value = - self.stack_read(self.stack_pointer)?;
+                let value = arithmetic_mode.neg(self.stack_read(self.stack_pointer)?)?;
                 self.stack_write(self.stack_pointer, value)?;
             },
             OperationCode::Eq => self.perform_operation(Vsm::eq_fn)?,
@@ -407,6 +1267,32 @@ impl Vsm {
             OperationCode::Lt => self.perform_operation(Vsm::lt_fn)?,
             OperationCode::Ge => self.perform_operation(Vsm::ge_fn)?,          
             OperationCode::Le => self.perform_operation(Vsm::le_fn)?,
+            OperationCode::Alloc => {
+                let size = self.stack_read(self.stack_pointer)?;
+                let address = self.heap.allocate(size, self.program_counter - 1)?;
+                self.record_heap_change(HeapChange::Allocate { address });
+                self.stack_write(self.stack_pointer, address as i32)?;
+            },
+            OperationCode::Free => {
+                let address = self.stack_read(self.stack_pointer)?;
+                if let Some(change) = self.heap.free(address, self.program_counter - 1)? {
+                    self.record_heap_change(change);
+                }
+                self.stack_pointer_decrement()?;
+            },
+            OperationCode::Lh => {
+                let address = self.stack_read(self.stack_pointer)?;
+                let value = self.heap.read(address)?;
+                self.stack_write(self.stack_pointer, value)?;
+            },
+            OperationCode::Sh => {
+                let value = self.stack_read(self.stack_pointer)?;
+                self.stack_pointer_decrement()?;
+                let address = self.stack_read(self.stack_pointer)?;
+                let old_value = self.heap.write(address, value)?;
+                self.record_heap_change(HeapChange::Write { address: address as usize, old_value });
+                self.stack_pointer_decrement()?;
+            },
             OperationCode::Exit => {
                 return_code = match self.stack_read(self.stack_pointer) {
                     Ok(value) => Some(value),
@@ -0,0 +1,18 @@
+// fact.vsm と同じ計算
+int fact(int n) {
+    if (n == 0) return 1;
+    else return n * fact(n - 1);
+}
+
+int main() {
+    int n, r;
+    putc('n');
+    putc('=');
+    n = 10;
+    r = fact(n);
+    puti(n);
+    putc('!');
+    putc('=');
+    puti(r);
+    putc('\n');
+}
@@ -0,0 +1,28 @@
+// 入力した数を連結リストに積み, 逆順に出力する. 0 で入力を終える
+int push(int head, int value) {
+    int node;
+    node = alloc(2);
+    store(node, value);
+    store(node + 1, head);
+    return node;
+}
+
+int main() {
+    int head;
+    int value;
+    int next;
+    head = 0;
+    value = geti();
+    while (value != 0) {
+        head = push(head, value);
+        value = geti();
+    }
+    while (head != 0) {
+        puti(load(head));
+        putc('\n');
+        next = load(head + 1);
+        free(head);
+        head = next;
+    }
+    return 0;
+}
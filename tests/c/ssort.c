@@ -0,0 +1,37 @@
+/* 選択ソート */
+int a[8];
+int count = 8;
+
+int sort(int n) {
+    int i, j, min, k, tmp;
+    for (i = 0; i < n - 1; i = i + 1) {
+        min = a[i];
+        k = i;
+        for (j = i + 1; j < n; j = j + 1) {
+            if (a[j] < min) {
+                min = a[j];
+                k = j;
+            }
+        }
+        tmp = a[k];
+        a[k] = a[i];
+        a[i] = tmp;
+    }
+    return 0;
+}
+
+int main() {
+    int i;
+    i = 0;
+    while (i < count) {
+        a[i] = geti();
+        i = i + 1;
+    }
+    sort(count);
+    for (i = 0; i < count; i = i + 1) {
+        puti(a[i]);
+        if (i < count - 1 && 1) putc(' ');
+    }
+    putc('\n');
+    return a[0] + a[count - 1];
+}
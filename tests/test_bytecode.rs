@@ -0,0 +1,179 @@
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use virtual_stack_machine::bytecode::BytecodeError;
+    use virtual_stack_machine::code::{AssembleError, Code, OperationCode};
+
+    const VSM_FILES: [&str; 11] = [
+        "tests/vsm/add.vsm",
+        "tests/vsm/average.vsm",
+        "tests/vsm/declare.vsm",
+        "tests/vsm/exam.vsm",
+        "tests/vsm/fact.vsm",
+        "tests/vsm/fact_label.vsm",
+        "tests/vsm/full.vsm",
+        "tests/vsm/hello.vsm",
+        "tests/vsm/matrix.vsm",
+        "tests/vsm/ssort.vmc",
+        "tests/vsm/while.vsm",
+    ];
+
+    #[test]
+    fn test_bytecode_round_trip() {
+        for file_path in VSM_FILES {
+            let mut code = Code::new();
+            code.read(file_path).unwrap();
+
+            let decoded = Code::from_bytes(&code.to_bytes()).unwrap();
+            assert_eq!(decoded.instructions(), code.instructions(), "{}", file_path);
+            assert_eq!(decoded.labels(), code.labels(), "{}", file_path);
+            assert_eq!(decoded.data(), code.data(), "{}", file_path);
+            assert_eq!(decoded.data_labels(), code.data_labels(), "{}", file_path);
+            assert_eq!(decoded.source_file(), Some(file_path));
+            for program_counter in 0..code.len() {
+                assert_eq!(decoded.source_location(program_counter), code.source_location(program_counter));
+            }
+        }
+    }
+
+    #[test]
+    fn test_bytecode_read_file() {
+        let file_path = "tests/bytecode_read_file.vsmb";
+        let mut code = Code::new();
+        code.read("tests/vsm/fact_label.vsm").unwrap();
+        code.write_bytes(file_path).unwrap();
+
+        let mut decoded = Code::new();
+        decoded.read(file_path).unwrap();
+        assert_eq!(decoded.instructions(), code.instructions());
+        assert_eq!(decoded.label_address("main"), Some(27));
+
+        fs::remove_file(file_path).unwrap();
+    }
+
+    #[test]
+    fn test_bytecode_without_sections() {
+        let mut code = Code::new();
+        code.append_instruction(OperationCode::Lc, Some(-5), None);
+        code.append_instruction(OperationCode::Exit, None, None);
+        let bytes = code.to_bytes();
+        // header 12 bytes + LC -5 (2 bytes) + EXIT (1 byte)
+        assert_eq!(bytes.len(), 15);
+
+        let decoded = Code::from_bytes(&bytes).unwrap();
+        assert_eq!(decoded.instructions(), code.instructions());
+        assert!(decoded.labels().is_empty());
+        assert_eq!(decoded.line_number(0), None);
+    }
+
+    #[test]
+    fn test_bytecode_errors() {
+        let mut code = Code::new();
+        code.read("tests/vsm/fact_label.vsm").unwrap();
+        let bytes = code.to_bytes();
+
+        assert_eq!(Code::from_bytes(b"VSMX").err(), Some(BytecodeError::InvalidMagic));
+        assert_eq!(Code::from_bytes(&bytes[..20]).err(), Some(BytecodeError::UnexpectedEof));
+
+        let mut version = bytes.clone();
+        version[4] = 99;
+        assert_eq!(Code::from_bytes(&version).err(), Some(BytecodeError::UnsupportedVersion(99)));
+
+        let mut operation_code = bytes.clone();
+        operation_code[12] = 200;
+        assert_eq!(Code::from_bytes(&operation_code).err(), Some(BytecodeError::InvalidOperationCode(200)));
+    }
+
+    // ラベルやソースの情報を持たない, 命令だけのバイトコード
+    fn instructions_only(code: &Code) -> Vec<u8> {
+        let mut stripped = Code::new();
+        for instruction in code.instructions() {
+            stripped.append_instruction(instruction.operation_code, instruction.operand[0], instruction.operand[1]);
+        }
+        stripped.to_bytes()
+    }
+
+    #[test]
+    fn test_bytecode_invalid_symbols() {
+        // 3 命令のコードのセクションを, 最後の命令を除いた 2 命令のコードに付ける
+        let mut code = Code::new();
+        code.parse("LC 0\nEXIT\nLC 0\nend:\n", "symbols.vsm").unwrap();
+        let mut truncated = Code::new();
+        code.instructions()[..2]
+            .iter()
+            .for_each(|instruction| truncated.append_instruction(instruction.operation_code, instruction.operand[0], None));
+        let mut bytes = truncated.to_bytes();
+        bytes.extend_from_slice(&code.to_bytes()[instructions_only(&code).len()..]);
+        assert_eq!(Code::from_bytes(&bytes).err(), Some(BytecodeError::InvalidSymbol("end".to_string())));
+
+        let mut code = Code::new();
+        code.append_instruction(OperationCode::Lc, Some(0), None);
+        code.append_instruction(OperationCode::Call, Some(4), None);
+        code.append_instruction(OperationCode::Exit, None, None);
+        assert_eq!(Code::from_bytes(&code.to_bytes()).err(), Some(BytecodeError::InvalidCallTarget(1)));
+        let err = code.write_bytes("tests/bytecode_invalid_call.vsmb").unwrap_err();
+        assert_eq!(err.to_string(), "CALL at 0001 jumps outside the code");
+        assert!(!std::path::Path::new("tests/bytecode_invalid_call.vsmb").exists());
+    }
+
+    #[test]
+    fn test_bytecode_call_to_end() {
+        // 末尾のラベルへの CALL はテキストと同じく読めて, 実行したときにエラーになる
+        let file_path = "tests/bytecode_call_to_end.vsmb";
+        let mut code = Code::new();
+        code.parse("LC 1\nBZ 2\nCALL end\nEXIT\nend:\n", "call_to_end.vsm").unwrap();
+        code.write_bytes(file_path).unwrap();
+
+        let mut decoded = Code::new();
+        decoded.read(file_path).unwrap();
+        assert_eq!(decoded.instructions(), code.instructions());
+        fs::remove_file(file_path).unwrap();
+    }
+
+    #[test]
+    fn test_bytecode_duplicate_label() {
+        let file_path = "tests/bytecode_duplicate_label.vsmb";
+        let mut code = Code::new();
+        code.read("tests/vsm/fact_label.vsm").unwrap();
+        code.write_bytes(file_path).unwrap();
+
+        let mut linked = Code::new();
+        linked.read(file_path).unwrap();
+        let errors = linked.read(file_path).unwrap_err();
+        fs::remove_file(file_path).unwrap();
+
+        assert_eq!(errors.len(), code.labels().len());
+        assert!(errors.iter().all(|err| matches!(err, AssembleError::DuplicateLabel { first_line: None, .. })));
+        assert_eq!(errors[0].to_string(), "tests/bytecode_duplicate_label.vsmb:0:1: duplicate label `else`");
+        // 失敗した読み込みは何も追加しない
+        assert_eq!(linked.instructions(), code.instructions());
+    }
+
+    #[test]
+    fn test_bytecode_data_not_relocatable() {
+        let file_path = "tests/bytecode_data_not_relocatable.vsmb";
+        let mut code = Code::new();
+        code.parse(".data\nmsg: .string \"hi\"\n.text\nLA 0 msg\nPUTS\n", "data.vsm").unwrap();
+        code.write_bytes(file_path).unwrap();
+
+        // データを指すオペランドは付け直せないので, データを持つコードを続けて読めない
+        let mut linked = Code::new();
+        linked.parse(".data\n.word 1\n", "first.vsm").unwrap();
+        let errors = linked.read(file_path).unwrap_err();
+        assert!(matches!(errors[0], AssembleError::DataNotRelocatable { .. }));
+        assert_eq!(
+            errors[0].to_string(),
+            "cannot load the data section of `tests/bytecode_data_not_relocatable.vsmb` after other data"
+        );
+
+        // データを持たないコードの後なら読める
+        let mut linked = Code::new();
+        linked.parse("LC 0\n", "first.vsm").unwrap();
+        linked.read(file_path).unwrap();
+        assert_eq!(linked.data(), code.data());
+        assert_eq!(linked.data_labels(), code.data_labels());
+
+        fs::remove_file(file_path).unwrap();
+    }
+}
@@ -0,0 +1,126 @@
+#[cfg(test)]
+mod tests {
+    use std::process::Command;
+
+    fn run_cli(args: &[&str]) -> (Option<i32>, String) {
+        let output = Command::new(env!("CARGO_BIN_EXE_virtual_stack_machine"))
+            .args(args)
+            .output()
+            .unwrap();
+        (output.status.code(), String::from_utf8_lossy(&output.stdout).to_string())
+    }
+
+    #[test]
+    fn test_cli_exit_code() {
+        let (code, stdout) = run_cli(&["tests/vsm/while.vsm"]);
+        assert_eq!(code, Some(101));
+        assert_eq!(stdout, "5050\n");
+
+        let (code, _) = run_cli(&["tests/vsm/fact.vsm"]);
+        assert_eq!(code, Some(0));
+    }
+
+    #[test]
+    fn test_cli_error_exit_code() {
+        let (code, _) = run_cli(&["tests/vsm/error.vsm"]);
+        assert_eq!(code, Some(1));
+
+        let (code, _) = run_cli(&["tests/vsm/get.vsm", "--input", "tests/vsm/add.vsm"]);
+        assert_eq!(code, Some(1));
+    }
+
+    #[test]
+    fn test_cli_compile_c_file() {
+        let (code, stdout) = run_cli(&["tests/c/fact.c"]);
+        assert_eq!(code, Some(0));
+        assert_eq!(stdout, "n=10!=3628800\n");
+    }
+
+    #[test]
+    fn test_cli_verify() {
+        let (code, stdout) = run_cli(&["tests/vsm/while.vsm", "--verify"]);
+        assert_eq!(code, Some(101));
+        assert_eq!(stdout, "5050\n");
+
+        // 検査で誤りが見つかったら実行しない
+        let file_path = "tests/cli_verify.vsm";
+        std::fs::write(file_path, "LC 1\nPUTI\nADD\nEXIT\n").unwrap();
+        let (code, stdout) = run_cli(&[file_path, "--verify"]);
+        std::fs::remove_file(file_path).unwrap();
+        assert_eq!(code, Some(1));
+        assert_eq!(stdout, "");
+    }
+
+    #[test]
+    fn test_cli_optimize() {
+        let (code, stdout) = run_cli(&["tests/vsm/fact.vsm", "-O"]);
+        assert_eq!(code, Some(0));
+        assert_eq!(stdout, "n=10!=3628800\n");
+    }
+
+    #[test]
+    fn test_cli_fast() {
+        let (code, stdout) = run_cli(&["tests/vsm/while.vsm", "--fast"]);
+        assert_eq!(code, Some(101));
+        assert_eq!(stdout, "5050\n");
+    }
+
+    #[test]
+    fn test_cli_profile() {
+        let (code, stdout) = run_cli(&["tests/c/fact.c", "--profile-output", "tests/cli_profile.json"]);
+        let profile = std::fs::read_to_string("tests/cli_profile.json").unwrap();
+        std::fs::remove_file("tests/cli_profile.json").unwrap();
+        assert_eq!(code, Some(0));
+        assert_eq!(stdout, "n=10!=3628800\n");
+        assert!(profile.contains("{\"name\": \"fact\", \"address\": 5, \"calls\": 11, \"inclusive\": 169, \"exclusive\": 169}"));
+    }
+
+    #[test]
+    fn test_cli_coverage() {
+        let (code, _) = run_cli(&["tests/vsm/while.vsm", "--coverage-output", "tests/cli_coverage.txt"]);
+        let listing = std::fs::read_to_string("tests/cli_coverage.txt").unwrap();
+        std::fs::remove_file("tests/cli_coverage.txt").unwrap();
+        assert_eq!(code, Some(101));
+        assert!(listing.starts_with("coverage: "));
+        assert!(listing.contains("taken 1, not taken 100"));
+    }
+
+    #[test]
+    fn test_cli_snapshot() {
+        // 途中で止めて保存した状態から再開すると続きの出力だけが出る
+        let (code, stdout) = run_cli(&["tests/c/fact.c", "--max-steps", "100", "--save-snapshot", "tests/cli_snapshot.vsms"]);
+        assert_eq!(code, Some(1));
+        assert_eq!(stdout, "n=");
+        let (code, resumed) = run_cli(&["tests/cli_snapshot.vsms"]);
+        let (optimize_code, _) = run_cli(&["tests/cli_snapshot.vsms", "-O"]);
+        std::fs::remove_file("tests/cli_snapshot.vsms").unwrap();
+        assert_eq!(code, Some(0));
+        assert_eq!(stdout + &resumed, "n=10!=3628800\n");
+        assert_eq!(optimize_code, Some(1));
+    }
+
+    #[test]
+    fn test_cli_stack_size() {
+        let file_path = "tests/cli_stack_size.vsm";
+        std::fs::write(file_path, "ISP 5000\nLC 7\nEXIT\n").unwrap();
+        let (code, _) = run_cli(&[file_path]);
+        let (overflow_code, _) = run_cli(&[file_path, "--max-stack-size", "4096"]);
+        let (fixed_code, _) = run_cli(&[file_path, "--stack-size", "8192", "--max-stack-size", "8192"]);
+        std::fs::remove_file(file_path).unwrap();
+        assert_eq!(code, Some(7));
+        assert_eq!(overflow_code, Some(1));
+        assert_eq!(fixed_code, Some(7));
+    }
+
+    #[test]
+    fn test_cli_heap() {
+        let input_path = "tests/cli_heap_input.txt";
+        std::fs::write(input_path, "3\n1\n4\n0\n").unwrap();
+        let (code, stdout) = run_cli(&["tests/c/list.c", "--input", input_path]);
+        let (exhausted_code, _) = run_cli(&["tests/c/list.c", "--input", input_path, "--max-heap-size", "4"]);
+        std::fs::remove_file(input_path).unwrap();
+        assert_eq!(code, Some(0));
+        assert_eq!(stdout, "4\n1\n3\n");
+        assert_eq!(exhausted_code, Some(1));
+    }
+}
@@ -4,7 +4,7 @@ mod common;
 mod tests {
     use std::fs;
 
-    use virtual_stack_machine::code::Code;
+    use virtual_stack_machine::code::{AssembleError, Code, SourceLocation};
 
     use crate::common::write_to_file_for_test;
 
@@ -113,4 +113,190 @@ mod tests {
 
         fs::remove_file(file_path).unwrap();
     }
+
+    #[test]
+    fn test_read_code_label() {
+        let mut code = Code::new();
+        code.read("tests/vsm/fact.vsm").unwrap();
+        let mut code_label = Code::new();
+        code_label.read("tests/vsm/fact_label.vsm").unwrap();
+
+        assert_eq!(code.len(), code_label.len());
+        for program_counter in 0..code.len() {
+            assert_eq!(
+                code.get_instruction(program_counter),
+                code_label.get_instruction(program_counter)
+            );
+        }
+        assert_eq!(code_label.label_address("fact"), Some(5));
+        assert_eq!(code_label.label_address("main"), Some(27));
+    }
+
+    #[test]
+    fn test_read_code_label_undefined() {
+        let file_path = "tests/read_code_label_undefined.txt";
+        let file_contents = r#"
+        loop: LC 1
+        BZ done
+        B loop
+        "#;
+
+        write_to_file_for_test(file_path, file_contents).unwrap();
+        let mut code = Code::new();
+        let errors = code.read(file_path).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert!(matches!(errors[0], AssembleError::UndefinedLabel { .. }));
+        assert_eq!(
+            errors[0].to_string(),
+            "tests/read_code_label_undefined.txt:3:12: undefined label `done`"
+        );
+
+        fs::remove_file(file_path).unwrap();
+    }
+
+    #[test]
+    fn test_read_code_label_duplicate() {
+        let file_path = "tests/read_code_label_duplicate.txt";
+        let file_contents = r#"
+        loop:
+        LC 1
+        loop: EXIT
+        "#;
+
+        write_to_file_for_test(file_path, file_contents).unwrap();
+        let mut code = Code::new();
+        let errors = code.read(file_path).unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert!(matches!(
+            errors[0],
+            AssembleError::DuplicateLabel { first_line: Some(2), .. }
+        ));
+        assert_eq!(errors[0].span().unwrap().line, 4);
+
+        fs::remove_file(file_path).unwrap();
+    }
+
+    #[test]
+    fn test_read_code_collect_errors() {
+        let file_path = "tests/read_code_collect_errors.txt";
+        let file_contents = "LC abc\nFOO 1\nADD 3\nLC 1\nEXIT\n";
+
+        write_to_file_for_test(file_path, file_contents).unwrap();
+        let mut code = Code::new();
+        let errors = code.read(file_path).unwrap_err();
+        assert_eq!(errors.len(), 3);
+        assert!(matches!(errors[0], AssembleError::InvalidOperand { .. }));
+        assert!(matches!(errors[1], AssembleError::InvalidOperationCode { .. }));
+        assert!(matches!(
+            errors[2],
+            AssembleError::OperandCount { expected: 0, found: 1, .. }
+        ));
+        assert!(code.is_empty());
+
+        let span = errors[0].span().unwrap();
+        assert_eq!(span.file_path, file_path);
+        assert_eq!((span.line, span.column_start, span.column_end), (1, 4, 7));
+        assert_eq!(span.text(), "abc");
+
+        fs::remove_file(file_path).unwrap();
+    }
+
+    #[test]
+    fn test_assemble_error_render() {
+        let mut code = Code::new();
+        let errors = code.parse("LC 1\n  BZ done // skip\n", "render.vsm").unwrap_err();
+        let expected = "\
+error: undefined label `done`
+ --> render.vsm:2:6
+  |
+2 |   BZ done // skip
+  |      ^^^^
+";
+        assert_eq!(errors[0].render(), expected);
+    }
+
+    #[test]
+    fn test_read_code_source_map() {
+        let mut code = Code::new();
+        code.parse("start:
+  LC 1 // one
+LC 2
+ADD //
+EXIT
+", "source_map.vsm").unwrap();
+        assert_eq!(
+            code.source_location(0),
+            Some(&SourceLocation {
+                file_path: "source_map.vsm".to_string(),
+                line: 2,
+                text: "  LC 1 // one".to_string(),
+                comment: Some("one".to_string()),
+            })
+        );
+        assert_eq!(code.source_location(0).unwrap().code(), "LC 1");
+        assert_eq!(code.source_location(2).unwrap().comment, None);
+        assert_eq!(code.source_location(3).unwrap().to_string(), "source_map.vsm:5: EXIT");
+        assert_eq!(code.line_number(1), Some(3));
+        assert_eq!(code.source_location(4), None);
+    }
+
+    #[test]
+    fn test_read_code_data() {
+        let mut code = Code::new();
+        code.read("tests/vsm/hello.vsm").unwrap();
+        let mut expected = "Hello, \0world!\n\0".chars().map(|c| c as i32).collect::<Vec<_>>();
+        expected.push(3);
+        assert_eq!(code.data(), &expected[..]);
+        assert_eq!(code.data_labels()["world"], 8);
+        assert_eq!(code.data_labels()["count"], 16);
+        // データのラベルはコードのラベルとは別に持つ
+        assert_eq!(code.label_address("count"), None);
+        assert_eq!(code.get_instruction(0).operand, [Some(0), Some(16)]);
+        assert_eq!(code.get_instruction(4).operand, [Some(0), Some(8)]);
+
+        let mut code = Code::new();
+        code.parse(".data\ns: .string \"a\\\"b // c\" // comment\nz: .zero 2\n.text\nLC z\nEXIT\n", "string.vsm").unwrap();
+        assert_eq!(code.data(), &[97, 34, 98, 32, 47, 47, 32, 99, 0, 0, 0]);
+        assert_eq!(code.get_instruction(0).operand, [Some(9), None]);
+    }
+
+    #[test]
+    fn test_write_code_data() {
+        let file_path = "tests/write_code_data.vsm";
+        let mut code = Code::new();
+        code.read("tests/vsm/hello.vsm").unwrap();
+        code.write(file_path).unwrap();
+
+        let mut written = Code::new();
+        written.read(file_path).unwrap();
+        assert_eq!(written.data(), code.data());
+        assert_eq!(written.data_labels(), code.data_labels());
+        assert_eq!(written.instructions(), code.instructions());
+        fs::remove_file(file_path).unwrap();
+    }
+
+    #[test]
+    fn test_read_code_data_errors() {
+        let mut code = Code::new();
+        let source = ".word 1\n.data\nLC 1\n.string abc\n.string \"a\\q\"\n.word 1 x\n.zero -1\n.bss\nx: .word 0\n.text\nx: EXIT\nB x\n";
+        let errors = code.parse(source, "data_errors.vsm").unwrap_err();
+        let messages = errors.iter().map(|err| err.message()).collect::<Vec<_>>();
+        assert_eq!(
+            messages,
+            [
+                "`.word` must be placed in the `.data` section",
+                "`LC` must be placed in the `.text` section",
+                "invalid operand `abc`",
+                "invalid operand `\"a\\q\"`",
+                "invalid operand `x`",
+                "invalid operand `-1`",
+                "invalid directive `.bss`",
+                "duplicate label `x`",
+                "`B` does not take a label operand `x`",
+            ]
+        );
+        assert!(matches!(errors[7], AssembleError::DuplicateLabel { first_line: Some(9), .. }));
+        assert!(code.is_empty());
+        assert!(code.data().is_empty());
+    }
 }
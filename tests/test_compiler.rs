@@ -0,0 +1,172 @@
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use virtual_stack_machine::code::Code;
+    use virtual_stack_machine::compiler::{compile, compile_file};
+    use virtual_stack_machine::vsm::{RuntimeError, SharedOutput, TraceType, Vsm};
+
+    fn exec_code_with_input(code: Code, input: &str) -> (Result<i32, RuntimeError>, String) {
+        let output = SharedOutput::new();
+        let mut vsm = Vsm::with_io(
+            TraceType::No,
+            Box::new(Cursor::new(input.to_string())),
+            Box::new(output.clone()),
+        );
+        vsm.load_code(code);
+        let result = vsm.exec_code();
+        (result, output.contents())
+    }
+
+    fn compile_and_exec(source: &str, input: &str) -> (Result<i32, RuntimeError>, String) {
+        exec_code_with_input(compile(source, "test.c").unwrap(), input)
+    }
+
+    fn compile_errors(source: &str) -> Vec<String> {
+        compile(source, "test.c")
+            .err()
+            .unwrap()
+            .iter()
+            .map(|error| error.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_compile_fact() {
+        let code = compile_file("tests/c/fact.c").unwrap();
+        assert_eq!(code.label_address("fact"), Some(5));
+        assert_eq!(code.source_file(), Some("tests/c/fact.c"));
+        assert_eq!(code.line_number(5), Some(2));
+
+        let (result, output) = exec_code_with_input(code, "");
+        assert_eq!(result, Ok(0));
+        assert_eq!(output, "n=10!=3628800\n");
+    }
+
+    #[test]
+    fn test_compile_ssort() {
+        let code = compile_file("tests/c/ssort.c").unwrap();
+        let (result, output) = exec_code_with_input(code, "5\n3\n-2\n8\n0\n7\n1\n4\n");
+        assert_eq!(output, "-2 0 1 3 4 5 7 8\n");
+        assert_eq!(result, Ok(6));
+    }
+
+    #[test]
+    fn test_compile_operators() {
+        let source = "
+            int g = -3;
+            int main() {
+                puti(7 / 2 + 7 % 2 * 10); putc(' ');
+                puti(-g - 1); putc(' ');
+                puti(!0 + !5); putc(' ');
+                puti((1 < 2) + (2 <= 2) + (3 > 4) + (4 >= 5) + (1 == 1) + (1 != 1));
+                return g;
+            }";
+        assert_eq!(compile_and_exec(source, ""), (Ok(-3), "13 2 1 3".to_string()));
+    }
+
+    #[test]
+    fn test_compile_short_circuit() {
+        let source = "
+            int main() {
+                int x;
+                x = 0 && putc('a');
+                x = x + (1 || putc('b'));
+                x = x + (1 && putc('c'));
+                x = x + (0 || putc(0));
+                return x;
+            }";
+        assert_eq!(compile_and_exec(source, ""), (Ok(2), "c\0".to_string()));
+    }
+
+    #[test]
+    fn test_compile_scopes_and_loops() {
+        let source = "
+            int main() {
+                int x = 1, i;
+                for (i = 0; i < 3; i = i + 1) {
+                    int x = i * 10;
+                    puti(x);
+                }
+                while (x < 100) x = x * 2;
+                if (x == 128) { putc('!'); }
+                return x;
+            }";
+        assert_eq!(compile_and_exec(source, ""), (Ok(128), "01020!".to_string()));
+    }
+
+    #[test]
+    fn test_compile_input() {
+        let source = "int main() { int c; c = getc(); return c + geti(); }";
+        assert_eq!(compile_and_exec(source, "a\n3\n"), (Ok(100), String::new()));
+    }
+
+    #[test]
+    fn test_compile_semantic_errors() {
+        let source = "
+int a[4];
+int f(int x, int x) { return a; }
+int main() {
+    int y;
+    y = f(1);
+    y[0] = z;
+    g();
+    return y;
+}";
+        assert_eq!(
+            compile_errors(source),
+            vec![
+                "test.c:3:18: duplicate parameter `x`",
+                "test.c:3:30: array `a` must be indexed",
+                "test.c:6:9: `f` takes 2 argument(s) but 1 were given",
+                "test.c:7:5: `y` is not an array",
+                "test.c:7:12: undefined variable `z`",
+                "test.c:8:5: undefined function `g`",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_compile_missing_main() {
+        assert_eq!(
+            compile_errors("int putc(int c) { return c; }"),
+            vec!["no `main` function", "test.c:1:5: `putc` is a builtin function"]
+        );
+    }
+
+    #[test]
+    fn test_compile_syntax_error_render() {
+        let errors = compile("int main() {\n    return 1 +;\n}\n", "test.c").err().unwrap();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(
+            errors[0].render(),
+            "error: expected an expression, found `;`\n --> test.c:2:15\n  |\n2 |     return 1 +;\n  |               ^\n"
+        );
+    }
+
+    #[test]
+    fn test_compile_nested_too_deeply() {
+        let source = format!("int main() {{ return {}1{}; }}", "(".repeat(100000), ")".repeat(100000));
+        let errors = compile(&source, "test.c").err().unwrap();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].message, "expression nested too deeply");
+
+        let source = format!("int main() {{ return {}1; }}", "-".repeat(100000));
+        assert_eq!(compile(&source, "test.c").err().unwrap()[0].message, "expression nested too deeply");
+
+        let source = format!("int main() {{ {}return 1;{} }}", "{".repeat(100000), "}".repeat(100000));
+        assert_eq!(compile(&source, "test.c").err().unwrap()[0].message, "statement nested too deeply");
+
+        // 括弧がなくても, 演算子の長い連鎖は AST を深くする
+        let source = format!("int main() {{ puti(1{}); }}", "+1".repeat(20000));
+        let errors = compile(&source, "test.c").err().unwrap();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].message, "expression nested too deeply");
+
+        // 制限より浅ければコンパイルできる
+        let source = format!("int main() {{ return {}1{}; }}", "(".repeat(90), ")".repeat(90));
+        assert_eq!(compile_and_exec(&source, ""), (Ok(1), String::new()));
+        let source = format!("int main() {{ return 0{}; }}", "+1".repeat(90));
+        assert_eq!(compile_and_exec(&source, ""), (Ok(90), String::new()));
+    }
+}
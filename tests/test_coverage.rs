@@ -0,0 +1,103 @@
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::Cursor;
+
+    use virtual_stack_machine::code::Code;
+    use virtual_stack_machine::compiler::compile;
+    use virtual_stack_machine::coverage::{BranchCoverage, Coverage, CoverageSummary};
+    use virtual_stack_machine::vsm::{SharedOutput, TraceType, Vsm};
+
+    fn exec_with_coverage(code: Code, input: &str) -> Vsm {
+        let mut vsm = Vsm::with_io(
+            TraceType::No,
+            Box::new(Cursor::new(input.to_string())),
+            Box::new(SharedOutput::new()),
+        );
+        vsm.load_code(code);
+        vsm.enable_coverage();
+        vsm.exec_code_fast().unwrap();
+        vsm
+    }
+
+    fn coverage_of_file(file_path: &str, input: &str) -> (Code, Coverage) {
+        let mut code = Code::new();
+        code.read(file_path).unwrap();
+        let vsm = exec_with_coverage(code.clone(), input);
+        (code, vsm.coverage().unwrap().clone())
+    }
+
+    #[test]
+    fn test_coverage_hits_and_branches() {
+        let (code, coverage) = coverage_of_file("tests/vsm/exam.vsm", "90\n");
+        assert_eq!(coverage.uncovered(&code), vec![]);
+        assert_eq!(coverage.branch(12), BranchCoverage { taken: 0, not_taken: 1 });
+        assert_eq!(
+            coverage.summary(&code),
+            CoverageSummary {
+                instructions: 21,
+                covered_instructions: 21,
+                branch_outcomes: 2,
+                covered_branch_outcomes: 1,
+            }
+        );
+
+        // 100 以下なら代入を飛ばす
+        let (code, coverage) = coverage_of_file("tests/vsm/exam.vsm", "50\n");
+        assert_eq!(coverage.uncovered(&code), vec![13, 14, 15]);
+        assert_eq!(coverage.branch(12), BranchCoverage { taken: 1, not_taken: 0 });
+        assert_eq!(coverage.hits(0), 1);
+        assert_eq!(coverage.hits(13), 0);
+
+        let (code, coverage) = coverage_of_file("tests/vsm/while.vsm", "");
+        let branch = (0..code.len()).map(|address| coverage.branch(address)).find(|branch| branch.taken > 0).unwrap();
+        assert_eq!(branch, BranchCoverage { taken: 1, not_taken: 100 });
+    }
+
+    #[test]
+    fn test_coverage_annotate_source() {
+        let (code, coverage) = coverage_of_file("tests/vsm/exam.vsm", "50\n");
+        let source = fs::read_to_string("tests/vsm/exam.vsm").unwrap();
+        let listing = coverage.annotate(&code, &source);
+        let lines = listing.lines().collect::<Vec<_>>();
+        assert_eq!(lines[0], "coverage: 18/21 instructions (85.7%), 1/2 branch outcomes (50.0%)");
+        assert_eq!(lines[1], "        1:    1: ISP 1 //(exam=>0 番地)");
+        assert_eq!(lines[13], "        1:   13: BZ 3 //条件不成立なら 16 へ分岐");
+        assert_eq!(lines[14], "           branch 0012: taken 1, not taken 0 (always taken)");
+        assert_eq!(lines[15], "    #####:   14: LA 0 0 //exam = 100;");
+        assert_eq!(lines[18], "        1:   17: LV 0 0 // putint(exam);");
+    }
+
+    #[test]
+    fn test_coverage_compiled_source() {
+        let source = "int main() {\n    int x;\n    x = 0;\n    // comment\n    return x && geti();\n}\n";
+        let code = compile(source, "coverage.c").unwrap();
+        let vsm = exec_with_coverage(code, "");
+        let listing = vsm.coverage().unwrap().annotate(vsm.code(), source);
+        let lines = listing.lines().collect::<Vec<_>>();
+        assert_eq!(lines[3], "        1:    3:     x = 0;");
+        assert_eq!(lines[4], "        -:    4:     // comment");
+        // && の右辺は実行されない
+        assert!(lines[5].starts_with("       1*:    5:     return x && geti();"), "{}", listing);
+    }
+
+    #[test]
+    fn test_coverage_annotate_instructions() {
+        let mut code = Code::new();
+        code.parse("LC 1\nBZ skip\nLC 2\nskip: EXIT\n", "coverage.vsm").unwrap();
+        let vsm = exec_with_coverage(code, "");
+        assert_eq!(
+            vsm.coverage().unwrap().annotate_instructions(vsm.code()),
+            [
+                "coverage: 4/4 instructions (100.0%), 1/2 branch outcomes (50.0%)",
+                "        1: 0000: LC 1",
+                "        1: 0001: BZ 1",
+                "           branch 0001: taken 0, not taken 1 (never taken)",
+                "        1: 0002: LC 2",
+                "        1: 0003: EXIT",
+                "",
+            ]
+            .join("\n")
+        );
+    }
+}
@@ -0,0 +1,92 @@
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use virtual_stack_machine::debugger::Debugger;
+    use virtual_stack_machine::vsm::{SharedOutput, TraceType, Vsm};
+
+    fn debug_for_test(file_path: &str, program_input: &str, commands: &str) -> (String, String) {
+        let program_output = SharedOutput::new();
+        let debugger_output = SharedOutput::new();
+        let mut vsm = Vsm::with_io(
+            TraceType::No,
+            Box::new(Cursor::new(program_input.to_string())),
+            Box::new(program_output.clone()),
+        );
+        vsm.read_code(file_path).unwrap();
+
+        let mut debugger = Debugger::new(
+            &mut vsm,
+            Box::new(Cursor::new(commands.to_string())),
+            Box::new(debugger_output.clone()),
+        );
+        assert!(debugger.run().unwrap().is_ok());
+        (program_output.contents(), debugger_output.contents())
+    }
+
+    #[test]
+    fn test_debugger_break_label() {
+        let (program_output, debugger_output) =
+            debug_for_test("tests/vsm/fact_label.vsm", "", "break fact\ncontinue\nregisters\nquit\n");
+        assert_eq!(program_output, "n=");
+        assert!(debugger_output.contains("breakpoint at 0005\n=> 0005: ISP 4\n"));
+        assert!(debugger_output.contains("PC = 5  SP = 5  B0 = 0  B1 = 6\n"));
+    }
+
+    #[test]
+    fn test_debugger_step_and_continue() {
+        let (program_output, debugger_output) =
+            debug_for_test("tests/vsm/while.vsm", "", "step 3\nstack 2 3\ncontinue\nstep\n");
+        assert_eq!(program_output, "5050\n");
+        assert!(debugger_output.contains("=> 0003: SI\n"));
+        assert!(debugger_output.contains(" SP->  S[  3]    0\n       S[  2]    0\n"));
+        assert!(debugger_output.contains("the program exited with code 101\n"));
+        assert!(debugger_output.contains("the program has exited with code 101\n"));
+    }
+
+    #[test]
+    fn test_debugger_invalid_ranges() {
+        let (_, debugger_output) =
+            debug_for_test("tests/vsm/add.vsm", "", "disas 18446744073709551615\nstack 3 2\nquit\n");
+        assert!(debugger_output.contains("=>  0000: LC 1\n"));
+        assert!(debugger_output.contains("    0004: EXIT\n"));
+        assert!(debugger_output.contains("usage: stack [from [to]]\n"));
+    }
+
+    #[test]
+    fn test_debugger_input_is_separate() {
+        let (program_output, debugger_output) =
+            debug_for_test("tests/vsm/get.vsm", "42\nx\n", "b 2\nc\ndelete 2\nc\n");
+        assert_eq!(program_output, "42x");
+        assert!(debugger_output.contains("=> 0002: GETC\n"));
+        assert!(debugger_output.contains("deleted breakpoint at 0002\n"));
+    }
+
+    #[test]
+    fn test_debugger_watchpoint() {
+        let (_, debugger_output) =
+            debug_for_test("tests/vsm/while.vsm", "", "watch 0\ncontinue\ncontinue\nquit\n");
+        assert!(debugger_output.contains("watchpoint 1: S[0] written at 0003 'SI': 0 -> 0\n=> 0004: LA 0 1\n"));
+        assert!(debugger_output.contains("watchpoint 1: S[0] written at 0015 'SI': 0 -> 1\n"));
+
+        let (_, debugger_output) =
+            debug_for_test("tests/vsm/while.vsm", "", "watch 0..1 change\ncontinue\nunwatch 1\nwatch 1 read\ncontinue\nquit\n");
+        assert!(debugger_output.contains("watchpoint 1: S[1] written at 0006 'SI': 0 -> 1\n"));
+        assert!(debugger_output.contains("watchpoint 2: S[1] read at 0007 'LV 0 1': value = 1\n"));
+    }
+
+    #[test]
+    fn test_debugger_reverse() {
+        // history で記録を有効にしてから戻る
+        let (_, debugger_output) = debug_for_test(
+            "tests/vsm/fact_label.vsm",
+            "",
+            "sb\nhistory 1000\nbreak fact\ncontinue\ncontinue\nstep-back 3\nlast-write 9\nreverse-continue\nregisters\nrc\nquit\n",
+        );
+        assert!(debugger_output.contains("the history is disabled. Use 'history <N>' to record instructions\n"));
+        assert!(debugger_output.contains("history: 0 of 1000 instructions recorded\n"));
+        assert!(debugger_output.contains("S[9] was last written at 0041 'LV 1 3' (13 instructions ago): 0 -> 10\n"));
+        assert!(debugger_output.contains("breakpoint at 0005\n=> 0005: ISP 4\n(vsm) PC = 5  SP = 5  B0 = 0  B1 = 6\n"));
+        assert!(debugger_output.contains("reached the beginning of the history\n=> 0000: ISP 0\n"));
+    }
+}
@@ -0,0 +1,111 @@
+#[cfg(test)]
+mod tests {
+    use virtual_stack_machine::code::Code;
+    use virtual_stack_machine::compiler::compile_file;
+    use virtual_stack_machine::disasm::{disassemble, disassemble_with_source, Disassembler};
+
+    const VSM_FILES: [&str; 10] = [
+        "tests/vsm/add.vsm",
+        "tests/vsm/average.vsm",
+        "tests/vsm/declare.vsm",
+        "tests/vsm/exam.vsm",
+        "tests/vsm/fact.vsm",
+        "tests/vsm/full.vsm",
+        "tests/vsm/hello.vsm",
+        "tests/vsm/matrix.vsm",
+        "tests/vsm/ssort.vmc",
+        "tests/vsm/while.vsm",
+    ];
+
+    #[test]
+    fn test_disassemble_round_trip() {
+        for file_path in VSM_FILES {
+            let mut code = Code::new();
+            code.read(file_path).unwrap();
+
+            let mut reassembled = Code::new();
+            reassembled.parse(&disassemble(&code), "disassembled.vsm").unwrap();
+            assert_eq!(reassembled.instructions(), code.instructions(), "{}", file_path);
+            assert_eq!(reassembled.data(), code.data(), "{}", file_path);
+        }
+    }
+
+    #[test]
+    fn test_disassemble_labels() {
+        let mut code = Code::new();
+        code.parse("LC 0\nCALL 4\nEXIT\nB -4\nISP 1\nloop: BZ loop\nRET\n", "labels.vsm").unwrap();
+
+        let expected = [
+            "L0000:",
+            "        LC 0                    // 0000",
+            "        CALL func_4             // 0001 -> 0004",
+            "        EXIT                    // 0002",
+            "        B L0000                 // 0003 -> 0000",
+            "func_4:",
+            "        ISP 1                   // 0004",
+            "loop:",
+            "        BZ loop                 // 0005 -> 0005",
+            "        RET                     // 0006",
+        ];
+        assert_eq!(disassemble(&code), expected.join("\n") + "\n");
+    }
+
+    #[test]
+    fn test_disassemble_data() {
+        let mut code = Code::new();
+        code.parse(".data\nmsg: .string \"a\\tb\"\nbuf: .zero 3\ntable: .word 1 2 3 4 5 6 7 8 9\n.text\nLA 0 msg\nPUTS\nLV 0 table\nEXIT\n", "data.vsm").unwrap();
+
+        let expected = [
+            "        .data",
+            "msg:",
+            "        .string \"a\\tb\"",
+            "buf:",
+            "        .zero 3",
+            "table:",
+            "        .word 1 2 3 4 5 6 7 8",
+            "        .word 9",
+            "        .text",
+            "        LA 0 msg                // 0000",
+            "        PUTS                    // 0001",
+            "        LV 0 table              // 0002",
+            "        EXIT                    // 0003",
+        ];
+        assert_eq!(disassemble(&code), expected.join("\n") + "\n");
+    }
+
+    #[test]
+    fn test_disassemble_out_of_range() {
+        let mut code = Code::new();
+        code.parse("LC 0\nBZ 10\nCALL 99\nB 0\nEXIT\n", "out_of_range.vsm").unwrap();
+
+        let disassembler = Disassembler::new(&code);
+        assert_eq!(disassembler.format_line(1), "        BZ 10                   // 0001 -> out of range");
+        assert_eq!(disassembler.format_line(2), "        CALL 99                 // 0002 -> out of range");
+        assert_eq!(disassembler.format_line(3), "        B L0004                 // 0003 -> 0004");
+        assert_eq!(disassembler.target(3), Some(4));
+
+        let mut reassembled = Code::new();
+        reassembled.parse(&disassembler.disassemble(), "disassembled.vsm").unwrap();
+        assert_eq!(reassembled.instructions(), code.instructions());
+    }
+
+    #[test]
+    fn test_disassemble_with_source() {
+        let code = compile_file("tests/c/fact.c").unwrap();
+        let listing = disassemble_with_source(&code);
+        let lines = listing.lines().collect::<Vec<_>>();
+        let fact = lines.iter().position(|line| *line == "fact:").unwrap();
+        assert_eq!(lines[fact + 1], "        // tests/c/fact.c:2: int fact(int n) {");
+        assert_eq!(lines[fact + 2], "        ISP 4                   // 0005");
+        assert_eq!(lines[fact + 3], "        // tests/c/fact.c:3: if (n == 0) return 1;");
+        // 同じ行から生成された命令の間には挟まない
+        assert!(lines[fact + 4].starts_with("        LV 1 3"));
+        assert!(lines[fact + 5].starts_with("        LC 0"));
+
+        // ソースの行はコメントなので読み直しても同じ命令になる
+        let mut reassembled = Code::new();
+        reassembled.parse(&listing, "disassembled.vsm").unwrap();
+        assert_eq!(reassembled.instructions(), code.instructions());
+        assert!(!disassemble(&code).contains("fact.c"));
+    }
+}
@@ -0,0 +1,131 @@
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use virtual_stack_machine::code::Code;
+    use virtual_stack_machine::compiler::compile_file;
+    use virtual_stack_machine::engine::DecodedCode;
+    use virtual_stack_machine::vsm::{ExecutionLimits, RuntimeError, SharedOutput, TraceType, Vsm, VsmError};
+
+    // 実行結果, 出力, 実行した命令数
+    type Outcome = (Result<i32, RuntimeError>, String, u64);
+
+    fn exec(code: &Code, input: &str, stack_size: usize, limits: ExecutionLimits, fast: bool) -> Outcome {
+        let output = SharedOutput::new();
+        let mut vsm = Vsm::with_io(
+            TraceType::No,
+            Box::new(Cursor::new(input.to_string())),
+            Box::new(output.clone()),
+        );
+        vsm.allocation_stack(stack_size);
+        vsm.set_limits(limits);
+        vsm.load_code(code.clone());
+        let result = if fast { vsm.exec_code_fast() } else { vsm.exec_code() };
+        (result, output.contents(), vsm.executed_instructions())
+    }
+
+    // 高速な実行とインタプリタの結果が一致することを確かめて, 高速な実行の結果を返す
+    fn exec_both(code: &Code, input: &str, stack_size: usize, limits: ExecutionLimits) -> Outcome {
+        let fast = exec(code, input, stack_size, limits, true);
+        assert_eq!(fast, exec(code, input, stack_size, limits, false));
+        fast
+    }
+
+    fn assemble(source: &str) -> Code {
+        let mut code = Code::new();
+        code.parse(source, "engine.vsm").unwrap();
+        code
+    }
+
+    #[test]
+    fn test_engine_sample_programs() {
+        let files = [
+            ("tests/vsm/add.vsm", ""),
+            ("tests/vsm/average.vsm", "3\n8\n"),
+            ("tests/vsm/exam.vsm", "90\n"),
+            ("tests/vsm/fact.vsm", ""),
+            ("tests/vsm/full.vsm", ""),
+            ("tests/vsm/matrix.vsm", ""),
+            ("tests/vsm/ssort.vmc", ""),
+            ("tests/vsm/while.vsm", ""),
+        ];
+        for (file_path, input) in files {
+            let mut code = Code::new();
+            code.read(file_path).unwrap();
+            let (result, _, _) = exec_both(&code, input, 1024, ExecutionLimits::default());
+            assert!(result.is_ok(), "{}", file_path);
+        }
+
+        let code = compile_file("tests/c/ssort.c").unwrap();
+        let (result, output, _) = exec_both(&code, "5\n3\n-2\n8\n0\n7\n1\n4\n", 1024, ExecutionLimits::default());
+        assert_eq!(result, Ok(6));
+        assert_eq!(output, "-2 0 1 3 4 5 7 8\n");
+    }
+
+    #[test]
+    fn test_engine_superinstructions() {
+        let code = assemble("LV 0 0\nLC 1\nADD\nLV 0 1\nSUB\nLC 2\nLT\nBZ 0\nEXIT\n");
+        assert_eq!(DecodedCode::new(&code).len(), 9);
+        assert_eq!(DecodedCode::new(&code).superinstructions(), 4);
+
+        // まとめた命令の 2 つ目へ分岐しても 1 命令ずつ実行される
+        let code = assemble("LC 5\nLC 1\nB add\nLC 9\nadd: ADD\nEXIT\n");
+        let (result, _, executed) = exec_both(&code, "", 1024, ExecutionLimits::default());
+        assert_eq!(result, Ok(6));
+        assert_eq!(executed, 5);
+
+        let code = assemble("LC 5\nloop: LC 1\nADD\nDUP\nLC 9\nLT\nBZ done\nB loop\ndone: EXIT\n");
+        let (result, _, executed) = exec_both(&code, "", 1024, ExecutionLimits::default());
+        assert_eq!(result, Ok(9));
+        assert_eq!(executed, 29);
+    }
+
+    #[test]
+    fn test_engine_runtime_errors() {
+        let sources = [
+            "LC 1\nADD\nEXIT\n",
+            "LC 1\nLC 0\nDIV\nEXIT\n",
+            "LC 7\nLC 0\nMOD\nEXIT\n",
+            "LC 1\nLV 0 5000\nADD\nEXIT\n",
+            "LV 2 0\nEXIT\n",
+            "LC 5\nSV 0 2000\nEXIT\n",
+            "LC 5\nSB 3\nEXIT\n",
+            "LC 5000\nLI\nEXIT\n",
+            "B -5\n",
+            "ISP 2000\nEXIT\n",
+            "f: LC 1\nCALL f\n",
+            "LC 1\n",
+        ];
+        for source in sources {
+            let code = assemble(source);
+            let (result, _, _) = exec_both(&code, "", 1024, ExecutionLimits::default());
+            assert!(result.is_err(), "{}", source);
+        }
+
+        // LC の時点でスタックが溢れる
+        let code = assemble("LC 1\nLC 2\nLC 3\nADD\nEXIT\n");
+        let (result, _, _) = exec_both(&code, "", 2, ExecutionLimits::default());
+        assert_eq!(result.err().unwrap().error, VsmError::StackOverflow);
+    }
+
+    #[test]
+    fn test_engine_limits() {
+        let code = assemble("loop: LC 1\nLC 2\nADD\nISP -1\nB loop\n");
+        let limits = ExecutionLimits {
+            max_instructions: Some(1001),
+            ..ExecutionLimits::default()
+        };
+        let (result, _, executed) = exec_both(&code, "", 1024, limits);
+        let err = result.err().unwrap();
+        assert_eq!(err.error, VsmError::InstructionLimitExceeded(1001));
+        assert_eq!(executed, 1001);
+
+        let code = assemble("loop: LC 1\nLC 2\nADD\nB loop\n");
+        let limits = ExecutionLimits {
+            max_stack_depth: Some(10),
+            ..ExecutionLimits::default()
+        };
+        let (result, _, _) = exec_both(&code, "", 1024, limits);
+        assert_eq!(result.err().unwrap().error, VsmError::StackLimitExceeded(10));
+    }
+}
@@ -0,0 +1,176 @@
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use virtual_stack_machine::code::Code;
+    use virtual_stack_machine::compiler::compile_file;
+    use virtual_stack_machine::debugger::Debugger;
+    use virtual_stack_machine::heap::MAX_FREED_BLOCKS;
+    use virtual_stack_machine::snapshot::Snapshot;
+    use virtual_stack_machine::vsm::{SharedOutput, TraceType, Vsm, VsmError};
+
+    fn vsm_for_test(code: Code, input: &str) -> (Vsm, SharedOutput) {
+        let output = SharedOutput::new();
+        let mut vsm = Vsm::with_io(
+            TraceType::No,
+            Box::new(Cursor::new(input.to_string())),
+            Box::new(output.clone()),
+        );
+        vsm.load_code(code);
+        (vsm, output)
+    }
+
+    fn parse(source: &str) -> Code {
+        let mut code = Code::new();
+        code.parse(source, "heap.vsm").unwrap();
+        code
+    }
+
+    #[test]
+    fn test_heap_instructions() {
+        // 2 セル確保して 7, 9 を書き込み, 読み出した和を返す
+        let source = "ISP 1\nLC 2\nALLOC\nSV 0 0\nLV 0 0\nLC 7\nSH\nLV 0 0\nLC 1\nADD\nLC 9\nSH\n\
+                      LV 0 0\nLH\nLV 0 0\nLC 1\nADD\nLH\nADD\nLV 0 0\nFREE\nEXIT\n";
+        let (mut vsm, _) = vsm_for_test(parse(source), "");
+        assert_eq!(vsm.exec_code(), Ok(16));
+        assert_eq!(vsm.heap().used(), 0);
+        assert!(vsm.heap().leaks().is_empty());
+
+        // 高速な実行でも同じ結果になる
+        let (mut vsm, _) = vsm_for_test(parse(source), "");
+        assert_eq!(vsm.exec_code_fast(), Ok(16));
+
+        // NULL の解放は何もしない
+        let (mut vsm, _) = vsm_for_test(parse("LC 0\nFREE\nLC 3\nEXIT\n"), "");
+        assert_eq!(vsm.exec_code(), Ok(3));
+    }
+
+    #[test]
+    fn test_heap_errors() {
+        let cases = [
+            ("LC 2\nALLOC\nDUP\nFREE\nLH\nEXIT\n", VsmError::UseAfterFree(1), 4),
+            ("LC 2\nALLOC\nLC 2\nADD\nLC 7\nSH\nEXIT\n", VsmError::HeapOutOfBounds(3), 5),
+            ("LC 2\nALLOC\nLC 1\nSUB\nLH\nEXIT\n", VsmError::HeapOutOfBounds(0), 4),
+            ("LC 2\nALLOC\nDUP\nFREE\nFREE\nEXIT\n", VsmError::DoubleFree(1), 4),
+            ("LC 2\nALLOC\nLC 1\nADD\nFREE\nEXIT\n", VsmError::InvalidFree(2), 4),
+            ("LC -1\nALLOC\nEXIT\n", VsmError::InvalidAllocationSize(-1), 1),
+            ("LC 5\nLH\nEXIT\n", VsmError::HeapOutOfBounds(5), 1),
+            ("LC 600\nALLOC\nLC 600\nALLOC\nEXIT\n", VsmError::HeapExhausted(1000), 3),
+        ];
+        for (source, error, program_counter) in cases {
+            for fast in [false, true] {
+                let (mut vsm, _) = vsm_for_test(parse(source), "");
+                vsm.set_max_heap_size(1000);
+                let result = if fast { vsm.exec_code_fast() } else { vsm.exec_code() };
+                let err = result.unwrap_err();
+                assert_eq!(err.error, error, "{}", source);
+                assert_eq!(err.program_counter, program_counter, "{}", source);
+            }
+        }
+
+        // 解放すれば上限まで確保し直せる
+        let (mut vsm, _) = vsm_for_test(parse("LC 600\nALLOC\nFREE\nLC 600\nALLOC\nEXIT\n"), "");
+        vsm.set_max_heap_size(1000);
+        assert_eq!(vsm.exec_code(), Ok(602));
+
+        // アドレスは再利用しないので, 使っているセルが少なくてもアドレスが尽きることがある
+        let (mut vsm, _) = vsm_for_test(parse("LC 2147483647\nALLOC\nEXIT\n"), "");
+        vsm.set_max_heap_size(usize::MAX);
+        assert_eq!(vsm.exec_code().unwrap_err().error, VsmError::HeapAddressSpaceExhausted);
+    }
+
+    #[test]
+    fn test_heap_forgets_old_freed_blocks() {
+        // MAX_FREED_BLOCKS + 1 回 1 セルを確保してすぐ解放する
+        let source = format!(
+            "ISP 1\nLC {}\nSV 0 0\nloop: LV 0 0\nBZ done\nLC 1\nALLOC\nFREE\nLV 0 0\nLC 1\nSUB\nSV 0 0\nB loop\ndone: LC 0\nEXIT\n",
+            MAX_FREED_BLOCKS + 1
+        );
+        let (mut vsm, _) = vsm_for_test(parse(&source), "");
+        vsm.enable_history(100);
+        assert_eq!(vsm.exec_code(), Ok(0));
+        assert_eq!(vsm.heap().blocks().count(), MAX_FREED_BLOCKS);
+        assert_eq!(vsm.heap().used(), 0);
+        let last = 2 * MAX_FREED_BLOCKS as i32 + 1;
+        assert_eq!(vsm.heap().read(last), Err(VsmError::UseAfterFree(last)));
+        // 最初に解放したブロックは忘れたので, 範囲外のアクセスになる
+        assert_eq!(vsm.heap().read(1), Err(VsmError::HeapOutOfBounds(1)));
+
+        // 最後の FREE を取り消すと, 忘れたブロックも戻る
+        for _ in 0..10 {
+            assert!(vsm.step_back());
+        }
+        assert_eq!(vsm.heap().blocks().next().unwrap().address, 1);
+        assert_eq!(vsm.heap().blocks().count(), MAX_FREED_BLOCKS + 1);
+        assert_eq!(vsm.heap().read(last), Ok(0));
+    }
+
+    #[test]
+    fn test_heap_compiled_list() {
+        let code = compile_file("tests/c/list.c").unwrap();
+        let (mut vsm, output) = vsm_for_test(code, "3\n1\n4\n0\n");
+        assert_eq!(vsm.exec_code(), Ok(0));
+        assert_eq!(output.contents(), "4\n1\n3\n");
+        assert_eq!(vsm.heap().blocks().count(), 3);
+        assert_eq!(vsm.heap().leak_report(vsm.code()), "");
+    }
+
+    #[test]
+    fn test_heap_leak_report() {
+        let (mut vsm, _) = vsm_for_test(parse("LC 3\nALLOC\nLC 1\nALLOC\nFREE\nLC 2\nALLOC\nEXIT\n"), "");
+        assert_eq!(vsm.exec_code(), Ok(7));
+        let leaks = vsm.heap().leaks();
+        assert_eq!(leaks.iter().map(|block| (block.address, block.size)).collect::<Vec<_>>(), vec![(1, 3), (7, 2)]);
+        assert_eq!(
+            vsm.heap().leak_report(vsm.code()),
+            "leak: 2 heap block(s) (5 cells) not freed at EXIT\n\
+             \x20 address 1: 3 cells allocated at 0001 'ALLOC'  // heap.vsm:2: ALLOC\n\
+             \x20 address 7: 2 cells allocated at 0006 'ALLOC'  // heap.vsm:7: ALLOC\n"
+        );
+    }
+
+    #[test]
+    fn test_heap_step_back_and_snapshot() {
+        let source = "LC 2\nALLOC\nDUP\nLC 5\nSH\nDUP\nFREE\nEXIT\n";
+        let (mut vsm, _) = vsm_for_test(parse(source), "");
+        vsm.enable_history(100);
+        assert_eq!(vsm.run_until(5), virtual_stack_machine::vsm::StepStatus::Continue);
+        let snapshot = vsm.snapshot();
+        assert_eq!(vsm.exec_code(), Ok(1));
+        assert!(vsm.heap().blocks().next().unwrap().is_freed());
+
+        // EXIT と FREE, DUP と SH, LC と DUP と ALLOC を順に取り消す
+        assert!(vsm.step_back() && vsm.step_back());
+        assert_eq!(vsm.heap().leaks()[0].values, vec![5, 0]);
+        assert!(vsm.step_back() && vsm.step_back());
+        assert_eq!(vsm.heap().leaks()[0].values, vec![0, 0]);
+        assert!(vsm.step_back() && vsm.step_back() && vsm.step_back());
+        assert_eq!(vsm.heap().blocks().count(), 0);
+        assert_eq!(vsm.heap().next_address(), 1);
+        assert_eq!(vsm.exec_code(), Ok(1));
+
+        // スナップショットにはヒープも含まれる
+        let restored = Snapshot::from_bytes(&snapshot.to_bytes()).unwrap();
+        assert_eq!(restored.heap, snapshot.heap);
+        let (mut vsm, _) = vsm_for_test(Code::new(), "");
+        vsm.restore(&restored);
+        assert_eq!(vsm.heap().leaks()[0].values, vec![5, 0]);
+        assert_eq!(vsm.exec_code(), Ok(1));
+        assert_eq!(vsm.heap().used(), 0);
+    }
+
+    #[test]
+    fn test_heap_debugger() {
+        let (mut vsm, _) = vsm_for_test(parse("LC 3\nALLOC\nDUP\nLC 4\nSH\nEXIT\n"), "");
+        let debugger_output = SharedOutput::new();
+        let mut debugger = Debugger::new(
+            &mut vsm,
+            Box::new(Cursor::new("step 5\nheap\ncontinue\n".to_string())),
+            Box::new(debugger_output.clone()),
+        );
+        assert_eq!(debugger.run().unwrap(), Ok(Some(1)));
+        let debugger_output = debugger_output.contents();
+        assert!(debugger_output.contains("heap: 1 block(s), 3 of 1048576 cells used\n  address 1 (3 cells, allocated at 0001): 4 0 0\n"), "{}", debugger_output);
+        assert!(debugger_output.contains("the program exited with code 1\nleak: 1 heap block(s) (3 cells) not freed at EXIT\n"));
+    }
+}
@@ -0,0 +1,82 @@
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use virtual_stack_machine::code::{Code, OperationCode};
+    use virtual_stack_machine::vsm::{SharedOutput, StepStatus, TraceType, Vsm, VsmError};
+
+    fn vsm_for_test(source: &str, input: &str) -> Vsm {
+        let mut vsm = Vsm::with_io(
+            TraceType::No,
+            Box::new(Cursor::new(input.to_string())),
+            Box::new(SharedOutput::new()),
+        );
+        let mut code = Code::new();
+        code.parse(source, "history.vsm").unwrap();
+        vsm.load_code(code);
+        vsm
+    }
+
+    #[test]
+    fn test_step_back() {
+        let mut vsm = vsm_for_test("LC 5\nCALL f\nEXIT\nf: LC 7\nSV 1 0\nRET\n", "");
+        assert!(!vsm.step_back());
+        vsm.enable_history(100);
+        assert_eq!(vsm.exec_code(), Ok(7));
+        assert_eq!(vsm.history().unwrap().len(), 6);
+        let stack = vsm.stack()[..4].to_vec();
+        assert_eq!(stack, vec![5, 7, 0, 2]);
+
+        // CALL の直後まで戻るとスタックとレジスタが元に戻る
+        for _ in 0..4 {
+            assert!(vsm.step_back());
+        }
+        assert_eq!(vsm.program_counter(), 3);
+        assert_eq!(vsm.frame_top_address(), 1);
+        assert_eq!(vsm.stack_pointer(), Some(0));
+        assert_eq!(&vsm.stack()[..4], &[5, 0, 0, 2]);
+        assert_eq!(vsm.executed_instructions(), 2);
+        assert_eq!(vsm.exit_code(), None);
+
+        let last_write = vsm.history().unwrap().last_write(3).unwrap();
+        assert_eq!(last_write.steps_ago, 1);
+        assert_eq!(last_write.program_counter, 1);
+        assert_eq!(last_write.instruction.operation_code, OperationCode::Call);
+        assert_eq!((last_write.old_value, last_write.new_value), (0, 2));
+        assert!(vsm.history().unwrap().last_write(1).is_none());
+
+        // もう一度実行すると同じ結果になる
+        assert_eq!(vsm.exec_code(), Ok(7));
+        assert_eq!(&vsm.stack()[..4], stack.as_slice());
+    }
+
+    #[test]
+    fn test_step_back_input_and_errors() {
+        let mut vsm = vsm_for_test("GETI\nGETI\nDIV\nEXIT\n", "6\n0\n");
+        vsm.enable_history(100);
+        assert_eq!(vsm.exec_code().unwrap_err().error, VsmError::DivisionByZero);
+
+        // エラーになった命令と入力も取り消せる
+        assert!(vsm.step_back());
+        assert!(vsm.step_back());
+        assert_eq!(vsm.program_counter(), 1);
+        assert_eq!(vsm.stack_pointer(), Some(0));
+        assert_eq!(vsm.step(), StepStatus::Continue);
+        assert_eq!(vsm.stack()[1], 0);
+    }
+
+    #[test]
+    fn test_history_capacity() {
+        let mut vsm = vsm_for_test("LC 1\nLC 2\nLC 3\nLC 4\nEXIT\n", "");
+        vsm.enable_history(2);
+        assert_eq!(vsm.exec_code(), Ok(4));
+        assert_eq!(vsm.history().unwrap().len(), 2);
+        assert!(vsm.step_back());
+        assert!(vsm.step_back());
+        assert!(!vsm.step_back());
+        assert_eq!(vsm.program_counter(), 3);
+
+        vsm.enable_history(0);
+        assert!(vsm.history().is_none());
+    }
+}
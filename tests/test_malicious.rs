@@ -0,0 +1,150 @@
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use virtual_stack_machine::bytecode::OPERATION_CODES;
+    use virtual_stack_machine::code::{Code, OperationCode};
+    use virtual_stack_machine::vsm::{ArithmeticMode, ExecutionLimits, RuntimeError, SharedOutput, TraceType, Vsm, VsmError};
+
+    fn exec_for_test(code: Code, arithmetic_mode: ArithmeticMode, fast: bool) -> (Result<i32, RuntimeError>, String) {
+        let output = SharedOutput::new();
+        let mut vsm = Vsm::with_io(TraceType::No, Box::new(Cursor::new(String::new())), Box::new(output.clone()));
+        vsm.load_code(code);
+        vsm.set_stack_size(16, 256);
+        vsm.set_arithmetic_mode(arithmetic_mode);
+        vsm.set_limits(ExecutionLimits { max_instructions: Some(1000), ..Default::default() });
+        let result = if fast { vsm.exec_code_fast() } else { vsm.exec_code() };
+        (result, output.contents())
+    }
+
+    // インタプリタと高速な実行で同じエラーになることも確かめる
+    fn exec_source(source: &str) -> Result<i32, RuntimeError> {
+        let mut code = Code::new();
+        code.parse(source, "malicious.vsm").unwrap();
+        let (result, _) = exec_for_test(code.clone(), ArithmeticMode::Trap, false);
+        let (fast_result, _) = exec_for_test(code, ArithmeticMode::Trap, true);
+        assert_eq!(result, fast_result, "{}", source);
+        result
+    }
+
+    fn error_of(source: &str) -> (VsmError, usize) {
+        let err = exec_source(source).unwrap_err();
+        (err.error, err.program_counter)
+    }
+
+    #[test]
+    fn test_malicious_addresses() {
+        let cases = [
+            ("LA 0 -1\nEXIT\n", VsmError::InvalidAddress(-1), 0),
+            ("LA 0 -2147483648\nEXIT\n", VsmError::InvalidAddress(-2147483648), 0),
+            ("LV 0 -1\nEXIT\n", VsmError::InvalidAddress(-1), 0),
+            ("LC 5\nSB 0\nLV 0 -6\nEXIT\n", VsmError::InvalidAddress(-1), 2),
+            ("LC -1\nLI\nEXIT\n", VsmError::InvalidAddress(-1), 1),
+            ("LC -3\nLC 7\nSI\nEXIT\n", VsmError::InvalidAddress(-3), 2),
+            ("LC 7\nSV 0 -1\nEXIT\n", VsmError::InvalidAddress(-1), 1),
+            ("LC 7\nSV 1 -2147483648\nEXIT\n", VsmError::InvalidAddress(-2147483648), 1),
+            ("LC 2147483647\nSB 0\nLC 1\nSV 0 1\nEXIT\n", VsmError::InvalidAddress(2147483648), 3),
+            ("LC 2147483647\nSB 1\nLA 1 1\nEXIT\n", VsmError::InvalidAddress(2147483648), 2),
+            ("LC -1\nSB 0\nEXIT\n", VsmError::InvalidAddress(-1), 1),
+            ("LC -2147483648\nSB 1\nEXIT\n", VsmError::InvalidAddress(-2147483648), 1),
+            ("LV 0 2147483647\nEXIT\n", VsmError::OutOfBoundsRead { address: 2147483647 }, 0),
+            ("LC 2147483647\nLI\nEXIT\n", VsmError::OutOfBoundsRead { address: 2147483647 }, 1),
+            ("LC 256\nLC 1\nSI\nEXIT\n", VsmError::OutOfBoundsWrite { address: 256 }, 2),
+            ("LV 2 0\nEXIT\n", VsmError::InvalidBaseRegister(2), 0),
+            ("LV -1 0\nEXIT\n", VsmError::InvalidBaseRegister(-1), 0),
+        ];
+        for (source, error, program_counter) in cases {
+            assert_eq!(error_of(source), (error, program_counter), "{}", source);
+        }
+    }
+
+    #[test]
+    fn test_malicious_control_flow() {
+        let cases = [
+            ("B -5\n", VsmError::InvalidBranchTarget(-4), 0),
+            ("LC 0\nBZ -10\n", VsmError::InvalidBranchTarget(-8), 1),
+            ("B -2147483648\n", VsmError::InvalidBranchTarget(-2147483647), 0),
+            ("CALL -1\n", VsmError::InvalidBranchTarget(-1), 0),
+            ("B 2147483647\n", VsmError::PcOutOfRange, 2147483648),
+            // RET は M[B1+1] を B1 に, M[B1+2] を PC にする
+            ("LC 0\nLC 0\nLC -5\nRET\n", VsmError::InvalidBranchTarget(-5), 3),
+            ("LC 0\nLC -2\nLC 0\nRET\n", VsmError::InvalidAddress(-2), 3),
+            ("LC 300\nSB 1\nRET\n", VsmError::OutOfBoundsRead { address: 301 }, 2),
+            ("ISP 3\nCALL 0\n", VsmError::StackOverflow, 1),
+        ];
+        for (source, error, program_counter) in cases {
+            assert_eq!(error_of(source), (error, program_counter), "{}", source);
+        }
+    }
+
+    #[test]
+    fn test_malicious_stack_and_values() {
+        let cases = [
+            ("ISP -2\nEXIT\n", VsmError::StackUnderflow, 0),
+            ("ISP 2147483647\nEXIT\n", VsmError::StackOverflow, 0),
+            ("ISP -2147483648\nEXIT\n", VsmError::StackUnderflow, 0),
+            ("LC 1\nISP 256\nEXIT\n", VsmError::StackOverflow, 1),
+            ("SI\nEXIT\n", VsmError::StackUnderflow, 0),
+            ("DUP\nEXIT\n", VsmError::StackUnderflow, 0),
+            ("LC -1\nPUTC\nEXIT\n", VsmError::InvalidCharacter(-1), 1),
+            ("LC 1114112\nPUTC\nEXIT\n", VsmError::InvalidCharacter(1114112), 1),
+            ("LC -2147483648\nLC -1\nDIV\nEXIT\n", VsmError::IntegerOverflow, 2),
+            ("LC -2147483648\nINV\nEXIT\n", VsmError::IntegerOverflow, 1),
+            ("GETI\nEXIT\n", VsmError::InputError("unexpected end of input".to_string()), 0),
+        ];
+        for (source, error, program_counter) in cases {
+            assert_eq!(error_of(source), (error, program_counter), "{}", source);
+        }
+        assert_eq!(exec_source("EXIT\n"), Ok(1));
+    }
+
+    // 線形合同法による擬似乱数. テストを再現できるように種は固定する
+    struct Random(u64);
+
+    impl Random {
+        fn next(&mut self) -> u64 {
+            self.0 = self.0.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            self.0 >> 33
+        }
+
+        fn below(&mut self, n: usize) -> usize {
+            (self.next() % n as u64) as usize
+        }
+
+        fn operand(&mut self) -> i32 {
+            const EDGES: [i32; 10] = [0, 1, -1, 2, 3, -3, 255, 256, i32::MIN, i32::MAX];
+            match self.below(3) {
+                0 => EDGES[self.below(EDGES.len())],
+                1 => self.below(20) as i32 - 10,
+                _ => self.next() as i32,
+            }
+        }
+    }
+
+    #[test]
+    fn test_malicious_random_programs() {
+        let mut random = Random(42);
+        for _ in 0..300 {
+            let mut code = Code::new();
+            let length = 1 + random.below(24);
+            for _ in 0..length {
+                let operation_code = OPERATION_CODES[random.below(OPERATION_CODES.len())];
+                let operands = match code.operand_size(operation_code) {
+                    0 => [None, None],
+                    1 => [Some(random.operand()), None],
+                    _ => [Some(random.below(3) as i32), Some(random.operand())],
+                };
+                // 早く終わるプログラムも混ぜる
+                let operation_code = if random.below(50) == 0 { OperationCode::Exit } else { operation_code };
+                code.append_instruction(operation_code, operands[0], operands[1]);
+            }
+
+            // パニックせず, どのモードでもインタプリタと高速な実行が一致する
+            for arithmetic_mode in [ArithmeticMode::Trap, ArithmeticMode::Wrapping, ArithmeticMode::Saturating] {
+                let expected = exec_for_test(code.clone(), arithmetic_mode, false);
+                let actual = exec_for_test(code.clone(), arithmetic_mode, true);
+                assert_eq!(actual, expected, "{:?}", code.instructions());
+            }
+        }
+    }
+}
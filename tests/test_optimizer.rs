@@ -0,0 +1,167 @@
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use virtual_stack_machine::code::Code;
+    use virtual_stack_machine::compiler::{compile, compile_file};
+    use virtual_stack_machine::disasm::disassemble;
+    use virtual_stack_machine::optimizer::{optimize, Rule};
+    use virtual_stack_machine::vsm::{RuntimeError, SharedOutput, TraceType, Vsm};
+
+    fn exec_code_with_input(code: Code, input: &str) -> (Result<i32, RuntimeError>, String) {
+        let output = SharedOutput::new();
+        let mut vsm = Vsm::with_io(
+            TraceType::No,
+            Box::new(Cursor::new(input.to_string())),
+            Box::new(output.clone()),
+        );
+        vsm.load_code(code);
+        let result = vsm.exec_code();
+        (result, output.contents())
+    }
+
+    fn optimize_source(source: &str) -> (Code, Code) {
+        let mut code = Code::new();
+        code.parse(source, "optimize.vsm").unwrap();
+        let (optimized, _) = optimize(&code);
+        (code, optimized)
+    }
+
+    fn assemble(source: &str) -> Code {
+        let mut code = Code::new();
+        code.parse(source, "expected.vsm").unwrap();
+        code
+    }
+
+    #[test]
+    fn test_optimize_preserves_behavior() {
+        let files = [
+            ("tests/vsm/add.vsm", ""),
+            ("tests/vsm/average.vsm", "3\n8\n"),
+            ("tests/vsm/exam.vsm", "90\n"),
+            ("tests/vsm/fact.vsm", ""),
+            ("tests/vsm/full.vsm", ""),
+            ("tests/vsm/matrix.vsm", ""),
+            ("tests/vsm/ssort.vmc", ""),
+            ("tests/vsm/while.vsm", ""),
+        ];
+        for (file_path, input) in files {
+            let mut code = Code::new();
+            code.read(file_path).unwrap();
+            let (optimized, report) = optimize(&code);
+            assert_eq!(report.after, optimized.len());
+            assert_eq!(
+                exec_code_with_input(optimized, input),
+                exec_code_with_input(code, input),
+                "{}",
+                file_path
+            );
+        }
+
+        let code = compile_file("tests/c/ssort.c").unwrap();
+        let input = "5\n3\n-2\n8\n0\n7\n1\n4\n";
+        let (optimized, _) = optimize(&code);
+        assert_eq!(exec_code_with_input(optimized, input), exec_code_with_input(code, input));
+    }
+
+    #[test]
+    fn test_optimize_matrix() {
+        let mut code = Code::new();
+        code.read("tests/vsm/matrix.vsm").unwrap();
+        let (_, report) = optimize(&code);
+        assert_eq!(report.before, 455);
+        assert_eq!(report.removed(), 135);
+        assert!(report.applied[&Rule::ConstantFolding] > 0);
+        assert!(report.applied[&Rule::AlgebraicIdentity] > 0);
+        assert!(report.applied[&Rule::RedundantStackOperation] > 0);
+    }
+
+    #[test]
+    fn test_optimize_constant_folding() {
+        let (_, optimized) = optimize_source("LC 2\nLC 3\nMUL\nLC 4\nADD\nINV\nEXIT\n");
+        assert_eq!(optimized.instructions(), assemble("LC -10\nEXIT\n").instructions());
+        // 畳み込んだ命令は元の最初の命令の行を指す
+        assert_eq!(optimized.source_location(0).unwrap().text, "LC 2");
+        assert_eq!(optimized.source_location(1).unwrap().line, 7);
+
+        // 実行時エラーになる演算は畳み込まない
+        for source in ["LC 1\nLC 0\nDIV\nEXIT\n", "LC 2147483647\nLC 1\nADD\nEXIT\n"] {
+            let (code, optimized) = optimize_source(source);
+            assert_eq!(optimized.instructions(), code.instructions());
+        }
+    }
+
+    #[test]
+    fn test_optimize_identities_and_stack_operations() {
+        let source = "GETI\nLC 1\nMUL\nLC 0\nADD\nDUP\nPUTI\nISP -1\nLC 5\nISP 2\nISP -3\nISP 0\nEXIT\n";
+        let (_, optimized) = optimize_source(source);
+        // SP より上に残った値は CALL のフレームや LV 1 n から読めるので, ISP の前の LC は消さない
+        assert_eq!(optimized.instructions(), assemble("GETI\nPUTI\nLC 5\nISP -1\nEXIT\n").instructions());
+    }
+
+    #[test]
+    fn test_optimize_keeps_call_arguments() {
+        let code = compile("int f(int a) { return a; }\nint main() { return f(5); }\n", "call.c").unwrap();
+        let (optimized, _) = optimize(&code);
+        assert_eq!(exec_code_with_input(code, "").0, Ok(5));
+        assert_eq!(exec_code_with_input(optimized, "").0, Ok(5));
+    }
+
+    #[test]
+    fn test_optimize_respects_branch_targets() {
+        // LC 3 は分岐先なので LC 2 LC 3 ADD を畳み込めない
+        let source = "GETI\nBZ three\nLC 2\nthree: LC 3\nADD\nEXIT\n";
+        let (code, optimized) = optimize_source(source);
+        assert_eq!(optimized.instructions(), code.instructions());
+    }
+
+    #[test]
+    fn test_optimize_remaps_targets() {
+        let source = "LC 0\nLC 0\nADD\nBZ skip\nLC 1\nLC 1\nMUL\nPUTI\nskip: CALL f\nEXIT\nf: LC 1\nLC 0\nADD\nRET\n";
+        let (code, optimized) = optimize_source(source);
+        let expected = "LC 0\nBZ skip\nLC 1\nPUTI\nskip: CALL f\nEXIT\nf: LC 1\nRET\n";
+        assert_eq!(optimized.instructions(), assemble(expected).instructions());
+        assert_eq!(code.label_address("f"), Some(10));
+        assert_eq!(optimized.label_address("f"), Some(6));
+        assert_eq!(optimized.label_address("skip"), Some(4));
+        assert_eq!(optimized.line_number(6), Some(11));
+    }
+
+    #[test]
+    fn test_optimize_jump_threading() {
+        let source = "GETI\nBZ a\nB b\na: B b\nLC 1\nb: B next\nnext: EXIT\n";
+        let (code, optimized) = optimize_source(source);
+        assert_eq!(
+            disassemble(&optimized),
+            [
+                "        GETI                    // 0000",
+                "        BZ b                    // 0001 -> 0005",
+                "        B b                     // 0002 -> 0005",
+                "a:",
+                "        B b                     // 0003 -> 0005",
+                "        LC 1                    // 0004",
+                "b:",
+                "next:",
+                "        EXIT                    // 0005",
+                "",
+            ]
+            .join("\n")
+        );
+        assert_eq!(exec_code_with_input(optimized, "0\n"), exec_code_with_input(code, "0\n"));
+
+        // B だけの無限ループは辿り続けない (次の命令への B だけが消える)
+        let (_, optimized) = optimize_source("a: B b\nb: B a\nEXIT\n");
+        assert_eq!(optimized.instructions(), assemble("a: B a\nEXIT\n").instructions());
+    }
+
+    #[test]
+    fn test_optimize_report() {
+        let mut code = Code::new();
+        code.parse("LC 1\nLC 2\nADD\nLC 1\nMUL\nISP 1\nISP -1\nEXIT\n", "report.vsm").unwrap();
+        let (_, report) = optimize(&code);
+        assert_eq!(
+            report.to_string(),
+            "optimized 8 -> 2 instructions (6 removed)\n  constant folding: 1\n  algebraic identity: 1\n  redundant DUP/ISP: 1\n  ISP merging: 1"
+        );
+    }
+}
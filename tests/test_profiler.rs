@@ -0,0 +1,136 @@
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use virtual_stack_machine::code::Code;
+    use virtual_stack_machine::compiler::compile_file;
+    use virtual_stack_machine::profiler::{FunctionProfile, Profile, ProfileFormat};
+    use virtual_stack_machine::vsm::{SharedOutput, TraceType, Vsm, VsmError};
+
+    fn profile_code(code: Code, input: &str, fast: bool) -> (Vsm, Profile) {
+        let mut vsm = Vsm::with_io(
+            TraceType::No,
+            Box::new(Cursor::new(input.to_string())),
+            Box::new(SharedOutput::new()),
+        );
+        vsm.load_code(code);
+        vsm.enable_profiling();
+        let _ = if fast { vsm.exec_code_fast() } else { vsm.exec_code() };
+        let profile = vsm.profile().unwrap();
+        (vsm, profile)
+    }
+
+    fn function<'a>(profile: &'a Profile, name: &str) -> &'a FunctionProfile {
+        profile.functions.iter().find(|function| function.name == name).unwrap()
+    }
+
+    #[test]
+    fn test_profile_compiled_program() {
+        let code = compile_file("tests/c/fact.c").unwrap();
+        let (vsm, profile) = profile_code(code, "", false);
+        assert_eq!(profile.total, vsm.executed_instructions());
+
+        let names = profile.functions.iter().map(|function| function.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, vec!["fact", "main", "(top level)"]);
+
+        // 再帰呼び出しは二重に数えない
+        let fact = function(&profile, "fact");
+        assert_eq!(fact.calls, 11);
+        assert_eq!(fact.inclusive, fact.exclusive);
+
+        let main = function(&profile, "main");
+        assert_eq!(main.calls, 1);
+        assert_eq!(main.inclusive, main.exclusive + fact.exclusive);
+        assert_eq!(function(&profile, "(top level)").inclusive, profile.total);
+
+        let exclusive = profile.functions.iter().map(|function| function.exclusive).sum::<u64>();
+        assert_eq!(exclusive, profile.total);
+        let counts = profile.instructions.iter().map(|instruction| instruction.count).sum::<u64>();
+        assert_eq!(counts, profile.total);
+        assert!(profile.instructions.windows(2).all(|pair| pair[0].count >= pair[1].count));
+    }
+
+    #[test]
+    fn test_profile_sample_program() {
+        let mut code = Code::new();
+        code.read("tests/vsm/ssort.vmc").unwrap();
+        let (_, profile) = profile_code(code, "", false);
+        assert_eq!(profile.total, 1463);
+        assert_eq!(profile.functions.iter().map(|function| function.exclusive).sum::<u64>(), 1463);
+
+        // 高速な実行を指定してもプロファイルはインタプリタで取る
+        let mut code = Code::new();
+        code.read("tests/vsm/ssort.vmc").unwrap();
+        let (_, fast_profile) = profile_code(code, "", true);
+        assert_eq!(fast_profile, profile);
+    }
+
+    #[test]
+    fn test_profile_unfinished_calls() {
+        // 関数の中で実行時エラーになっても, そこまでの分を inclusive に数える
+        let mut code = Code::new();
+        code.parse("LC 1\nCALL f\nEXIT\nf: LC 2\nLC 0\nDIV\nRET\n", "profile.vsm").unwrap();
+        let (vsm, profile) = profile_code(code, "", false);
+        assert_eq!(vsm.executed_instructions(), 5);
+        assert_eq!(profile.total, 5);
+        assert_eq!(
+            profile.functions,
+            vec![
+                FunctionProfile {
+                    name: "f".to_string(),
+                    address: 3,
+                    calls: 1,
+                    inclusive: 3,
+                    exclusive: 3,
+                },
+                FunctionProfile {
+                    name: "(top level)".to_string(),
+                    address: 0,
+                    calls: 0,
+                    inclusive: 5,
+                    exclusive: 2,
+                },
+            ]
+        );
+
+        let mut vsm = Vsm::new(TraceType::No);
+        assert!(vsm.profile().is_none());
+        vsm.enable_profiling();
+        assert_eq!(vsm.exec_code().err().unwrap().error, VsmError::PcOutOfRange);
+    }
+
+    #[test]
+    fn test_profile_formats() {
+        let mut code = Code::new();
+        code.parse("LC 2\nloop: LC 1\nSUB\nDUP\nBZ end\nB loop\nend: EXIT\n", "profile.vsm").unwrap();
+        let (_, profile) = profile_code(code, "", false);
+        assert_eq!(
+            profile.render(ProfileFormat::Csv),
+            [
+                "function,address,calls,inclusive,exclusive",
+                "(top level),0,0,11,11",
+                "",
+                "pc,instruction,line,function,count",
+                "1,LC 1,2,(top level),2",
+                "2,SUB,3,(top level),2",
+                "3,DUP,4,(top level),2",
+                "4,BZ 1,5,(top level),2",
+                "0,LC 2,1,(top level),1",
+                "5,B -5,6,(top level),1",
+                "6,EXIT,7,(top level),1",
+                "",
+            ]
+            .join("\n")
+        );
+
+        let json = profile.render(ProfileFormat::Json);
+        assert!(json.starts_with("{\n  \"total\": 11,\n  \"functions\": [\n"));
+        assert!(json.contains("{\"name\": \"(top level)\", \"address\": 0, \"calls\": 0, \"inclusive\": 11, \"exclusive\": 11}"));
+        assert!(json.contains("{\"pc\": 4, \"instruction\": \"BZ 1\", \"line\": 5, \"function\": \"(top level)\", \"count\": 2}"));
+
+        let text = profile.render(ProfileFormat::Text);
+        assert!(text.starts_with("profile: 11 instructions executed\n"));
+        assert_eq!(ProfileFormat::from_path("out.json"), ProfileFormat::Json);
+        assert_eq!(ProfileFormat::from_path("out.txt"), ProfileFormat::Text);
+    }
+}
@@ -0,0 +1,121 @@
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use virtual_stack_machine::bytecode::BytecodeError;
+    use virtual_stack_machine::code::Code;
+    use virtual_stack_machine::snapshot::{is_snapshot, Snapshot, SnapshotError};
+    use virtual_stack_machine::vsm::{SharedOutput, StepStatus, TraceType, Vsm};
+
+    fn vsm_for_test(source: &str, input: &str) -> (Vsm, SharedOutput) {
+        let output = SharedOutput::new();
+        let mut vsm = Vsm::with_io(
+            TraceType::No,
+            Box::new(Cursor::new(input.to_string())),
+            Box::new(output.clone()),
+        );
+        let mut code = Code::new();
+        code.parse(source, "snapshot.vsm").unwrap();
+        vsm.load_code(code);
+        (vsm, output)
+    }
+
+    const SUM: &str = "LC 0\nloop: GETI\nDUP\nBZ end\nADD\nB loop\nend: ISP -1\nDUP\nPUTI\nEXIT\n";
+
+    #[test]
+    fn test_snapshot_restore() {
+        let (mut vsm, output) = vsm_for_test(SUM, "1\n2\n3\n0\n");
+        assert_eq!(vsm.run_until(5), StepStatus::Continue);
+        let snapshot = vsm.snapshot();
+        assert_eq!(snapshot.input_position, 2);
+        assert_eq!(snapshot.stack_pointer, Some(0));
+        assert_eq!(snapshot.stack[0], 1);
+
+        assert_eq!(vsm.exec_code(), Ok(6));
+        assert_eq!(vsm.exec_code(), Ok(6));
+        let executed = vsm.executed_instructions();
+
+        // 読み込み済みの入力は読み直すので同じ結果になる
+        vsm.restore(&snapshot);
+        assert_eq!(vsm.program_counter(), 5);
+        assert_eq!(vsm.exit_code(), None);
+        assert_eq!(vsm.exec_code_fast(), Ok(6));
+        assert_eq!(vsm.executed_instructions(), executed);
+        assert_eq!(output.contents(), "66");
+        // 出力は取り消せないが位置は戻る
+        assert_eq!(vsm.snapshot().output_position, 1);
+    }
+
+    #[test]
+    fn test_snapshot_resume_in_new_vsm() {
+        let (mut vsm, _) = vsm_for_test(SUM, "10\n20\n0\n");
+        vsm.run_until(5);
+        vsm.run_until(5);
+        let bytes = vsm.snapshot().to_bytes();
+        assert!(is_snapshot(&bytes));
+
+        // 新しい Vsm では保存した位置まで入力を読み飛ばす
+        let snapshot = Snapshot::from_bytes(&bytes).unwrap();
+        let (mut resumed, output) = vsm_for_test("EXIT\n", "10\n20\n0\n");
+        resumed.restore(&snapshot);
+        assert_eq!(resumed.code().instructions(), vsm.code().instructions());
+        assert_eq!(resumed.stack_pointer(), vsm.stack_pointer());
+        assert_eq!(resumed.stack(), vsm.stack());
+        assert_eq!(resumed.executed_instructions(), vsm.executed_instructions());
+        assert_eq!(resumed.exec_code(), Ok(30));
+        assert_eq!(output.contents(), "30");
+
+        // 終了した状態も保存できる
+        let snapshot = Snapshot::from_bytes(&resumed.snapshot().to_bytes()).unwrap();
+        assert_eq!(snapshot.exit_code, Some(30));
+        assert_eq!(snapshot.to_bytes(), resumed.snapshot().to_bytes());
+    }
+
+    #[test]
+    fn test_snapshot_errors() {
+        let (vsm, _) = vsm_for_test(SUM, "");
+        let bytes = vsm.snapshot().to_bytes();
+
+        assert_eq!(Snapshot::from_bytes(b"VSMB").err(), Some(SnapshotError::InvalidMagic));
+        let mut version = bytes.clone();
+        version[4] = 9;
+        assert_eq!(Snapshot::from_bytes(&version).err(), Some(SnapshotError::UnsupportedVersion(9)));
+        assert_eq!(
+            Snapshot::from_bytes(&bytes[..bytes.len() - 1]).err(),
+            Some(SnapshotError::Malformed(BytecodeError::UnexpectedEof))
+        );
+        assert!(matches!(Snapshot::read("tests/no_such_file.vsms"), Err(SnapshotError::Io(_))));
+    }
+
+    #[test]
+    fn test_snapshot_malformed() {
+        // スタックの大きさに 2^62 を書いたファイルでも確保しようとしない
+        let mut bytes = b"VSMS\x02\x00\x00\x00".to_vec();
+        bytes.extend_from_slice(&[0; 9]);
+        bytes.extend_from_slice(&[0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x40, 0]);
+        assert_eq!(Snapshot::from_bytes(&bytes).err(), Some(SnapshotError::InvalidStack { size: 1 << 62, count: 0 }));
+
+        let (vsm, _) = vsm_for_test(SUM, "");
+        let bytes = vsm.snapshot().to_bytes();
+        assert!(Snapshot::from_bytes(&bytes).is_ok());
+        assert_eq!(
+            Snapshot::from_bytes_with_max_stack_size(&bytes, 100).err(),
+            Some(SnapshotError::InvalidStack { size: 1024, count: 0 })
+        );
+
+        // header の後が program_counter, stack_pointer + 1
+        let mut program_counter = bytes.clone();
+        program_counter[8] = 11;
+        assert_eq!(Snapshot::from_bytes(&program_counter).err(), Some(SnapshotError::InvalidRegisters));
+        program_counter[8] = 10;
+        assert!(Snapshot::from_bytes(&program_counter).is_ok());
+
+        let (mut vsm, _) = vsm_for_test(SUM, "");
+        vsm.set_stack_size(4, 4);
+        let mut bytes = vsm.snapshot().to_bytes();
+        bytes[9] = 4;
+        assert!(Snapshot::from_bytes(&bytes).is_ok());
+        bytes[9] = 5;
+        assert_eq!(Snapshot::from_bytes(&bytes).err(), Some(SnapshotError::InvalidRegisters));
+    }
+}
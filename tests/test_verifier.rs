@@ -0,0 +1,118 @@
+#[cfg(test)]
+mod tests {
+    use virtual_stack_machine::code::Code;
+    use virtual_stack_machine::compiler::compile_file;
+    use virtual_stack_machine::verifier::{verify, Diagnostic, DiagnosticKind, Severity};
+
+    fn verify_source(source: &str) -> Vec<Diagnostic> {
+        let mut code = Code::new();
+        code.parse(source, "verify.vsm").unwrap();
+        verify(&code)
+    }
+
+    fn kinds(diagnostics: &[Diagnostic]) -> Vec<(usize, DiagnosticKind)> {
+        diagnostics
+            .iter()
+            .map(|diagnostic| (diagnostic.program_counter, diagnostic.kind.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn test_verify_sample_programs() {
+        let files = [
+            "tests/vsm/add.vsm",
+            "tests/vsm/average.vsm",
+            "tests/vsm/exam.vsm",
+            "tests/vsm/fact.vsm",
+            "tests/vsm/matrix.vsm",
+            "tests/vsm/ssort.vmc",
+            "tests/vsm/while.vsm",
+        ];
+        for file_path in files {
+            let mut code = Code::new();
+            code.read(file_path).unwrap();
+            let diagnostics = verify(&code);
+            assert!(diagnostics.iter().all(|diagnostic| !diagnostic.is_error()), "{}: {:?}", file_path, diagnostics);
+        }
+
+        // return の後ろの B と RET は実行されない
+        let mut code = Code::new();
+        code.read("tests/vsm/fact.vsm").unwrap();
+        assert_eq!(
+            kinds(&verify(&code)),
+            vec![
+                (14, DiagnosticKind::Unreachable { to: 14 }),
+                (26, DiagnosticKind::Unreachable { to: 26 }),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_verify_compiled_programs() {
+        for file_path in ["tests/c/fact.c", "tests/c/ssort.c"] {
+            let code = compile_file(file_path).unwrap();
+            assert_eq!(verify(&code), vec![], "{}", file_path);
+        }
+    }
+
+    #[test]
+    fn test_verify_stack_underflow() {
+        let diagnostics = verify_source("LC 1\nADD\nEXIT\n");
+        assert_eq!(
+            kinds(&diagnostics),
+            vec![
+                (1, DiagnosticKind::StackUnderflow { required: 2, height: 1 }),
+                (2, DiagnosticKind::Unreachable { to: 2 }),
+            ]
+        );
+        assert_eq!(diagnostics[0].severity(), Severity::Error);
+        assert_eq!(diagnostics[1].severity(), Severity::Warning);
+        assert_eq!(
+            diagnostics[0].to_string(),
+            "error: 0001 'ADD': stack underflow: needs 2 value(s) but the stack has 1 (line 2)"
+        );
+
+        let diagnostics = verify_source("ISP 2\nISP -3\nEXIT\n");
+        assert_eq!(diagnostics[0].kind, DiagnosticKind::StackUnderflow { required: 3, height: 2 });
+    }
+
+    #[test]
+    fn test_verify_inconsistent_height() {
+        let diagnostics = verify_source("LC 1\nBZ skip\nLC 2\nskip: EXIT\n");
+        assert_eq!(
+            kinds(&diagnostics),
+            vec![(3, DiagnosticKind::InconsistentHeight { first: 0, second: 1 })]
+        );
+    }
+
+    #[test]
+    fn test_verify_function_frames() {
+        // 関数の中の高さは B1 からの相対なので, 呼び出し元とは別に数える
+        let diagnostics = verify_source("ISP 3\nISP -3\nCALL f\nEXIT\nf: ISP 3\nADD\nRET\n");
+        assert_eq!(diagnostics, vec![]);
+
+        let diagnostics = verify_source("CALL f\nEXIT\nf: LC 1\nSUB\nRET\n");
+        assert_eq!(
+            kinds(&diagnostics),
+            vec![
+                (3, DiagnosticKind::StackUnderflow { required: 2, height: 1 }),
+                (4, DiagnosticKind::Unreachable { to: 4 }),
+            ]
+        );
+
+        assert_eq!(kinds(&verify_source("LC 1\nRET\n")), vec![(1, DiagnosticKind::ReturnOutsideFunction)]);
+    }
+
+    #[test]
+    fn test_verify_branch_out_of_range() {
+        let diagnostics = verify_source("LC 0\nBZ 5\nCALL 100\nLC 1\n");
+        assert_eq!(
+            kinds(&diagnostics),
+            vec![
+                (1, DiagnosticKind::BranchOutOfRange { target: 7 }),
+                (2, DiagnosticKind::BranchOutOfRange { target: 100 }),
+                (3, DiagnosticKind::FallOffEnd),
+            ]
+        );
+    }
+}
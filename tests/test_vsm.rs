@@ -0,0 +1,375 @@
+mod common;
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::Cursor;
+    use std::time::Duration;
+
+    use virtual_stack_machine::code::{Code, OperationCode};
+    use virtual_stack_machine::vsm::{ArithmeticMode, ExecutionLimits, RuntimeError, SharedOutput, StepStatus, TraceType, Vsm, VsmError};
+
+    use crate::common::write_to_file_for_test;
+
+    fn exec_for_test(file_path: &str, file_contents: &str, stack_size: usize) -> Result<i32, RuntimeError> {
+        write_to_file_for_test(file_path, file_contents).unwrap();
+        let mut vsm = Vsm::new(TraceType::No);
+        vsm.allocation_stack(stack_size);
+        vsm.read_code(file_path).unwrap();
+        fs::remove_file(file_path).unwrap();
+        vsm.exec_code()
+    }
+
+    fn exec_file_with_input(file_path: &str, input: &str) -> (Result<i32, RuntimeError>, String) {
+        let output = SharedOutput::new();
+        let mut vsm = Vsm::with_io(
+            TraceType::No,
+            Box::new(Cursor::new(input.to_string())),
+            Box::new(output.clone()),
+        );
+        vsm.read_code(file_path).unwrap();
+        let result = vsm.exec_code();
+        (result, output.contents())
+    }
+
+    #[test]
+    fn test_exec_code_ok() {
+        let result = exec_for_test("tests/exec_code_ok.txt", "LC 1\nLC 2\nADD\nEXIT\n", 1024);
+        assert_eq!(result, Ok(3));
+    }
+
+    #[test]
+    fn test_exec_code_stack_underflow() {
+        let err = exec_for_test("tests/exec_code_stack_underflow.txt", "LC 1\nADD\nEXIT\n", 1024).unwrap_err();
+        assert_eq!(err.error, VsmError::StackUnderflow);
+        assert_eq!(err.program_counter, 1);
+        assert_eq!(err.instruction.unwrap().operation_code, OperationCode::Add);
+        // 命令を実行する前のレジスタを報告する
+        assert_eq!(err.stack_pointer, Some(0));
+    }
+
+    #[test]
+    fn test_exec_code_stack_overflow() {
+        let err = exec_for_test("tests/exec_code_stack_overflow.txt", "LC 1\nLC 2\nLC 3\nEXIT\n", 2).unwrap_err();
+        assert_eq!(err.error, VsmError::StackOverflow);
+        assert_eq!(err.program_counter, 2);
+        assert_eq!(err.stack_pointer, Some(1));
+    }
+
+    #[test]
+    fn test_exec_code_division_by_zero() {
+        let err = exec_for_test("tests/exec_code_division_by_zero.txt", "LC 1\nLC 0\nDIV\nEXIT\n", 1024).unwrap_err();
+        assert_eq!(err.error, VsmError::DivisionByZero);
+        assert_eq!(err.program_counter, 2);
+        assert_eq!(err.stack_pointer, Some(1));
+
+        let err = exec_for_test("tests/exec_code_modulo_by_zero.txt", "LC 1\nLC 0\nMOD\nEXIT\n", 1024).unwrap_err();
+        assert_eq!(err.error, VsmError::DivisionByZero);
+    }
+
+    #[test]
+    fn test_exec_code_error_source() {
+        let err = exec_for_test("tests/exec_code_error_source.txt", "LC 1\nLC 0\n  DIV // boom\nEXIT\n", 1024).unwrap_err();
+        let source = err.source.as_ref().unwrap();
+        assert_eq!(source.line, 3);
+        assert_eq!(source.comment.as_deref(), Some("boom"));
+        let expected = "\
+error: division by zero at PC=2 'DIV' (SP=1, B0=0, B1=0)
+ --> tests/exec_code_error_source.txt:3:3
+  |
+3 |   DIV // boom
+  |   ^^^
+";
+        assert_eq!(err.render(), expected);
+    }
+
+    #[test]
+    fn test_exec_code_invalid_base_register() {
+        let err = exec_for_test("tests/exec_code_invalid_base_register.txt", "LV 2 0\nEXIT\n", 1024).unwrap_err();
+        assert_eq!(err.error, VsmError::InvalidBaseRegister(2));
+        assert_eq!(err.instruction.unwrap().to_string(), "LV 2 0");
+        assert_eq!(err.stack_pointer, None);
+    }
+
+    #[test]
+    fn test_exec_code_out_of_bounds() {
+        let err = exec_for_test("tests/exec_code_out_of_bounds.txt", "LV 0 2000\nEXIT\n", 1024).unwrap_err();
+        assert_eq!(err.error, VsmError::OutOfBoundsRead { address: 2000 });
+
+        let err = exec_for_test("tests/exec_code_out_of_bounds_write.txt", "LC 5\nSV 0 2000\nEXIT\n", 1024).unwrap_err();
+        assert_eq!(err.error, VsmError::OutOfBoundsWrite { address: 2000 });
+    }
+
+    #[test]
+    fn test_exec_code_pc_out_of_range() {
+        let err = exec_for_test("tests/exec_code_pc_out_of_range.txt", "LC 1\nB 5\n", 1024).unwrap_err();
+        assert_eq!(err.error, VsmError::PcOutOfRange);
+        assert_eq!(err.program_counter, 7);
+        assert_eq!(err.instruction, None);
+        assert_eq!(err.stack_pointer, Some(0));
+    }
+
+    #[test]
+    fn test_exec_code_output() {
+        let (result, output) = exec_file_with_input("tests/vsm/fact.vsm", "");
+        assert_eq!(result, Ok(0));
+        assert_eq!(output, "n=10!=3628800\n");
+
+        let (result, output) = exec_file_with_input("tests/vsm/while.vsm", "");
+        assert_eq!(result, Ok(101));
+        assert_eq!(output, "5050\n");
+    }
+
+    #[test]
+    fn test_exec_code_input() {
+        let (result, output) = exec_file_with_input("tests/vsm/average.vsm", "3\n8\n");
+        assert!(result.is_ok());
+        assert_eq!(output, "5\n");
+
+        let (result, output) = exec_file_with_input("tests/vsm/get.vsm", "42\nx\n");
+        assert!(result.is_ok());
+        assert_eq!(output, "42x");
+    }
+
+    #[test]
+    fn test_exec_code_input_error() {
+        let (result, output) = exec_file_with_input("tests/vsm/get.vsm", "42\n");
+        assert_eq!(output, "42");
+        let err = result.unwrap_err();
+        assert!(matches!(err.error, VsmError::InputError(_)));
+        assert_eq!(err.instruction.unwrap().operation_code, OperationCode::Getc);
+
+        let (result, _) = exec_file_with_input("tests/vsm/get.vsm", "abc\n");
+        assert!(matches!(result.unwrap_err().error, VsmError::InputError(_)));
+    }
+
+    fn exec_with_limits_for_test(file_path: &str, file_contents: &str, limits: ExecutionLimits) -> Result<i32, RuntimeError> {
+        write_to_file_for_test(file_path, file_contents).unwrap();
+        let mut vsm = Vsm::with_io(TraceType::No, Box::new(Cursor::new(String::new())), Box::new(SharedOutput::new()));
+        vsm.set_limits(limits);
+        vsm.read_code(file_path).unwrap();
+        fs::remove_file(file_path).unwrap();
+        vsm.exec_code()
+    }
+
+    #[test]
+    fn test_exec_code_instruction_limit() {
+        let limits = ExecutionLimits { max_instructions: Some(100), ..Default::default() };
+        let err = exec_with_limits_for_test("tests/exec_code_instruction_limit.txt", "LC 1\nB -1\n", limits).unwrap_err();
+        assert_eq!(err.error, VsmError::InstructionLimitExceeded(100));
+        assert_eq!(err.program_counter, 1);
+
+        let limits = ExecutionLimits { max_instructions: Some(3), ..Default::default() };
+        let result = exec_with_limits_for_test("tests/exec_code_instruction_limit_ok.txt", "LC 1\nLC 2\nEXIT\n", limits);
+        assert!(result.is_ok());
+    }
+
+    #[test]
+    fn test_exec_code_time_limit() {
+        let limits = ExecutionLimits { max_duration: Some(Duration::from_millis(50)), ..Default::default() };
+        let err = exec_with_limits_for_test("tests/exec_code_time_limit.txt", "B -1\n", limits).unwrap_err();
+        assert_eq!(err.error, VsmError::TimeLimitExceeded(Duration::from_millis(50)));
+    }
+
+    #[test]
+    fn test_exec_code_stack_limit() {
+        let limits = ExecutionLimits { max_stack_depth: Some(2), ..Default::default() };
+        let err = exec_with_limits_for_test("tests/exec_code_stack_limit.txt", "LC 1\nLC 2\nLC 3\nEXIT\n", limits).unwrap_err();
+        assert_eq!(err.error, VsmError::StackLimitExceeded(2));
+        assert_eq!(err.program_counter, 2);
+
+        // SP が上限の手前でも, それより上のセルへの書き込みは制限を超える
+        let cases = [("LC 1\nSV 0 5\nEXIT\n", 1), ("LC 1\nLC 9\nLC 1\nSI\nEXIT\n", 3), ("LC 0\nCALL f\nf: EXIT\n", 1)];
+        for (source, program_counter) in cases {
+            for fast in [false, true] {
+                let (mut vsm, _) = vsm_for_step(source, "");
+                vsm.set_limits(ExecutionLimits { max_stack_depth: Some(3), ..Default::default() });
+                let result = if fast { vsm.exec_code_fast() } else { vsm.exec_code() };
+                let err = result.unwrap_err();
+                assert_eq!(err.error, VsmError::StackLimitExceeded(3), "{}", source);
+                assert_eq!(err.program_counter, program_counter, "{}", source);
+                assert!(vsm.stack()[3..].iter().all(|value| *value == 0), "{}", source);
+            }
+        }
+    }
+
+    #[test]
+    fn test_exec_code_return_code() {
+        let result = exec_for_test("tests/exec_code_return_code.txt", "LC 42\nEXIT\n", 1024);
+        assert_eq!(result, Ok(42));
+
+        let result = exec_for_test("tests/exec_code_return_code_empty.txt", "EXIT\n", 1024);
+        assert_eq!(result, Ok(1));
+    }
+
+    fn vsm_for_step(source: &str, input: &str) -> (Vsm, SharedOutput) {
+        let output = SharedOutput::new();
+        let mut vsm = Vsm::with_io(
+            TraceType::No,
+            Box::new(Cursor::new(input.to_string())),
+            Box::new(output.clone()),
+        );
+        let mut code = Code::new();
+        code.parse(source, "step.vsm").unwrap();
+        vsm.load_code(code);
+        (vsm, output)
+    }
+
+    #[test]
+    fn test_step() {
+        let (mut vsm, _) = vsm_for_step("LC 1\nLC 2\nADD\nEXIT\n", "");
+        assert_eq!(vsm.step(), StepStatus::Continue);
+        assert_eq!(vsm.program_counter(), 1);
+        assert_eq!(vsm.stack_pointer(), Some(0));
+        assert_eq!(vsm.step(), StepStatus::Continue);
+        assert_eq!(&vsm.stack()[..2], &[1, 2]);
+        assert_eq!(vsm.step(), StepStatus::Continue);
+        assert_eq!(vsm.stack_pointer(), Some(0));
+        assert_eq!(vsm.stack()[0], 3);
+        assert_eq!(vsm.step(), StepStatus::Halted(3));
+        // 終了後は何も実行しない
+        assert_eq!(vsm.step(), StepStatus::Halted(3));
+        assert_eq!(vsm.executed_instructions(), 4);
+        assert_eq!(vsm.exit_code(), Some(3));
+
+        let (mut vsm, _) = vsm_for_step("LC 1\nLC 0\nDIV\nEXIT\n", "");
+        vsm.step();
+        vsm.step();
+        match vsm.step() {
+            StepStatus::Error(err) => assert_eq!(err.error, VsmError::DivisionByZero),
+            status => panic!("{:?}", status),
+        }
+    }
+
+    #[test]
+    fn test_step_run_until() {
+        let (mut vsm, output) = vsm_for_step("LC 0\nCALL f\nEXIT\nf: LC 7\nPUTI\nRET\n", "");
+        assert_eq!(vsm.run_until(4), StepStatus::Continue);
+        assert_eq!(vsm.program_counter(), 4);
+        assert_eq!(vsm.frame_top_address(), 1);
+        assert_eq!(vsm.global_top_address(), 0);
+        assert_eq!(vsm.stack()[3], 2);
+        // 到達しなければ終了まで実行する. RET 後の SP は B1 なので M[1] が終了コードになる
+        assert_eq!(vsm.run_until(4), StepStatus::Halted(7));
+        assert_eq!(output.contents(), "7");
+    }
+
+    #[test]
+    fn test_step_waiting_for_input() {
+        let (mut vsm, output) = vsm_for_step("GETI\nGETI\nADD\nPUTI\nLC 0\nEXIT\n", "1\n");
+        assert_eq!(vsm.step(), StepStatus::Continue);
+        assert_eq!(vsm.step(), StepStatus::WaitingForInput);
+        assert_eq!(vsm.program_counter(), 1);
+        assert_eq!(vsm.executed_instructions(), 1);
+
+        vsm.provide_input("41\n");
+        assert_eq!(vsm.run_until(usize::MAX), StepStatus::Halted(0));
+        assert_eq!(output.contents(), "42");
+    }
+
+    fn exec_with_arithmetic_mode(source: &str, arithmetic_mode: ArithmeticMode, fast: bool) -> Result<i32, VsmError> {
+        let (mut vsm, _) = vsm_for_step(source, "");
+        vsm.set_arithmetic_mode(arithmetic_mode);
+        let result = if fast { vsm.exec_code_fast() } else { vsm.exec_code() };
+        result.map_err(|err| err.error)
+    }
+
+    #[test]
+    fn test_exec_code_arithmetic_modes() {
+        let overflow = Err(VsmError::IntegerOverflow);
+        let cases = [
+            ("LC 2147483647\nLC 1\nADD\nEXIT\n", overflow.clone(), Ok(i32::MIN), Ok(i32::MAX)),
+            ("LC -2147483648\nLC 1\nSUB\nEXIT\n", overflow.clone(), Ok(i32::MAX), Ok(i32::MIN)),
+            ("LC 65536\nLC -65536\nMUL\nEXIT\n", overflow.clone(), Ok(0), Ok(i32::MIN)),
+            ("LC -2147483648\nLC -1\nDIV\nEXIT\n", overflow.clone(), Ok(i32::MIN), Ok(i32::MAX)),
+            ("LC -2147483648\nLC -1\nMOD\nEXIT\n", Ok(0), Ok(0), Ok(0)),
+            ("LC -2147483648\nINV\nEXIT\n", overflow.clone(), Ok(i32::MIN), Ok(i32::MAX)),
+            ("LC 7\nLC 0\nDIV\nEXIT\n", Err(VsmError::DivisionByZero), Err(VsmError::DivisionByZero), Err(VsmError::DivisionByZero)),
+            ("LC 7\nLC -2\nMOD\nEXIT\n", Ok(1), Ok(1), Ok(1)),
+        ];
+        for (source, trap, wrapping, saturating) in cases {
+            for fast in [false, true] {
+                assert_eq!(exec_with_arithmetic_mode(source, ArithmeticMode::Trap, fast), trap, "{}", source);
+                assert_eq!(exec_with_arithmetic_mode(source, ArithmeticMode::Wrapping, fast), wrapping, "{}", source);
+                assert_eq!(exec_with_arithmetic_mode(source, ArithmeticMode::Saturating, fast), saturating, "{}", source);
+            }
+        }
+
+        assert_eq!(Vsm::new(TraceType::No).arithmetic_mode(), ArithmeticMode::Trap);
+        assert_eq!("saturating".parse::<ArithmeticMode>(), Ok(ArithmeticMode::Saturating));
+        assert!("checked".parse::<ArithmeticMode>().is_err());
+    }
+
+    #[test]
+    fn test_exec_code_growable_stack() {
+        // 既定では 1024 セルを超えても伸びる
+        let (mut vsm, _) = vsm_for_step("ISP 2000\nLC 5\nSV 0 3000\nLV 0 2500\nLV 0 3000\nEXIT\n", "");
+        assert_eq!(vsm.stack().len(), 1024);
+        assert_eq!(vsm.exec_code(), Ok(5));
+        assert!(vsm.stack().len() > 3000);
+        assert_eq!(vsm.stack_pointer(), Some(2001));
+
+        // 上限を超えるとオーバーフロー
+        let (mut vsm, _) = vsm_for_step("LC 1\nLC 2\nLC 3\nLC 4\nEXIT\n", "");
+        vsm.set_stack_size(1, 3);
+        let err = vsm.exec_code().unwrap_err();
+        assert_eq!(err.error, VsmError::StackOverflow);
+        assert_eq!(err.program_counter, 3);
+        assert_eq!(vsm.stack().len(), 3);
+
+        let (mut vsm, _) = vsm_for_step("LV 0 10\nEXIT\n", "");
+        vsm.set_stack_size(4, 10);
+        assert_eq!(vsm.exec_code().unwrap_err().error, VsmError::OutOfBoundsRead { address: 10 });
+    }
+
+    #[test]
+    fn test_exec_code_runaway_recursion() {
+        let source = "LC 0\nCALL f\nEXIT\nf: ISP 3\nLV 1 0\nCALL f\nRET\n";
+        for fast in [false, true] {
+            let (mut vsm, _) = vsm_for_step(source, "");
+            vsm.set_stack_size(16, 10000);
+            let result = if fast { vsm.exec_code_fast() } else { vsm.exec_code() };
+            let err = result.unwrap_err();
+            assert_eq!(err.error, VsmError::StackOverflow);
+            assert_eq!(vsm.stack().len(), 10000);
+        }
+    }
+
+    #[test]
+    fn test_exec_code_data() {
+        let (result, output) = exec_file_with_input("tests/vsm/hello.vsm", "");
+        assert_eq!(result, Ok(0));
+        assert_eq!(output, "Hello, world!\n".repeat(3));
+
+        // データは B0 から置かれ, SP はその上から始まる
+        let source = ".data\nmsg: .string \"hi\"\n.text\nLC msg\nPUTS\nLC 5\nEXIT\n";
+        for fast in [false, true] {
+            let (mut vsm, output) = vsm_for_step(source, "");
+            assert_eq!(vsm.stack_pointer(), Some(2));
+            assert_eq!(&vsm.stack()[..3], &[104, 105, 0]);
+            let result = if fast { vsm.exec_code_fast() } else { vsm.exec_code() };
+            assert_eq!(result, Ok(5));
+            assert_eq!(output.contents(), "hi");
+            assert_eq!(vsm.stack_pointer(), Some(3));
+        }
+
+        let (mut vsm, _) = vsm_for_step(".data\n.word 104 -1 0\n.text\nLC 0\nPUTS\nEXIT\n", "");
+        assert_eq!(vsm.exec_code().unwrap_err().error, VsmError::InvalidCharacter(-1));
+
+        // スタックの上限を超えるデータは置かず, 実行しようとするとオーバーフローになる
+        for fast in [false, true] {
+            let output = SharedOutput::new();
+            let mut vsm = Vsm::with_io(TraceType::No, Box::new(Cursor::new(String::new())), Box::new(output.clone()));
+            vsm.set_stack_size(4, 4);
+            let mut code = Code::new();
+            code.parse(".data\n.zero 5\n.text\nLC 0\nEXIT\n", "overflow.vsm").unwrap();
+            vsm.load_code(code);
+            let result = if fast { vsm.exec_code_fast() } else { vsm.exec_code() };
+            let err = result.unwrap_err();
+            assert_eq!(err.error, VsmError::StackOverflow);
+            assert_eq!(err.program_counter, 0);
+            assert_eq!(vsm.stack().len(), 4);
+            assert_eq!(vsm.stack_pointer(), None);
+        }
+    }
+}